@@ -0,0 +1,305 @@
+//! `calloop` integration
+
+use std::io;
+use std::os::fd::{AsFd, OwnedFd};
+
+use ::calloop::generic::Generic;
+use ::calloop::{
+    EventSource, InsertError, Interest, LoopHandle, Mode, Poll, PostAction, Readiness,
+    RegistrationToken, Token, TokenFactory,
+};
+
+use crate::{Connection, IoMode};
+
+/// A `calloop` event source for a [`Connection`].
+///
+/// Flushes the connection before the event loop goes to sleep and receives events when the
+/// socket is readable. If the socket buffer is full, the source waits for the socket to become
+/// writable and flushes again, so requests are never stuck in the buffer. Requests sent with
+/// handles are flushed when [`Connection::wakeup_fd`] becomes readable.
+///
+/// Use [`insert`](Self::insert) to dispatch the events automatically, or insert it manually to
+/// get `&mut Connection<D>` in the callback each time new events are received.
+pub struct WaylandSource<D> {
+    conn: Connection<D>,
+    fd: Generic<OwnedFd>,
+    // Created once the connection has a wakeup fd
+    wakeup: Option<Generic<OwnedFd>>,
+    fake_token: Option<Token>,
+}
+
+impl<D> WaylandSource<D> {
+    pub fn new(conn: Connection<D>) -> io::Result<Self> {
+        let fd = conn.as_fd().try_clone_to_owned()?;
+        Ok(Self {
+            conn,
+            fd: Generic::new(fd, Interest::READ, Mode::Level),
+            wakeup: None,
+            fake_token: None,
+        })
+    }
+
+    /// Get a reference to the connection.
+    #[must_use]
+    pub fn conn(&self) -> &Connection<D> {
+        &self.conn
+    }
+
+    /// Get a mutable reference to the connection.
+    #[must_use]
+    pub fn conn_mut(&mut self) -> &mut Connection<D> {
+        &mut self.conn
+    }
+
+    /// Insert this source into an event loop, dispatching the events to the loop data.
+    #[allow(clippy::result_large_err)]
+    pub fn insert(self, handle: &LoopHandle<'_, D>) -> Result<RegistrationToken, InsertError<Self>>
+    where
+        D: 'static,
+    {
+        handle.insert_source(self, |(), conn, state| conn.dispatch_events(state))
+    }
+
+    /// Flush the connection. Returns whether the flush would block.
+    fn flush(&mut self) -> io::Result<bool> {
+        match self.conn.flush(IoMode::NonBlocking) {
+            Ok(()) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the connection has a wakeup fd which is not registered yet.
+    fn needs_wakeup(&self) -> bool {
+        self.wakeup.is_none() && self.conn.wakeup_fd().is_some()
+    }
+
+    /// Register the wakeup fd of the connection, if it exists.
+    fn register_wakeup(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> ::calloop::Result<()> {
+        if self.wakeup.is_none() {
+            let Some(fd) = self.conn.wakeup_fd() else {
+                return Ok(());
+            };
+            let fd = fd.try_clone_to_owned()?;
+            self.wakeup = Some(Generic::new(fd, Interest::READ, Mode::Level));
+        }
+        self.wakeup.as_mut().unwrap().register(poll, token_factory)
+    }
+}
+
+impl<D> EventSource for WaylandSource<D> {
+    type Event = ();
+    type Metadata = Connection<D>;
+    type Ret = ();
+    type Error = io::Error;
+
+    const NEEDS_EXTRA_LIFECYCLE_EVENTS: bool = true;
+
+    fn process_events<F>(
+        &mut self,
+        readiness: Readiness,
+        _token: Token,
+        mut callback: F,
+    ) -> Result<PostAction, Self::Error>
+    where
+        F: FnMut(Self::Event, &mut Self::Metadata) -> Self::Ret,
+    {
+        if readiness.readable {
+            match self.conn.recv_events(IoMode::NonBlocking) {
+                Ok(()) => callback((), &mut self.conn),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Wait for the socket to become writable only while there are requests stuck in the
+        // buffer, since it is writable most of the time. This also flushes the requests sent with
+        // handles if the wakeup fd is readable.
+        let would_block = self.flush()?;
+        if self.fd.interest.writable != would_block || self.needs_wakeup() {
+            self.fd.interest.writable = would_block;
+            Ok(PostAction::Reregister)
+        } else {
+            Ok(PostAction::Continue)
+        }
+    }
+
+    fn register(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> ::calloop::Result<()> {
+        self.fake_token = Some(token_factory.token());
+        self.fd.register(poll, token_factory)?;
+        self.register_wakeup(poll, token_factory)
+    }
+
+    fn reregister(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> ::calloop::Result<()> {
+        self.fd.reregister(poll, token_factory)?;
+        match &mut self.wakeup {
+            Some(wakeup) => wakeup.reregister(poll, token_factory),
+            None => self.register_wakeup(poll, token_factory),
+        }
+    }
+
+    fn unregister(&mut self, poll: &mut Poll) -> ::calloop::Result<()> {
+        self.fd.unregister(poll)?;
+        match &mut self.wakeup {
+            Some(wakeup) => wakeup.unregister(poll),
+            None => Ok(()),
+        }
+    }
+
+    fn before_sleep(&mut self) -> ::calloop::Result<Option<(Readiness, Token)>> {
+        let would_block = self.flush()?;
+        if (would_block && !self.fd.interest.writable) || self.needs_wakeup() {
+            // Wake up immediately to register the writable interest or the wakeup fd
+            Ok(Some((Readiness::EMPTY, self.fake_token.unwrap())))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use ::calloop::{Dispatcher, EventLoop};
+    use wayrs_core::transport::{BufferedSocket, PeekHeaderError};
+    use wayrs_core::ArgValue;
+
+    use super::*;
+    use crate::protocol::*;
+    use crate::testing::MockCompositor;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    type TestDispatcher<'l, D> = Dispatcher<'l, WaylandSource<D>, D>;
+
+    fn insert<'l, D: 'static>(
+        event_loop: &EventLoop<'l, D>,
+        conn: Connection<D>,
+    ) -> TestDispatcher<'l, D> {
+        let dispatcher = Dispatcher::new(
+            WaylandSource::new(conn).unwrap(),
+            |(), conn: &mut Connection<D>, state: &mut D| conn.dispatch_events(state),
+        );
+        event_loop
+            .handle()
+            .register_dispatcher(dispatcher.clone())
+            .unwrap();
+        dispatcher
+    }
+
+    #[test]
+    fn dispatch_cycle() {
+        let (mut mock, mut conn) = MockCompositor::new::<Vec<i32>>();
+        let mut state = Vec::new();
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut state);
+        let output = conn
+            .bind_singleton_with_cb::<WlOutput, _>(4, |ctx| {
+                if let wl_output::Event::Scale(scale) = ctx.event {
+                    ctx.state.push(scale);
+                }
+            })
+            .unwrap();
+
+        let mut event_loop = EventLoop::try_new().unwrap();
+        let _dispatcher = insert(&event_loop, conn);
+
+        // The bind request is flushed before the loop goes to sleep
+        event_loop.dispatch(Duration::ZERO, &mut state).unwrap();
+        assert_eq!(mock.dispatch_requests().unwrap(), 1);
+
+        mock.send_event(output, "scale", vec![ArgValue::Int(2)]);
+        mock.send_event(output, "scale", vec![ArgValue::Int(3)]);
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+        event_loop.dispatch(TIMEOUT, &mut state).unwrap();
+        assert_eq!(state, [2, 3]);
+    }
+
+    #[test]
+    fn writable_interest_on_would_block() {
+        // The loopback transport never blocks, so use a real socket
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut server = BufferedSocket::from(server);
+        let mut conn = Connection::<()>::with_transport(client);
+        let registry = conn.registry();
+
+        // Fill the socket buffer
+        let mut sent = 1; // wl_display.get_registry
+        loop {
+            for _ in 0..100 {
+                let _: WlOutput = registry.bind(&mut conn, 1, 1);
+            }
+            sent += 100;
+            match conn.flush(IoMode::NonBlocking) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                result => result.unwrap(),
+            }
+        }
+
+        let mut event_loop = EventLoop::try_new().unwrap();
+        let dispatcher = insert(&event_loop, conn);
+        event_loop.dispatch(Duration::ZERO, &mut ()).unwrap();
+        assert!(dispatcher.as_source_ref().fd.interest.writable);
+
+        // The requests are flushed once the socket becomes writable
+        let mut received = 0;
+        while dispatcher.as_source_ref().fd.interest.writable {
+            loop {
+                match server.peek_message_header(IoMode::NonBlocking) {
+                    Ok(header) => server.skip_message(header, IoMode::NonBlocking).unwrap(),
+                    Err(PeekHeaderError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("{e}"),
+                }
+                received += 1;
+            }
+            event_loop.dispatch(TIMEOUT, &mut ()).unwrap();
+        }
+        while let Ok(header) = server.peek_message_header(IoMode::NonBlocking) {
+            server.skip_message(header, IoMode::NonBlocking).unwrap();
+            received += 1;
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn handle_requests_are_flushed() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        let name = mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut ());
+        let registry = conn.registry();
+
+        let mut event_loop = EventLoop::try_new().unwrap();
+        let dispatcher = insert(&event_loop, conn);
+        event_loop.dispatch(Duration::ZERO, &mut ()).unwrap();
+
+        // The wakeup fd is registered once a handle is created
+        let mut handle = dispatcher.as_source_mut().conn_mut().handle().unwrap();
+        event_loop.dispatch(Duration::ZERO, &mut ()).unwrap();
+        assert!(dispatcher.as_source_ref().wakeup.is_some());
+
+        let output = std::thread::spawn(move || {
+            let output: WlOutput = registry.bind(&mut handle, name, 4);
+            output
+        })
+        .join()
+        .unwrap();
+
+        event_loop.dispatch(Duration::ZERO, &mut ()).unwrap();
+        assert_eq!(mock.dispatch_requests().unwrap(), 1);
+        assert_eq!(mock.objects_of::<WlOutput>(), [output]);
+    }
+}
@@ -0,0 +1,397 @@
+//! Connecting to a Wayland socket
+
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use crate::logger::ProtocolLogger;
+use crate::Connection;
+
+use wayrs_core::transport::capture::Recorder;
+
+/// An error that can occur while connecting to a Wayland socket.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ConnectError {
+    /// `$XDG_RUNTIME_DIR` was not set, but the display name is not an absolute path.
+    NotEnoughEnvVars,
+    /// `$WAYLAND_SOCKET` is set, but it is not a valid file descriptor.
+    WaylandSocket(io::Error),
+    /// Could not connect to the socket at this path.
+    Socket { path: PathBuf, error: io::Error },
+    /// Could not create the capture file requested by `$WAYRS_CAPTURE`.
+    Capture { path: PathBuf, error: io::Error },
+    /// Some IO error.
+    Io(io::Error),
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::NotEnoughEnvVars => None,
+            Self::WaylandSocket(error)
+            | Self::Socket { error, .. }
+            | Self::Capture { error, .. }
+            | Self::Io(error) => Some(error),
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotEnoughEnvVars => f.write_str(
+                "$XDG_RUNTIME_DIR must be set, unless the display name is an absolute path",
+            ),
+            Self::WaylandSocket(error) => write!(f, "invalid $WAYLAND_SOCKET: {error}"),
+            Self::Socket { path, error } => {
+                write!(f, "could not connect to {}: {error}", path.display())
+            }
+            Self::Capture { path, error } => {
+                write!(f, "could not create capture {}: {error}", path.display())
+            }
+            Self::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Options for connecting to a Wayland socket.
+///
+/// By default, the socket is found the same way `libwayland` does it:
+///
+/// 1. If `$WAYLAND_SOCKET` is set, interpret it as a file descriptor number on which the
+///    connection is already established. The file descriptor is marked close-on-exec. Unlike
+///    `libwayland`, the variable is not unset unless
+///    [`unset_wayland_socket`](Self::unset_wayland_socket) is enabled.
+/// 2. Otherwise, take the display name from `$WAYLAND_DISPLAY`, or use `wayland-0` if it is not
+///    set. If the name is an absolute path, connect to it, otherwise connect to the socket with
+///    this name in `$XDG_RUNTIME_DIR`.
+///
+/// If [`use_capture`](Self::use_capture) is enabled and `$WAYRS_CAPTURE` environment variable is
+/// set, all the traffic is recorded to a file at this path. See
+/// [`capture`](wayrs_core::transport::capture) for details.
+///
+/// ```no_run
+/// use wayrs_client::{ConnectOptions, Connection};
+///
+/// let conn: Connection<()> = ConnectOptions::new()
+///     .display("wayland-1")
+///     .max_buffer_size(1024 * 1024)
+///     .connect()
+///     .unwrap();
+/// ```
+pub struct ConnectOptions {
+    target: Option<Target>,
+    use_wayland_socket: bool,
+    unset_wayland_socket: bool,
+    use_capture: bool,
+    debug: Option<bool>,
+    logger: Option<Box<dyn ProtocolLogger>>,
+    lenient: bool,
+    buffer_size: Option<usize>,
+    max_buffer_size: Option<usize>,
+}
+
+impl fmt::Debug for ConnectOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectOptions")
+            .field("target", &self.target)
+            .field("use_wayland_socket", &self.use_wayland_socket)
+            .field("unset_wayland_socket", &self.unset_wayland_socket)
+            .field("use_capture", &self.use_capture)
+            .field("debug", &self.debug)
+            .field("lenient", &self.lenient)
+            .field("buffer_size", &self.buffer_size)
+            .field("max_buffer_size", &self.max_buffer_size)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+enum Target {
+    Path(PathBuf),
+    Display(OsString),
+    Fd(OwnedFd),
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectOptions {
+    /// Default options, see the [type level documentation](Self).
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            target: None,
+            use_wayland_socket: true,
+            unset_wayland_socket: false,
+            use_capture: false,
+            debug: None,
+            logger: None,
+            lenient: false,
+            buffer_size: None,
+            max_buffer_size: None,
+        }
+    }
+
+    /// Connect to the socket at this path, ignoring the environment.
+    #[must_use]
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.target = Some(Target::Path(path.into()));
+        self
+    }
+
+    /// Use this display name instead of `$WAYLAND_DISPLAY`.
+    ///
+    /// `$WAYLAND_SOCKET` is ignored. A relative name is resolved in `$XDG_RUNTIME_DIR`.
+    #[must_use]
+    pub fn display(mut self, name: impl Into<OsString>) -> Self {
+        self.target = Some(Target::Display(name.into()));
+        self
+    }
+
+    /// Use an already connected socket, ignoring the environment.
+    #[must_use]
+    pub fn fd(mut self, fd: OwnedFd) -> Self {
+        self.target = Some(Target::Fd(fd));
+        self
+    }
+
+    /// Use an already connected socket, ignoring the environment.
+    #[must_use]
+    pub fn stream(self, stream: UnixStream) -> Self {
+        self.fd(stream.into())
+    }
+
+    /// Whether to use `$WAYLAND_SOCKET`. Default is `true`.
+    #[must_use]
+    pub fn use_wayland_socket(mut self, value: bool) -> Self {
+        self.use_wayland_socket = value;
+        self
+    }
+
+    /// Whether to unset `$WAYLAND_SOCKET` after using it, so that it is not inherited by child
+    /// processes. Default is `false`.
+    ///
+    /// # Thread safety
+    ///
+    /// Modifying the environment is not thread-safe: if another thread reads or writes the
+    /// environment at the same time (including via libc functions such as `getenv`), the behavior
+    /// is undefined. Enable this only if the program is single-threaded at the time of connecting.
+    #[must_use]
+    pub fn unset_wayland_socket(mut self, value: bool) -> Self {
+        self.unset_wayland_socket = value;
+        self
+    }
+
+    /// Whether to record a capture when `$WAYRS_CAPTURE` is set. Default is `false`.
+    ///
+    /// The capture contains all the traffic, including everything typed on the keyboard and the
+    /// contents of the clipboard, so enable it only for debugging. A warning is printed to stderr
+    /// when the recording starts.
+    #[must_use]
+    pub fn use_capture(mut self, value: bool) -> Self {
+        self.use_capture = value;
+        self
+    }
+
+    /// Enable or disable debug messages. By default, they are enabled if `$WAYLAND_DEBUG` is set.
+    #[must_use]
+    pub fn debug(mut self, value: bool) -> Self {
+        self.debug = Some(value);
+        self
+    }
+
+    /// Set the logger which receives debug messages and diagnostics, see
+    /// [`Connection::set_protocol_logger`].
+    #[must_use]
+    pub fn protocol_logger(mut self, logger: impl ProtocolLogger + 'static) -> Self {
+        self.logger = Some(Box::new(logger));
+        self
+    }
+
+    /// Enable or disable lenient mode, see [`Connection::set_lenient`].
+    #[must_use]
+    pub fn lenient(mut self, value: bool) -> Self {
+        self.lenient = value;
+        self
+    }
+
+    /// Set the initial size of the socket buffers.
+    ///
+    /// The buffers grow as needed, this can be used to avoid reallocations if a lot of data is
+    /// expected. It is capped by the [maximum size](Self::max_buffer_size).
+    #[must_use]
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = Some(size);
+        self
+    }
+
+    /// Set the maximum size of the socket buffers, see [`Connection::set_max_buffer_size`].
+    #[must_use]
+    pub fn max_buffer_size(mut self, size: usize) -> Self {
+        self.max_buffer_size = Some(size);
+        self
+    }
+
+    /// Connect to the socket and create a registry.
+    pub fn connect<D>(self) -> Result<Connection<D>, ConnectError> {
+        let stream = match self.target {
+            Some(Target::Path(path)) => connect_to(path)?,
+            Some(Target::Display(name)) => connect_to(display_path(name)?)?,
+            Some(Target::Fd(fd)) => UnixStream::from(fd),
+            None => match self.wayland_socket()? {
+                Some(stream) => stream,
+                None => {
+                    let name = env::var_os("WAYLAND_DISPLAY").unwrap_or_else(|| "wayland-0".into());
+                    connect_to(display_path(name)?)?
+                }
+            },
+        };
+
+        let capture = match env::var_os("WAYRS_CAPTURE") {
+            Some(path) if self.use_capture => Some(PathBuf::from(path)),
+            _ => None,
+        };
+        let mut conn = match &capture {
+            Some(path) => {
+                let file = File::create(path).map_err(|error| ConnectError::Capture {
+                    path: path.clone(),
+                    error,
+                })?;
+                let recorder =
+                    Recorder::new(stream, file).map_err(|error| ConnectError::Capture {
+                        path: path.clone(),
+                        error,
+                    })?;
+                Connection::with_transport(recorder)
+            }
+            None => Connection::with_transport(stream),
+        };
+
+        if let Some(debug) = self.debug {
+            conn.set_debug(debug);
+        }
+        if let Some(logger) = self.logger {
+            conn.log.set_logger(logger);
+        }
+        conn.set_lenient(self.lenient);
+        if let Some(size) = self.max_buffer_size {
+            conn.set_max_buffer_size(size);
+        }
+        if let Some(size) = self.buffer_size {
+            conn.reserve_buffers(size);
+        }
+
+        if let Some(path) = capture {
+            conn.log.warning(format_args!(
+                "WARNING: recording all Wayland traffic to {}",
+                path.display()
+            ));
+        }
+
+        Ok(conn)
+    }
+
+    /// Take the socket from `$WAYLAND_SOCKET`, if set.
+    fn wayland_socket(&self) -> Result<Option<UnixStream>, ConnectError> {
+        if !self.use_wayland_socket {
+            return Ok(None);
+        }
+        let Some(fd) = env::var_os("WAYLAND_SOCKET") else {
+            return Ok(None);
+        };
+        // Not thread-safe, see `unset_wayland_socket`
+        if self.unset_wayland_socket {
+            env::remove_var("WAYLAND_SOCKET");
+        }
+
+        let fd: RawFd = fd.to_str().and_then(|fd| fd.parse().ok()).ok_or_else(|| {
+            ConnectError::WaylandSocket(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a file descriptor number",
+            ))
+        })?;
+        if fd < 0 {
+            return Err(ConnectError::WaylandSocket(io::Error::from_raw_os_error(
+                libc::EBADF,
+            )));
+        }
+
+        // SAFETY: the fd is only borrowed to check that it is valid.
+        set_cloexec(unsafe { BorrowedFd::borrow_raw(fd) }).map_err(ConnectError::WaylandSocket)?;
+        // SAFETY: the fd is valid and it was given to us by the parent process.
+        Ok(Some(unsafe { UnixStream::from_raw_fd(fd) }))
+    }
+}
+
+fn display_path(name: OsString) -> Result<PathBuf, ConnectError> {
+    if Path::new(&name).is_absolute() {
+        return Ok(name.into());
+    }
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").ok_or(ConnectError::NotEnoughEnvVars)?;
+    Ok(Path::new(&runtime_dir).join(name))
+}
+
+fn connect_to(path: PathBuf) -> Result<UnixStream, ConnectError> {
+    UnixStream::connect(&path).map_err(|error| ConnectError::Socket { path, error })
+}
+
+fn set_cloexec(fd: BorrowedFd<'_>) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn connect_to_path() {
+        let dir = env::temp_dir().join(format!("wayrs-connect-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wayland-test");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let _conn: Connection<()> = ConnectOptions::new()
+            .path(&path)
+            .buffer_size(1 << 20)
+            .connect()
+            .unwrap();
+        listener.accept().unwrap();
+
+        // An absolute display name does not need $XDG_RUNTIME_DIR
+        let _conn: Connection<()> = ConnectOptions::new().display(&path).connect().unwrap();
+        listener.accept().unwrap();
+
+        let missing = dir.join("wayland-missing");
+        let Err(err) = ConnectOptions::new().path(&missing).connect::<()>() else {
+            panic!("connected to a missing socket");
+        };
+        assert!(matches!(&err, ConnectError::Socket { path, .. } if *path == missing));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,57 +1,252 @@
 //! Wayland connection
 
-use std::collections::VecDeque;
-use std::env;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
 use std::fmt;
+use std::future::{poll_fn, Future};
 use std::io;
 use std::num::NonZeroU32;
-use std::os::fd::{AsRawFd, FromRawFd, RawFd};
-use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
 
-use crate::debug_message::DebugMessage;
 use crate::global::BindError;
 use crate::global::GlobalExt;
 use crate::global::VersionBounds;
-use crate::object::{Object, ObjectManager, Proxy};
+use crate::handle::{ConnectionHandle, QueuedRequest, Shared};
+use crate::logger::{Direction, InterfaceFilter, ProtocolLog, ProtocolLogger};
+use crate::object::{
+    lookup_interface, register_interface, BadMessage, Object, ObjectInfo, ObjectManager,
+    ObjectState, Proxy,
+};
 use crate::protocol::wl_registry::GlobalArgs;
 use crate::protocol::*;
-use crate::EventCtx;
+use crate::reactor::{default_reactor, Interest, Reactor};
+use crate::{ConnectError, ConnectOptions, Dispatch, EventCtx};
+
+use wayrs_core::transport::{
+    BufferLimitError, BufferedSocket, PeekHeaderError, RecvMessageError, SendMessageError,
+    Transport,
+};
+use wayrs_core::{
+    ArgType, ArgValue, BorrowedArgValue, Interface, IoMode, Message, MessageBuffersPool,
+    MessageHeader, ObjectId,
+};
+
+/// A fatal protocol error sent by the compositor.
+///
+/// Once a protocol error is received, the connection is no longer usable. The error is returned
+/// wrapped in an [`io::Error`] of kind [`Other`](io::ErrorKind::Other) by all the methods which
+/// receive events, use [`ProtocolError::from_io`] to extract it. It is also retained on the
+/// connection, see [`Connection::protocol_error`].
+#[derive(Debug, Clone)]
+pub struct ProtocolError {
+    /// The ID of the object the error was posted for.
+    pub object_id: ObjectId,
+    /// The object the error was posted for, if it is known to the client.
+    pub object: Option<Object>,
+    /// The error code.
+    pub code: u32,
+    /// The name of the error code from the `error` enum of the object's interface.
+    pub code_name: Option<&'static str>,
+    /// The error description.
+    pub message: CString,
+}
+
+impl ProtocolError {
+    /// Get a reference to a `ProtocolError` if `error` wraps one.
+    #[must_use]
+    pub fn from_io(error: &io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    /// The interface of the object the error was posted for, if it is known to the client.
+    #[must_use]
+    pub fn interface(&self) -> Option<&'static Interface> {
+        self.object.map(|object| object.interface)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.object {
+            Some(object) => write!(f, "error in object {object:?}")?,
+            None => write!(f, "error in object {}", self.object_id.0)?,
+        }
+        match self.code_name {
+            Some(name) => write!(f, " (code {} {name:?})", self.code)?,
+            None => write!(f, " (code {})", self.code)?,
+        }
+        write!(f, ": {}", self.message.to_string_lossy())
+    }
+}
 
-use wayrs_core::transport::{BufferedSocket, PeekHeaderError, RecvMessageError, SendMessageError};
-use wayrs_core::{ArgType, ArgValue, Interface, IoMode, Message, MessageBuffersPool, ObjectId};
+impl From<ProtocolError> for io::Error {
+    fn from(value: ProtocolError) -> Self {
+        io::Error::other(value)
+    }
+}
 
-#[cfg(feature = "tokio")]
-use tokio::io::unix::AsyncFd;
+/// An event which cannot be handled by the client.
+///
+/// The event is discarded and the error is returned wrapped in an [`io::Error`] of kind
+/// [`InvalidData`](io::ErrorKind::InvalidData), use [`EventError::from_io`] to extract it. The
+/// connection can still be used after this error. In lenient mode (see
+/// [`Connection::set_lenient`]) such events are discarded silently.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum EventError {
+    /// The event was sent for an object which does not exist.
+    UnknownObject { object_id: ObjectId, opcode: u16 },
+    /// The interface of the object does not have an event with this opcode.
+    UnknownOpcode { object: Object, opcode: u16 },
+    /// The event creates an object with an interface which was not registered with
+    /// [`register_interface`](crate::object::register_interface).
+    UnknownInterface {
+        object: Object,
+        opcode: u16,
+        interface: CString,
+    },
+    /// The arguments of the event are invalid, for example an enum has an unknown value.
+    InvalidData { object: Object, opcode: u16 },
+}
 
-/// An error that can occur while connecting to a Wayland socket.
-#[derive(Debug)]
-pub enum ConnectError {
-    /// Either `$XDG_RUNTIME_DIR` or `$WAYLAND_DISPLAY` was not available.
-    NotEnoughEnvVars,
-    /// Some IO error.
-    Io(io::Error),
+impl EventError {
+    /// Get a reference to an `EventError` if `error` wraps one.
+    #[must_use]
+    pub fn from_io(error: &io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
 }
 
-impl std::error::Error for ConnectError {}
+impl std::error::Error for EventError {}
 
-impl fmt::Display for ConnectError {
+impl fmt::Display for EventError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::NotEnoughEnvVars => {
-                f.write_str("both $XDG_RUNTIME_DIR and $WAYLAND_DISPLAY must be set")
+            Self::UnknownObject { object_id, opcode } => write!(
+                f,
+                "received event (opcode {opcode}) for non-existing object {}",
+                object_id.0
+            ),
+            Self::UnknownOpcode { object, opcode } => {
+                write!(
+                    f,
+                    "received event with unknown opcode {opcode} for {object:?}"
+                )
             }
-            Self::Io(error) => error.fmt(f),
+            Self::UnknownInterface {
+                object,
+                opcode,
+                interface,
+            } => write!(
+                f,
+                "received event (opcode {opcode}) for {object:?} with unknown interface {interface:?}"
+            ),
+            Self::InvalidData { object, opcode } => write!(
+                f,
+                "received event (opcode {opcode}) with invalid arguments for {object:?}"
+            ),
+        }
+    }
+}
+
+impl From<EventError> for io::Error {
+    fn from(value: EventError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// An attempt to send a request which is not supported by the version of the object.
+#[derive(Debug, Clone, Copy)]
+pub struct VersionTooLow {
+    /// The object the request was sent for.
+    pub object: Object,
+    /// The name of the request.
+    pub request: &'static str,
+    /// The version of the interface the request was introduced in.
+    pub since: u32,
+}
+
+impl std::error::Error for VersionTooLow {}
+
+impl fmt::Display for VersionTooLow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "request {}.{} requires version {}, but {:?} has version {}",
+            self.object.interface.name.to_string_lossy(),
+            self.request,
+            self.since,
+            self.object,
+            self.object.version
+        )
+    }
+}
+
+/// An error which occurs when a request cannot be sent or a callback cannot be set.
+///
+/// Usually this means that a stale copy of a proxy was used after the object was destroyed, for
+/// example by a destructor event such as `wl_callback.done`. Returned by the `try_*` variants of
+/// the generated request methods and [`Connection::try_set_callback_for`]. The other variants
+/// panic instead.
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum RequestError {
+    /// The object does not exist.
+    NonExistingObject(ObjectId),
+    /// The object was destroyed.
+    DeadObject(Object),
+    /// The request is not supported by the version of the object.
+    VersionTooLow(VersionTooLow),
+}
+
+impl std::error::Error for RequestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::VersionTooLow(err) => Some(err),
+            _ => None,
         }
     }
 }
 
-impl From<io::Error> for ConnectError {
-    fn from(value: io::Error) -> Self {
-        Self::Io(value)
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NonExistingObject(id) => write!(f, "attempt to use non-existing object {}", id.0),
+            Self::DeadObject(object) => write!(f, "attempt to use dead object {object:?}"),
+            Self::VersionTooLow(err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<VersionTooLow> for RequestError {
+    fn from(value: VersionTooLow) -> Self {
+        Self::VersionTooLow(value)
     }
 }
 
+/// A queue of events.
+///
+/// Each object belongs to a single event queue. Received events are put into the queues of their
+/// objects, and each queue is dispatched separately with [`Connection::dispatch_queue`]. This
+/// allows, for example, a library to wait for its own events without calling unrelated callbacks
+/// of the application.
+///
+/// Initially, all objects belong to [`EventQueue::DEFAULT`], which is dispatched by
+/// [`Connection::dispatch_events`]. New objects are put into the queue of the object which created
+/// them. Queues are created with [`Connection::create_queue`] and live as long as the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventQueue(usize);
+
+impl EventQueue {
+    /// The default event queue.
+    pub const DEFAULT: Self = Self(0);
+}
+
 /// Wayland connection state.
 ///
 /// This struct manages a buffered Wayland socket, keeps track of objects and request/event queues
@@ -59,15 +254,16 @@ impl From<io::Error> for ConnectError {
 ///
 /// Set `WAYLAND_DEBUG=1` environment variable to get debug messages.
 pub struct Connection<D> {
-    #[cfg(feature = "tokio")]
-    async_fd: Option<AsyncFd<RawFd>>,
+    reactor: Option<Box<dyn Reactor>>,
 
-    socket: BufferedSocket<UnixStream>,
+    socket: BufferedSocket<Box<dyn Transport + Send>>,
     msg_buffers_pool: MessageBuffersPool,
 
     object_mgr: ObjectManager<D>,
 
-    event_queue: VecDeque<QueuedEvent>,
+    event_queues: Vec<VecDeque<QueuedEvent>>,
+    // IDs from `wl_display.delete_id` events which are not freed yet.
+    deleted_ids: Vec<ObjectId>,
     requests_queue: VecDeque<Message>,
     break_dispatch: bool,
 
@@ -77,7 +273,14 @@ pub struct Connection<D> {
     // This is `None` while dispatching registry events, to prevent mutation from registry callbacks.
     registry_cbs: Option<Vec<RegistryCb<D>>>,
 
-    debug: bool,
+    dispatchers: HashMap<&'static Interface, DispatchFn<D>>,
+
+    // Created with the first handle.
+    handle_shared: Option<Arc<Shared<D>>>,
+
+    pub(crate) log: ProtocolLog,
+    lenient: bool,
+    protocol_error: Option<ProtocolError>,
 }
 
 enum QueuedEvent {
@@ -86,9 +289,20 @@ enum QueuedEvent {
     Message(Message),
 }
 
+pub(crate) type BorrowedCallback = Box<
+    dyn for<'a> FnMut(
+            Object,
+            u16,
+            &mut dyn ExactSizeIterator<Item = BorrowedArgValue<'a>>,
+        ) -> Result<(), BadMessage>
+        + Send,
+>;
+
 pub(crate) type GenericCallback<D> =
     Box<dyn FnMut(&mut Connection<D>, &mut D, Object, Message) + Send>;
 
+type DispatchFn<D> = fn(&mut Connection<D>, &mut D, Object, Message);
+
 type RegistryCb<D> = Box<dyn FnMut(&mut Connection<D>, &mut D, &wl_registry::Event) + Send>;
 
 impl<D> AsRawFd for Connection<D> {
@@ -97,53 +311,37 @@ impl<D> AsRawFd for Connection<D> {
     }
 }
 
+impl<D> AsFd for Connection<D> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // SAFETY: the file descriptor is owned by the transport, which lives as long as `self`.
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
+    }
+}
+
 impl<D> Connection<D> {
     /// Connect to a Wayland socket and create a registry.
     ///
-    /// From the [Wayland Book](https://wayland-book.com/protocol-design/wire-protocol.html):
-    ///
-    /// > To find the Unix socket to connect to, most implementations just do what `libwayland` does:
-    /// >
-    /// > 1. If `WAYLAND_SOCKET` is set, interpret it as a file descriptor number on which the
-    /// >    connection is already established, assuming that the parent process configured the
-    /// >    connection for us.
-    /// > 2. If `WAYLAND_DISPLAY` is set, concat with `XDG_RUNTIME_DIR` to form the path to the
-    /// >    Unix socket.
-    /// > 3. Assume the socket name is wayland-0 and concat with `XDG_RUNTIME_DIR` to form the path
-    /// >    to the Unix socket.
-    /// > 4. Give up.
-    ///
-    /// Current implementation follows `libwayland` except for the step 3.
+    /// This is a shorthand for [`ConnectOptions::new().connect()`](ConnectOptions::connect). The
+    /// socket is found the same way `libwayland` does it, see [`ConnectOptions`] for details.
     pub fn connect() -> Result<Self, ConnectError> {
-        if let Some(fd) = env::var("WAYLAND_SOCKET")
-            .ok()
-            .and_then(|fd| fd.parse::<RawFd>().ok())
-        {
-            let stream = unsafe { UnixStream::from_raw_fd(fd) };
-            return Ok(Self::connect_with_unix_stream(stream));
-        }
-
-        let runtime_dir = env::var_os("XDG_RUNTIME_DIR").ok_or(ConnectError::NotEnoughEnvVars)?;
-        let wayland_disp = env::var_os("WAYLAND_DISPLAY").ok_or(ConnectError::NotEnoughEnvVars)?;
-
-        let mut path = PathBuf::new();
-        path.push(runtime_dir);
-        path.push(wayland_disp);
-
-        Ok(Self::connect_with_unix_stream(UnixStream::connect(path)?))
+        ConnectOptions::new().connect()
     }
 
-    fn connect_with_unix_stream(stream: UnixStream) -> Self {
+    /// Create a connection on top of an already established transport and create a registry.
+    ///
+    /// This can be used with [`loopback`](wayrs_core::transport::loopback) transport to test
+    /// client code against a scripted fake server, without a running compositor.
+    pub fn with_transport(transport: impl Transport + Send + 'static) -> Self {
         let mut this = Self {
-            #[cfg(feature = "tokio")]
-            async_fd: None,
+            reactor: None,
 
-            socket: BufferedSocket::from(stream),
+            socket: BufferedSocket::from(Box::new(transport) as Box<dyn Transport + Send>),
             msg_buffers_pool: MessageBuffersPool::default(),
 
             object_mgr: ObjectManager::new(),
 
-            event_queue: VecDeque::with_capacity(32),
+            event_queues: vec![VecDeque::with_capacity(32)],
+            deleted_ids: Vec::new(),
             requests_queue: VecDeque::with_capacity(32),
             break_dispatch: false,
 
@@ -151,10 +349,17 @@ impl<D> Connection<D> {
             globals: Vec::new(),
             registry_cbs: Some(Vec::new()),
 
-            debug: std::env::var_os("WAYLAND_DEBUG").is_some(),
+            dispatchers: HashMap::new(),
+
+            handle_shared: None,
+
+            log: ProtocolLog::from_env(),
+            lenient: false,
+            protocol_error: None,
         };
 
         this.registry = WlDisplay::INSTANCE.get_registry(&mut this);
+        this.set_leak_report(std::env::var_os("WAYRS_LEAK_REPORT").is_some());
 
         this
     }
@@ -167,23 +372,110 @@ impl<D> Connection<D> {
         let mut this = Self::connect()?;
         this.blocking_roundtrip()?;
         let globals = this.globals.clone();
-        this.event_queue.clear();
+        this.event_queues[0].clear();
         Ok((this, globals))
     }
 
     /// Async version of [`connect_and_collect_globals`](Self::connect_and_collect_globals).
-    #[cfg(feature = "tokio")]
-    #[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+    ///
+    /// Fails if there is no reactor, see [`async_roundtrip`](Self::async_roundtrip).
     #[deprecated = "use async_roundtrip() + bind_singleton() instead"]
     pub async fn async_connect_and_collect_globals() -> Result<(Self, Vec<GlobalArgs>), ConnectError>
     {
         let mut this = Self::connect()?;
         this.async_roundtrip().await?;
         let globals = this.globals.clone();
-        this.event_queue.clear();
+        this.event_queues[0].clear();
         Ok((this, globals))
     }
 
+    /// Enable or disable lenient mode.
+    ///
+    /// By default, events for non-existing objects and events with unknown opcodes are discarded
+    /// and an [`EventError`] is returned. In lenient mode, such events are discarded silently,
+    /// which may help with buggy or newer compositors. Discarded events are reported to
+    /// [`ProtocolLogger::diagnostic`] if debugging is enabled.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Enable or disable debug messages.
+    ///
+    /// By default, debug messages are enabled if `WAYLAND_DEBUG` environment variable is set. See
+    /// the [`logger`](crate::logger) module for details.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.log.enabled = debug;
+    }
+
+    /// Log only the messages of the interfaces matching `filter`.
+    ///
+    /// By default, the filter is taken from `WAYLAND_DEBUG` environment variable.
+    pub fn set_debug_filter(&mut self, filter: InterfaceFilter) {
+        self.log.filter = filter;
+    }
+
+    /// Set the logger which receives debug messages. The default logger prints them to stderr.
+    ///
+    /// Note that messages are logged only while debugging is [enabled](Self::set_debug).
+    pub fn set_protocol_logger(&mut self, logger: impl ProtocolLogger + 'static) {
+        self.log.set_logger(Box::new(logger));
+    }
+
+    /// Report the client objects which were not destroyed when the connection is dropped, see
+    /// [`leak_report`](Self::leak_report).
+    ///
+    /// The report is passed to [`ProtocolLogger::diagnostic`] even if debugging is disabled. By
+    /// default it is enabled if `WAYRS_LEAK_REPORT` environment variable is set.
+    pub fn set_leak_report(&mut self, enabled: bool) {
+        self.object_mgr.leak_logger = enabled.then(|| self.log.logger.clone());
+    }
+
+    /// Get the fatal protocol error, if the compositor sent one.
+    #[must_use]
+    pub fn protocol_error(&self) -> Option<&ProtocolError> {
+        self.protocol_error.as_ref()
+    }
+
+    /// Set the [`Reactor`] used by the async methods.
+    ///
+    /// By default a reactor is created on first use, depending on the enabled features. See the
+    /// [`reactor`](crate::reactor) module for details.
+    pub fn set_reactor(&mut self, reactor: impl Reactor + 'static) {
+        self.reactor = Some(Box::new(reactor));
+    }
+
+    /// Set the maximum size of the socket buffers.
+    ///
+    /// The buffers grow as needed, up to this limit, instead of blocking when the server is slow
+    /// to read requests. Requests larger than the limit cannot be sent. The default is
+    /// [`DEFAULT_MAX_BUFFER_SIZE`](wayrs_core::transport::DEFAULT_MAX_BUFFER_SIZE).
+    pub fn set_max_buffer_size(&mut self, size: usize) {
+        self.socket.set_max_buffer_size(size);
+    }
+
+    pub(crate) fn reserve_buffers(&mut self, size: usize) {
+        self.socket.reserve(size);
+    }
+
+    /// Create a handle which can send requests from other threads.
+    ///
+    /// See [`ConnectionHandle`] for details.
+    pub fn handle(&mut self) -> io::Result<ConnectionHandle<D>> {
+        let shared = match &self.handle_shared {
+            Some(shared) => shared,
+            None => self.handle_shared.insert(Arc::new(Shared::new()?)),
+        };
+        Ok(ConnectionHandle::new(shared, self.object_mgr.client_ids()))
+    }
+
+    /// Get the file descriptor which becomes readable when a [`ConnectionHandle`] sends a request.
+    ///
+    /// Call [`flush`](Self::flush) when it is readable. Returns `None` if no handles were created.
+    #[must_use]
+    pub fn wakeup_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.handle_shared.as_deref().map(Shared::wakeup_fd)
+    }
+
     /// Get Wayland registry.
     ///
     /// At the moment, only a single registry can be created. This might or might not change in the
@@ -297,12 +589,29 @@ impl<D> Connection<D> {
     /// registry events.
     ///
     /// Calling this function on a destroyed object will most likely panic, but this is not
-    /// guarantied due to id-reuse.
+    /// guarantied due to id-reuse. Use [`try_set_callback_for`](Self::try_set_callback_for) if the
+    /// object may be destroyed.
     pub fn set_callback_for<P: Proxy, F: FnMut(EventCtx<D, P>) + Send + 'static>(
         &mut self,
         proxy: P,
         cb: F,
     ) {
+        if let Err(err) = self.try_set_callback_for(proxy, cb) {
+            panic!("{err}");
+        }
+    }
+
+    /// Same as [`set_callback_for`](Self::set_callback_for), but returns an error instead of
+    /// panicking if the object does not exist or is dead.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `proxy` is a `wl_registry`.
+    pub fn try_set_callback_for<P: Proxy, F: FnMut(EventCtx<D, P>) + Send + 'static>(
+        &mut self,
+        proxy: P,
+        cb: F,
+    ) -> Result<(), RequestError> {
         assert_ne!(
             P::INTERFACE,
             WlRegistry::INTERFACE,
@@ -312,36 +621,306 @@ impl<D> Connection<D> {
         let obj = self
             .object_mgr
             .get_object_mut(proxy.id())
-            .expect("attempt to set a callback for non-existing object");
+            .ok_or(RequestError::NonExistingObject(proxy.id()))?;
 
         assert_eq!(obj.object, proxy.id(), "object mismatch");
-        assert!(obj.is_alive, "attempt to set a callback for dead object");
+        if !obj.is_alive {
+            return Err(RequestError::DeadObject(obj.object));
+        }
 
         obj.cb = Some(Self::make_generic_cb(cb));
+        Ok(())
+    }
+
+    /// Set a callback which receives the events of a given object without copying their strings
+    /// and arrays.
+    ///
+    /// This is a fast path for high-frequency events, such as pointer motion or dmabuf feedback
+    /// tables. The callback is called as soon as an event is received, before the events of other
+    /// objects which are already queued are dispatched, so it has no access to the state. If
+    /// events of this object are still queued, new events are queued behind them and passed to
+    /// the callback when the queue is dispatched, so the events of an object are always handled
+    /// in order. Events which create objects are queued and passed to the regular callback of the
+    /// object as usual.
+    ///
+    /// Events with invalid arguments are reported as [`EventError::InvalidData`]. If such an
+    /// event was queued, it is discarded with a diagnostic.
+    ///
+    /// # Panics
+    ///
+    /// This method panics in the same cases as [`set_callback_for`](Self::set_callback_for).
+    pub fn set_borrowed_callback_for<P, F>(&mut self, proxy: P, cb: F)
+    where
+        P: Proxy,
+        F: for<'a> FnMut(P, P::BorrowedEvent<'a>) + Send + 'static,
+    {
+        if let Err(err) = self.try_set_borrowed_callback_for(proxy, cb) {
+            panic!("{err}");
+        }
+    }
+
+    /// Same as [`set_borrowed_callback_for`](Self::set_borrowed_callback_for), but returns an
+    /// error instead of panicking if the object does not exist or is dead.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `proxy` is a `wl_registry`.
+    pub fn try_set_borrowed_callback_for<P, F>(
+        &mut self,
+        proxy: P,
+        mut cb: F,
+    ) -> Result<(), RequestError>
+    where
+        P: Proxy,
+        F: for<'a> FnMut(P, P::BorrowedEvent<'a>) + Send + 'static,
+    {
+        assert_ne!(
+            P::INTERFACE,
+            WlRegistry::INTERFACE,
+            "attempt to set a callback for wl_registry"
+        );
+
+        let obj = self
+            .object_mgr
+            .get_object_mut(proxy.id())
+            .ok_or(RequestError::NonExistingObject(proxy.id()))?;
+
+        assert_eq!(obj.object, proxy.id(), "object mismatch");
+        if !obj.is_alive {
+            return Err(RequestError::DeadObject(obj.object));
+        }
+
+        obj.borrowed_cb = Some(Box::new(move |object, opcode, args| {
+            let proxy: P = object.try_into().unwrap();
+            let event = P::parse_event_borrowed(opcode, args, object.version)?;
+            cb(proxy, event);
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    /// Dispatch the events of objects of interface `P` to the [`Dispatch<P>`] implementation of
+    /// the state.
+    ///
+    /// The implementation is used for all objects of this interface which do not have a callback.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `P` is `wl_registry`. Use [`add_registry_cb`](Self::add_registry_cb)
+    /// to listen to registry events.
+    pub fn register_dispatch<P: Proxy>(&mut self)
+    where
+        D: Dispatch<P>,
+    {
+        assert_ne!(
+            P::INTERFACE,
+            WlRegistry::INTERFACE,
+            "attempt to register dispatch for wl_registry"
+        );
+
+        fn dispatch<D: Dispatch<P>, P: Proxy>(
+            conn: &mut Connection<D>,
+            state: &mut D,
+            object: Object,
+            event: Message,
+        ) {
+            let proxy: P = object.try_into().unwrap();
+            let event = P::parse_event(event, object.version, &mut conn.msg_buffers_pool).unwrap();
+
+            // Removing the user data from the object to make borrow checker happy
+            let mut data = conn
+                .object_mgr
+                .get_object_mut(object.id)
+                .and_then(|obj| obj.user_data.take());
+            let typed_data = data.as_deref_mut().and_then(|data| data.downcast_mut());
+            state.event(conn, proxy, typed_data, event);
+
+            // Re-add the data if it wasn't replaced and the object wasn't destroyed
+            if let Some(obj) = conn.object_mgr.get_object_mut(object.id) {
+                if obj.is_alive && obj.user_data.is_none() {
+                    obj.user_data = data;
+                }
+            }
+        }
+
+        self.dispatchers.insert(P::INTERFACE, dispatch::<D, P>);
+    }
+
+    /// Create a new event queue.
+    #[must_use]
+    pub fn create_queue(&mut self) -> EventQueue {
+        self.event_queues.push(VecDeque::new());
+        EventQueue(self.event_queues.len() - 1)
+    }
+
+    /// Move an object to a given event queue.
+    ///
+    /// Events which were already received for this object stay in the old queue. Objects which
+    /// will be created by this object are put into the new queue.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if current set of objects does not contain an object with id identical
+    /// to `proxy.id()`, internally stored object differs from `proxy` or `queue` was created by a
+    /// different connection.
+    pub fn set_queue_for<P: Proxy>(&mut self, proxy: P, queue: EventQueue) {
+        assert!(queue.0 < self.event_queues.len(), "invalid event queue");
+
+        let obj = self
+            .object_mgr
+            .get_object_mut(proxy.id())
+            .expect("attempt to set a queue for non-existing object");
+
+        assert_eq!(obj.object, proxy.id(), "object mismatch");
+
+        obj.queue = queue;
+    }
+
+    /// Iterate over all live objects, including the ones created by the server.
+    ///
+    /// Objects are live until they are destroyed with a destructor request or event. This can be
+    /// used to find objects which are leaked over time.
+    pub fn objects(&self) -> impl Iterator<Item = ObjectInfo> + '_ {
+        self.object_mgr.live_objects().map(|obj| ObjectInfo {
+            object: obj.object,
+            has_callback: obj.cb.is_some(),
+        })
+    }
+
+    /// Describe the client objects which were not destroyed yet. Returns `None` if there are no
+    /// such objects.
+    ///
+    /// The objects are grouped by interface. Only objects which could have been destroyed are
+    /// reported: objects with a destructor request available at their version, and objects which
+    /// are destroyed by an event, such as `wl_callback`. Long-running clients can call it
+    /// periodically to find leaks without dropping the connection, see also
+    /// [`set_leak_report`](Self::set_leak_report).
+    #[must_use]
+    pub fn leak_report(&self) -> Option<String> {
+        self.object_mgr.leak_report()
+    }
+
+    /// Attach user data to an object, replacing the previous one.
+    ///
+    /// The data is dropped when the object is destroyed.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if current set of objects does not contain an object with id identical
+    /// to `proxy.id()`, internally stored object differs from `proxy` or object is dead.
+    pub fn set_user_data<T: Send + 'static>(&mut self, proxy: impl Proxy, data: T) {
+        let obj = self
+            .object_mgr
+            .get_object_mut(proxy.id())
+            .expect("attempt to set user data for non-existing object");
+
+        assert!(is_same_object(obj.object, proxy), "object mismatch");
+        assert!(obj.is_alive, "attempt to set user data for dead object");
+
+        obj.user_data = Some(Box::new(data));
+    }
+
+    /// Get the user data of an object.
+    ///
+    /// Returns `None` if the object is dead, its ID was reused by another object, or it does not
+    /// have user data of type `T`.
+    #[must_use]
+    pub fn user_data<T: 'static>(&self, proxy: impl Proxy) -> Option<&T> {
+        let obj = self.object_mgr.get_object(proxy.id())?;
+        if !obj.is_alive || !is_same_object(obj.object, proxy) {
+            return None;
+        }
+        obj.user_data.as_deref()?.downcast_ref()
+    }
+
+    /// Get a mutable reference to the user data of an object.
+    ///
+    /// Returns `None` if the object is dead, its ID was reused by another object, or it does not
+    /// have user data of type `T`.
+    #[must_use]
+    pub fn user_data_mut<T: 'static>(&mut self, proxy: impl Proxy) -> Option<&mut T> {
+        let obj = self.object_mgr.get_object_mut(proxy.id())?;
+        if !obj.is_alive || !is_same_object(obj.object, proxy) {
+            return None;
+        }
+        obj.user_data.as_deref_mut()?.downcast_mut()
     }
 
     /// Remove all callbacks.
     ///
-    /// You can use this function to change the "state type" of a connection.
+    /// You can use this function to change the "state type" of a connection. Existing
+    /// [`ConnectionHandle`]s are detached.
     #[must_use]
     #[deprecated = "this function is error-prone and best avoided"]
-    pub fn clear_callbacks<D2>(self) -> Connection<D2> {
+    pub fn clear_callbacks<D2>(mut self) -> Connection<D2> {
+        self.apply_handle_requests();
         Connection {
-            #[cfg(feature = "tokio")]
-            async_fd: self.async_fd,
+            reactor: self.reactor,
             socket: self.socket,
             msg_buffers_pool: self.msg_buffers_pool,
             object_mgr: self.object_mgr.clear_callbacks(),
-            event_queue: self.event_queue,
+            event_queues: self.event_queues,
+            deleted_ids: self.deleted_ids,
             requests_queue: self.requests_queue,
             break_dispatch: self.break_dispatch,
             registry: self.registry,
             globals: self.globals,
             registry_cbs: Some(Vec::new()),
-            debug: self.debug,
+            dispatchers: HashMap::new(),
+            handle_shared: None,
+            log: self.log,
+            lenient: self.lenient,
+            protocol_error: self.protocol_error,
         }
     }
 
+    /// Send a `wl_display.sync` request and call `f` when the compositor responds to it.
+    ///
+    /// Since the compositor processes requests in order, all the previous requests are handled by
+    /// the time `f` is called. Unlike [`blocking_roundtrip`](Self::blocking_roundtrip), this does
+    /// not block and does not affect other events, so any number of components can wait for their
+    /// own sync points at the same time.
+    pub fn sync_with<F: FnOnce(&mut Connection<D>, &mut D) + Send + 'static>(
+        &mut self,
+        f: F,
+    ) -> WlCallback {
+        let mut f = Some(f);
+        WlDisplay::INSTANCE.sync_with_cb(self, move |ctx| {
+            if let Some(f) = f.take() {
+                f(ctx.conn, ctx.state);
+            }
+        })
+    }
+
+    /// Send a `wl_display.sync` request and return a future which resolves when the compositor
+    /// responds to it.
+    ///
+    /// The future does not borrow the connection and resolves when the response is dispatched,
+    /// which must be done separately, e.g. by the event loop of the application. See
+    /// [`sync_with`](Self::sync_with) for more details.
+    ///
+    /// The future fails if the callback is dropped before the response is received, e.g. because
+    /// the connection was dropped.
+    pub fn sync(&mut self) -> impl Future<Output = io::Result<()>> + Send + 'static {
+        let point = Arc::new(Mutex::new(SyncPoint::default()));
+        let notifier = SyncNotifier(point.clone());
+        WlDisplay::INSTANCE.sync_with_cb(self, move |_| notifier.notify());
+
+        poll_fn(move |cx| {
+            let mut point = point.lock().unwrap_or_else(|e| e.into_inner());
+            if point.done {
+                Poll::Ready(Ok(()))
+            } else if point.dropped {
+                Poll::Ready(Err(io::Error::other(
+                    "sync callback was dropped before the response",
+                )))
+            } else {
+                point.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+
     /// Perform a blocking roundtrip.
     ///
     /// This function flushes the buffer of pending requests. All received events during the
@@ -352,8 +931,43 @@ impl<D> Connection<D> {
 
         loop {
             match self.recv_event(IoMode::Blocking)? {
-                QueuedEvent::Message(m) if m.header.object_id == sync_cb => break,
-                other => self.event_queue.push_back(other),
+                Some(QueuedEvent::Message(m)) if m.header.object_id == sync_cb => break,
+                Some(other) => self.queue_event(other),
+                None => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Perform a blocking roundtrip and dispatch the events of a given queue.
+    ///
+    /// Events of other queues are received but not dispatched, so no unrelated callbacks are
+    /// called.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if called from the context of a callback.
+    pub fn blocking_roundtrip_queue(&mut self, queue: EventQueue, state: &mut D) -> io::Result<()> {
+        self.blocking_roundtrip()?;
+        self.dispatch_queue(queue, state);
+        Ok(())
+    }
+
+    /// Same as [`blocking_roundtrip`](Self::blocking_roundtrip), but fails with
+    /// [`TimedOut`](io::ErrorKind::TimedOut) if the roundtrip does not complete in time.
+    ///
+    /// Events received before the timeout stay queued.
+    pub fn blocking_roundtrip_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now().checked_add(timeout);
+        let sync_cb = WlDisplay::INSTANCE.sync(self);
+        self.flush_until(deadline)?;
+
+        loop {
+            match self.recv_event_until(deadline)? {
+                Some(QueuedEvent::Message(m)) if m.header.object_id == sync_cb => break,
+                Some(other) => self.queue_event(other),
+                None => (),
             }
         }
 
@@ -361,22 +975,44 @@ impl<D> Connection<D> {
     }
 
     /// Async version of [`blocking_roundtrip`](Self::blocking_roundtrip).
-    #[cfg(feature = "tokio")]
-    #[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Unsupported`](io::ErrorKind::Unsupported) if there is no reactor: neither
+    /// `tokio` nor `async-io` feature is enabled and none was set with
+    /// [`set_reactor`](Self::set_reactor).
     pub async fn async_roundtrip(&mut self) -> io::Result<()> {
         let sync_cb = WlDisplay::INSTANCE.sync(self);
         self.async_flush().await?;
 
         loop {
             match self.async_recv_event().await? {
-                QueuedEvent::Message(m) if m.header.object_id == sync_cb => break,
-                other => self.event_queue.push_back(other),
+                Some(QueuedEvent::Message(m)) if m.header.object_id == sync_cb => break,
+                Some(other) => self.queue_event(other),
+                None => (),
             }
         }
 
         Ok(())
     }
 
+    /// Async version of [`blocking_roundtrip_queue`](Self::blocking_roundtrip_queue).
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Unsupported`](io::ErrorKind::Unsupported) if there is no reactor: neither
+    /// `tokio` nor `async-io` feature is enabled and none was set with
+    /// [`set_reactor`](Self::set_reactor).
+    pub async fn async_roundtrip_queue(
+        &mut self,
+        queue: EventQueue,
+        state: &mut D,
+    ) -> io::Result<()> {
+        self.async_roundtrip().await?;
+        self.dispatch_queue(queue, state);
+        Ok(())
+    }
+
     #[doc(hidden)]
     pub fn alloc_msg_args(&mut self) -> Vec<ArgValue> {
         self.msg_buffers_pool.get_args()
@@ -384,29 +1020,157 @@ impl<D> Connection<D> {
 
     #[doc(hidden)]
     pub fn send_request(&mut self, iface: &'static Interface, request: Message) {
-        let obj = self
-            .object_mgr
-            .get_object_mut(request.header.object_id)
-            .expect("attempt to send request for non-existing object");
-        assert!(obj.is_alive, "attempt to send request for dead object");
+        if let Err(err) = self.try_send_request(iface, request) {
+            panic!("{err}");
+        }
+    }
+
+    /// Same as [`send_request`](Self::send_request), but returns an error instead of panicking.
+    /// The request is discarded on error.
+    #[doc(hidden)]
+    pub fn try_send_request(
+        &mut self,
+        iface: &'static Interface,
+        request: Message,
+    ) -> Result<(), RequestError> {
+        let creates_object = request
+            .args
+            .iter()
+            .any(|arg| matches!(arg, ArgValue::NewId(_) | ArgValue::AnyNewId(..)));
+        if creates_object {
+            // Requests from handles which were queued before the new object was allocated are
+            // already applied. The rest may create objects with higher IDs, so they go after this
+            // request.
+            let result = self.queue_request(iface, request);
+            self.apply_handle_requests();
+            result
+        } else {
+            // Requests from handles go first, they may create the objects used by this request
+            self.apply_handle_requests();
+            self.queue_request(iface, request)
+        }
+    }
+
+    /// Apply the requests sent with [`ConnectionHandle`]s.
+    fn apply_handle_requests(&mut self) {
+        if let Some(shared) = &self.handle_shared {
+            let requests = shared.take_requests();
+            self.apply_queued_requests(requests);
+        }
+    }
+
+    fn apply_queued_requests(&mut self, requests: Vec<QueuedRequest<D>>) {
+        for queued in requests {
+            if let Some(state) = queued.new_object {
+                self.object_mgr.insert_client_object(state);
+            }
+            // Handles cannot report errors, and their objects may be destroyed at any time
+            if let Err(err) = self.queue_request(queued.iface, queued.request) {
+                self.log
+                    .diagnostic(format_args!("discarding request from handle: {err}"));
+            }
+        }
+    }
 
-        if self.debug {
-            eprintln!(
-                "[wayrs]  -> {:?}",
-                DebugMessage::new(&request, false, obj.object)
-            );
+    fn queue_request(
+        &mut self,
+        iface: &'static Interface,
+        request: Message,
+    ) -> Result<(), RequestError> {
+        if let Err(err) = self.check_request(iface, &request) {
+            self.discard_request(request);
+            return Err(err);
         }
 
+        let obj = self
+            .object_mgr
+            .get_object_mut(request.header.object_id)
+            .unwrap();
+        let desc = &iface.requests[request.header.opcode as usize];
+
+        self.log.message(Direction::Request, obj.object, &request);
+
         // Destroy object if request is destrctor
-        if iface.requests[request.header.opcode as usize].is_destructor {
-            obj.is_alive = false;
+        if desc.is_destructor {
+            obj.kill();
+        }
+
+        // New objects belong to the queue of their parent. Remember the interfaces of untyped
+        // new objects, so that the compositor can create objects of these interfaces too.
+        let queue = obj.queue;
+        for arg in &request.args {
+            let (ArgValue::NewId(id) | ArgValue::AnyNewId(_, _, id)) = arg else {
+                continue;
+            };
+            if let Some(new_obj) = self.object_mgr.get_object_mut(*id) {
+                new_obj.queue = queue;
+                if matches!(arg, ArgValue::AnyNewId(..)) {
+                    register_interface(new_obj.object.interface);
+                }
+            }
         }
 
         // Queue request
         self.requests_queue.push_back(request);
+        Ok(())
+    }
+
+    fn check_request(
+        &self,
+        iface: &'static Interface,
+        request: &Message,
+    ) -> Result<(), RequestError> {
+        let obj = self
+            .object_mgr
+            .get_object(request.header.object_id)
+            .ok_or(RequestError::NonExistingObject(request.header.object_id))?;
+        if !obj.is_alive {
+            return Err(RequestError::DeadObject(obj.object));
+        }
+
+        let desc = &iface.requests[request.header.opcode as usize];
+        if obj.object.version < desc.since {
+            return Err(RequestError::VersionTooLow(VersionTooLow {
+                object: obj.object,
+                request: desc.name,
+                since: desc.since,
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// Drop a request which was not sent, freeing the objects it would have created.
+    fn discard_request(&mut self, request: Message) {
+        for arg in &request.args {
+            if let ArgValue::NewId(id) | ArgValue::AnyNewId(_, _, id) = arg {
+                if self.object_mgr.get_object(*id).is_some() {
+                    self.object_mgr.delete_client_object(*id);
+                }
+            }
+        }
+        self.msg_buffers_pool.reuse_args(request.args);
+    }
+
+    /// Receive the next event. Returns `None` if the event was handled by a borrowed callback.
+    fn recv_event(&mut self, mode: IoMode) -> io::Result<Option<QueuedEvent>> {
+        loop {
+            match self.try_recv_event(mode) {
+                Err(err) if self.lenient && EventError::from_io(&err).is_some() => {
+                    self.log.diagnostic(format_args!("discarding event: {err}"));
+                }
+                result => return result,
+            }
+        }
     }
 
-    fn recv_event(&mut self, mode: IoMode) -> io::Result<QueuedEvent> {
+    /// Receive the next event. Events which cannot be handled are discarded and reported as
+    /// [`EventError`].
+    fn try_recv_event(&mut self, mode: IoMode) -> io::Result<Option<QueuedEvent>> {
+        if let Some(err) = &self.protocol_error {
+            return Err(err.clone().into());
+        }
+
         let header = self
             .socket
             .peek_message_header(mode)
@@ -415,49 +1179,79 @@ impl<D> Connection<D> {
                 other => io::Error::new(io::ErrorKind::InvalidData, other),
             })?;
 
-        let obj = self
-            .object_mgr
-            .get_object_mut(header.object_id)
-            .expect("received event for non-existing object");
-        let object = obj.object;
-        let signature = object
-            .interface
-            .events
-            .get(header.opcode as usize)
-            .expect("incorrect opcode")
-            .signature;
+        let desc = match self.object_mgr.get_object_mut(header.object_id) {
+            None => Err(EventError::UnknownObject {
+                object_id: header.object_id,
+                opcode: header.opcode,
+            }),
+            Some(obj) => match obj.object.interface.events.get(header.opcode as usize) {
+                Some(desc) => Ok((
+                    obj.object,
+                    obj.queue,
+                    desc.signature,
+                    // Queued events of the object must be handled first
+                    obj.borrowed_cb.is_some() && obj.queued_events == 0,
+                )),
+                None => Err(EventError::UnknownOpcode {
+                    object: obj.object,
+                    opcode: header.opcode,
+                }),
+            },
+        };
+        let (object, queue, signature, has_borrowed_cb) = match desc {
+            Ok(desc) => desc,
+            Err(err) => {
+                // Skip the message, so the connection can still be used
+                self.socket
+                    .skip_message(header, mode)
+                    .map_err(recv_error_to_io)?;
+                return Err(err.into());
+            }
+        };
+
+        // Events which create objects are always queued, since the objects must be registered
+        if has_borrowed_cb
+            && !signature
+                .iter()
+                .any(|arg| matches!(arg, ArgType::NewId(_) | ArgType::AnyNewId))
+        {
+            self.recv_borrowed_event(header, object, signature, mode)?;
+            return Ok(None);
+        }
 
         let event = self
             .socket
             .recv_message(header, signature, &mut self.msg_buffers_pool, mode)
-            .map_err(|err| match err {
-                RecvMessageError::Io(io) => io,
-                other => io::Error::new(io::ErrorKind::InvalidData, other),
-            })?;
-        if self.debug {
-            eprintln!("[wayrs] {:?}", DebugMessage::new(&event, true, object));
-        }
+            .map_err(recv_error_to_io)?;
+        self.log.message(Direction::Event, object, &event);
 
         if event.header.object_id == ObjectId::DISPLAY {
             match WlDisplay::parse_event(event, 1, &mut self.msg_buffers_pool).unwrap() {
                 wl_display::Event::Error(err) => {
                     // Catch protocol error as early as possible
-                    return Err(io::Error::other(format!(
-                        "Error in object {} (code({})): {}",
-                        err.object_id.0,
-                        err.code,
-                        err.message.to_string_lossy(),
-                    )));
+                    let object = self
+                        .object_mgr
+                        .get_object_mut(err.object_id)
+                        .map(|obj| obj.object);
+                    let err = ProtocolError {
+                        object_id: err.object_id,
+                        object,
+                        code: err.code,
+                        code_name: object.and_then(|obj| obj.interface.error_name(err.code)),
+                        message: err.message,
+                    };
+                    self.protocol_error = Some(err.clone());
+                    return Err(err.into());
                 }
                 wl_display::Event::DeleteId(id) => {
-                    return Ok(QueuedEvent::DeleteId(ObjectId(
+                    return Ok(Some(QueuedEvent::DeleteId(ObjectId(
                         NonZeroU32::new(id).ok_or_else(|| {
                             io::Error::new(
                                 io::ErrorKind::InvalidData,
                                 "wl_display.delete_id with null id",
                             )
                         })?,
-                    )));
+                    ))));
                 }
             };
         }
@@ -474,53 +1268,173 @@ impl<D> Connection<D> {
                     }
                 }
             }
-            return Ok(QueuedEvent::RegistryEvent(event));
+            return Ok(Some(QueuedEvent::RegistryEvent(event)));
+        }
+
+        // Make sure the interfaces of untyped new objects are known before allocating anything
+        for arg in &event.args {
+            if let ArgValue::AnyNewId(name, _, _) = arg {
+                if lookup_interface(name).is_none() {
+                    let err = EventError::UnknownInterface {
+                        object,
+                        opcode: header.opcode,
+                        interface: name.clone().into_owned(),
+                    };
+                    self.msg_buffers_pool.reuse_args(event.args);
+                    return Err(err.into());
+                }
+            }
         }
 
         // Allocate objects if necessary
-        let signature = object
-            .interface
-            .events
-            .get(header.opcode as usize)
-            .expect("incorrect opcode")
-            .signature;
         for (arg, arg_ty) in event.args.iter().zip(signature) {
             match arg {
                 ArgValue::NewId(id) => {
                     let ArgType::NewId(interface) = arg_ty else {
                         unreachable!()
                     };
-                    self.object_mgr.register_server_object(Object {
-                        id: *id,
-                        interface,
-                        version: object.version,
-                    });
+                    self.object_mgr
+                        .register_server_object(Object {
+                            id: *id,
+                            interface,
+                            version: object.version,
+                        })
+                        .queue = queue;
+                }
+                ArgValue::AnyNewId(name, version, id) => {
+                    self.object_mgr
+                        .register_server_object(Object {
+                            id: *id,
+                            interface: lookup_interface(name).unwrap(),
+                            version: *version,
+                        })
+                        .queue = queue;
                 }
-                ArgValue::AnyNewId(_, _, _) => unimplemented!(),
                 _ => (),
             }
         }
 
-        Ok(QueuedEvent::Message(event))
+        Ok(Some(QueuedEvent::Message(event)))
     }
 
-    #[cfg(feature = "tokio")]
-    async fn async_recv_event(&mut self) -> io::Result<QueuedEvent> {
-        let mut async_fd = match self.async_fd.take() {
-            Some(fd) => fd,
-            None => AsyncFd::new(self.as_raw_fd())?,
-        };
+    /// Receive an event and pass it to the borrowed callback of its object.
+    fn recv_borrowed_event(
+        &mut self,
+        header: MessageHeader,
+        object: Object,
+        signature: &[ArgType],
+        mode: IoMode,
+    ) -> io::Result<()> {
+        let obj = self.object_mgr.get_object_mut(object.id).unwrap();
+
+        if self.log.is_enabled_for(object) {
+            // The message is copied only to be logged
+            let event = self
+                .socket
+                .recv_message(header, signature, &mut self.msg_buffers_pool, mode)
+                .map_err(recv_error_to_io)?;
+            self.log.message(Direction::Event, object, &event);
+            let args = event
+                .args
+                .iter()
+                .map(ArgValue::try_borrow)
+                .collect::<io::Result<Vec<_>>>()?;
+            let result = call_borrowed_cb(obj, header.opcode, &mut args.into_iter());
+            self.msg_buffers_pool.reuse_args(event.args);
+            result?;
+        } else {
+            let mut event = self
+                .socket
+                .recv_message_borrowed(header, signature, mode)
+                .map_err(recv_error_to_io)?;
+            call_borrowed_cb(obj, header.opcode, &mut event.args)?;
+        }
 
-        loop {
-            let mut fd_guard = async_fd.readable_mut().await?;
-            match self.recv_event(IoMode::NonBlocking) {
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => fd_guard.clear_ready(),
-                result => {
-                    self.async_fd = Some(async_fd);
-                    return result;
+        Ok(())
+    }
+
+    /// Put a received event into the queue of its object.
+    ///
+    /// `wl_display.delete_id` events are not queued, see [`Self::free_deleted_ids`].
+    fn queue_event(&mut self, event: QueuedEvent) {
+        let object_id = match &event {
+            QueuedEvent::DeleteId(id) => {
+                self.deleted_ids.push(*id);
+                return;
+            }
+            QueuedEvent::RegistryEvent(_) => self.registry.id(),
+            QueuedEvent::Message(msg) => msg.header.object_id,
+        };
+        let is_message = matches!(event, QueuedEvent::Message(_));
+        let queue = match self.object_mgr.get_object_mut(object_id) {
+            Some(obj) => {
+                if is_message {
+                    obj.queued_events += 1;
                 }
+                obj.queue
+            }
+            None => EventQueue::DEFAULT,
+        };
+        self.event_queues[queue.0].push_back(event);
+    }
+
+    /// Free the IDs of deleted objects, unless the events of an object are still queued.
+    ///
+    /// This is done on every dispatch regardless of the queue, so that the IDs of objects in
+    /// queues which are rarely dispatched are not leaked. Objects with queued events are deleted
+    /// after these events are dispatched.
+    fn free_deleted_ids(&mut self) {
+        let object_mgr = &mut self.object_mgr;
+        let event_queues = &self.event_queues;
+        self.deleted_ids.retain(|&id| {
+            // The object may have been moved to another queue after its events were received
+            let has_events = event_queues
+                .iter()
+                .flatten()
+                .any(|event| matches!(event, QueuedEvent::Message(m) if m.header.object_id == id));
+            if !has_events {
+                object_mgr.delete_client_object(id);
             }
+            has_events
+        });
+    }
+
+    async fn async_recv_event(&mut self) -> io::Result<Option<QueuedEvent>> {
+        self.async_io(Interest::Readable, |this| {
+            this.recv_event(IoMode::NonBlocking)
+        })
+        .await
+    }
+
+    /// Perform a non-blocking operation, waiting for the socket to become ready if it would block.
+    async fn async_io<T>(
+        &mut self,
+        interest: Interest,
+        mut op: impl FnMut(&mut Self) -> io::Result<T>,
+    ) -> io::Result<T> {
+        // Try to just perform the operation before even touching the reactor. In many cases it
+        // does not block.
+        match op(self) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            result => return result,
         }
+
+        let mut reactor = match self.reactor.take() {
+            Some(reactor) => reactor,
+            None => default_reactor(self.as_fd())?,
+        };
+
+        let mut output = None;
+        let result = poll_fn(|cx| {
+            reactor.poll_io(cx, interest, &mut || {
+                output = Some(op(self)?);
+                Ok(())
+            })
+        })
+        .await;
+
+        self.reactor = Some(reactor);
+        result.map(|()| output.unwrap())
     }
 
     /// Receive events from Wayland socket.
@@ -545,20 +1459,106 @@ impl<D> Connection<D> {
 
             at_least_one = true;
             mode = IoMode::NonBlocking;
-            self.event_queue.push_back(msg);
+            if let Some(msg) = msg {
+                self.queue_event(msg);
+            }
+        }
+    }
+
+    /// Same as [`recv_events`](Self::recv_events) with [`Blocking`](IoMode::Blocking) mode, but
+    /// fails with [`TimedOut`](io::ErrorKind::TimedOut) if no events are received in time.
+    pub fn recv_events_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now().checked_add(timeout);
+        if let Some(msg) = self.recv_event_until(deadline)? {
+            self.queue_event(msg);
+        }
+
+        loop {
+            match self.recv_event(IoMode::NonBlocking) {
+                Ok(Some(msg)) => self.queue_event(msg),
+                Ok(None) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    /// Receive an event, waiting for the socket to become readable until `deadline`.
+    fn recv_event_until(&mut self, deadline: Option<Instant>) -> io::Result<Option<QueuedEvent>> {
+        loop {
+            match self.recv_event(IoMode::NonBlocking) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.poll_until(libc::POLLIN, deadline)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Flush the connection, waiting for the socket to become writable until `deadline`.
+    fn flush_until(&mut self, deadline: Option<Instant>) -> io::Result<()> {
+        loop {
+            match self.flush(IoMode::NonBlocking) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.poll_until(libc::POLLOUT, deadline)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Wait for `events` on the socket. `None` deadline means no timeout.
+    fn poll_until(&self, events: libc::c_short, deadline: Option<Instant>) -> io::Result<()> {
+        loop {
+            let timeout = match deadline {
+                // Round up, so that we do not wake up right before the deadline
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_nanos()
+                    .div_ceil(1_000_000)
+                    .try_into()
+                    .unwrap_or(libc::c_int::MAX),
+                None => -1,
+            };
+            let mut fd = libc::pollfd {
+                fd: self.as_raw_fd(),
+                events,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut fd, 1, timeout) } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting for the compositor",
+                    ))
+                }
+                _ => return Ok(()),
+            }
         }
     }
 
     /// Async version of [`recv_events`](Self::recv_events).
-    #[cfg(feature = "tokio")]
-    #[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Unsupported`](io::ErrorKind::Unsupported) if there is no reactor: neither
+    /// `tokio` nor `async-io` feature is enabled and none was set with
+    /// [`set_reactor`](Self::set_reactor).
     pub async fn async_recv_events(&mut self) -> io::Result<()> {
-        let msg = self.async_recv_event().await?;
-        self.event_queue.push_back(msg);
+        if let Some(msg) = self.async_recv_event().await? {
+            self.queue_event(msg);
+        }
 
         loop {
             match self.recv_event(IoMode::NonBlocking) {
-                Ok(msg) => self.event_queue.push_back(msg),
+                Ok(Some(msg)) => self.queue_event(msg),
+                Ok(None) => (),
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
                 Err(e) => return Err(e),
             };
@@ -566,14 +1566,25 @@ impl<D> Connection<D> {
     }
 
     /// Send the queue of pending request to the server.
+    ///
+    /// If a request exceeds the limits of the socket buffers (see
+    /// [`set_max_buffer_size`](Self::set_max_buffer_size)), it is dropped and an error wrapping
+    /// [`BufferLimitError`] is returned.
     pub fn flush(&mut self, mode: IoMode) -> io::Result<()> {
+        self.apply_handle_requests();
+
         // Send pending messages
         while let Some(msg) = self.requests_queue.pop_front() {
             if let Err(SendMessageError { msg, err }) =
                 self.socket
                     .write_message(msg, &mut self.msg_buffers_pool, mode)
             {
-                self.requests_queue.push_front(msg);
+                // A message which exceeds the buffer limits will never be sent, so drop it
+                if BufferLimitError::from_io(&err).is_some() {
+                    self.msg_buffers_pool.reuse_args(msg.args);
+                } else {
+                    self.requests_queue.push_front(msg);
+                }
                 return Err(err);
             }
         }
@@ -583,43 +1594,42 @@ impl<D> Connection<D> {
     }
 
     /// Async version of [`flush`](Self::flush).
-    #[cfg(feature = "tokio")]
-    #[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Unsupported`](io::ErrorKind::Unsupported) if there is no reactor: neither
+    /// `tokio` nor `async-io` feature is enabled and none was set with
+    /// [`set_reactor`](Self::set_reactor).
     pub async fn async_flush(&mut self) -> io::Result<()> {
-        // Try to just flush before even touching async fd. In many cases flushing does not block.
-        match self.flush(IoMode::NonBlocking) {
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
-            result => return result,
-        }
-
-        let mut async_fd = match self.async_fd.take() {
-            Some(fd) => fd,
-            None => AsyncFd::new(self.as_raw_fd())?,
-        };
-
-        loop {
-            let mut fd_guard = async_fd.writable_mut().await?;
-            match self.flush(IoMode::NonBlocking) {
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => fd_guard.clear_ready(),
-                result => {
-                    self.async_fd = Some(async_fd);
-                    return result;
-                }
-            }
-        }
+        self.async_io(Interest::Writable, |this| this.flush(IoMode::NonBlocking))
+            .await
     }
 
-    /// Empty the queue of pending events, calling a callback (if set) for each event.
+    /// Empty the default queue of pending events, calling a callback (if set) for each event.
+    ///
+    /// Events of other queues are not dispatched, see [`EventQueue`].
     ///
     /// # Panics
     ///
     /// This method panics if called from the context of a callback.
     pub fn dispatch_events(&mut self, state: &mut D) {
+        self.dispatch_queue(EventQueue::DEFAULT, state);
+    }
+
+    /// Empty a given queue of pending events, calling a callback (if set) for each event.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if called from the context of a callback, or if `queue` was created by
+    /// a different connection.
+    pub fn dispatch_queue(&mut self, queue: EventQueue, state: &mut D) {
+        assert!(queue.0 < self.event_queues.len(), "invalid event queue");
         self.break_dispatch = false;
+        self.free_deleted_ids();
 
-        while let Some(event) = self.event_queue.pop_front() {
+        while let Some(event) = self.event_queues[queue.0].pop_front() {
             match event {
-                QueuedEvent::DeleteId(id) => self.object_mgr.delete_client_object(id),
+                QueuedEvent::DeleteId(_) => unreachable!("delete_id events are not queued"),
                 QueuedEvent::RegistryEvent(event) => {
                     let mut registry_cbs = self
                         .registry_cbs
@@ -638,9 +1648,41 @@ impl<D> Connection<D> {
                 }
                 QueuedEvent::Message(event) => {
                     let object = match self.object_mgr.get_object_mut(event.header.object_id) {
-                        Some(obj) if obj.is_alive => obj,
-                        _ => continue, // Ignore unknown/dead objects
+                        Some(obj) => {
+                            obj.queued_events = obj.queued_events.saturating_sub(1);
+                            obj
+                        }
+                        None => continue,
                     };
+                    if !object.is_alive {
+                        continue; // Ignore dead objects
+                    }
+
+                    // Events received before the borrowed callback was set, or while earlier
+                    // events were queued
+                    let creates_objects = event
+                        .args
+                        .iter()
+                        .any(|arg| matches!(arg, ArgValue::NewId(_) | ArgValue::AnyNewId(..)));
+                    if object.borrowed_cb.is_some() && !creates_objects {
+                        let result = event
+                            .args
+                            .iter()
+                            .map(ArgValue::try_borrow)
+                            .collect::<io::Result<Vec<_>>>()
+                            .and_then(|args| {
+                                call_borrowed_cb(object, event.header.opcode, &mut args.into_iter())
+                                    .map_err(io::Error::from)
+                            });
+                        self.msg_buffers_pool.reuse_args(event.args);
+                        if let Err(err) = result {
+                            self.log.diagnostic(format_args!("discarding event: {err}"));
+                        }
+                        if self.break_dispatch {
+                            break;
+                        }
+                        continue;
+                    }
 
                     // Removing the callback from the object to make borrow checker happy
                     let mut object_cb = object.cb.take();
@@ -649,13 +1691,15 @@ impl<D> Connection<D> {
 
                     if let Some(cb) = &mut object_cb {
                         cb(self, state, object, event);
+                    } else if let Some(&dispatch) = self.dispatchers.get(&object.interface) {
+                        dispatch(self, state, object, event);
                     }
 
                     let object = self.object_mgr.get_object_mut(object.id).unwrap();
 
                     // Destroy object if event is destructor.
                     if object.object.interface.events[opcode as usize].is_destructor {
-                        object.is_alive = false;
+                        object.kill();
                     }
 
                     // Re-add callback if it wasn't re-set in the callback
@@ -669,11 +1713,14 @@ impl<D> Connection<D> {
                 }
             }
         }
+
+        self.free_deleted_ids();
     }
 
     /// Call this function from a callback to break the dispatch loop.
     ///
-    /// This will cause [`dispatch_events`](Self::dispatch_events) to return. Events that go after
+    /// This will cause [`dispatch_events`](Self::dispatch_events) or
+    /// [`dispatch_queue`](Self::dispatch_queue) to return. Events that go after
     /// current event are left in the queue.
     pub fn break_dispatch_loop(&mut self) {
         self.break_dispatch = true;
@@ -682,11 +1729,7 @@ impl<D> Connection<D> {
     /// Allocate a new object. Returned object must be sent in a request as a "new_id" argument.
     #[doc(hidden)]
     pub fn allocate_new_object<P: Proxy>(&mut self, version: u32) -> P {
-        let id = self
-            .object_mgr
-            .alloc_client_object(P::INTERFACE, version)
-            .object
-            .id;
+        let id = self.alloc_client_object(P::INTERFACE, version).object.id;
         P::new(id, version)
     }
 
@@ -698,12 +1741,38 @@ impl<D> Connection<D> {
         version: u32,
         cb: F,
     ) -> P {
-        let state = self.object_mgr.alloc_client_object(P::INTERFACE, version);
+        let state = self.alloc_client_object(P::INTERFACE, version);
         state.cb = Some(Self::make_generic_cb(cb));
         P::new(state.object.id, version)
     }
 
-    fn make_generic_cb<P: Proxy, F: FnMut(EventCtx<D, P>) + Send + 'static>(
+    fn alloc_client_object(
+        &mut self,
+        interface: &'static Interface,
+        version: u32,
+    ) -> &mut ObjectState<D> {
+        // Handles may have allocated lower IDs. Take their requests while the allocator is
+        // locked and apply them first, so that the IDs are used in order.
+        let client_ids = self.object_mgr.client_ids();
+        let mut ids = client_ids.lock();
+        let requests = self
+            .handle_shared
+            .as_ref()
+            .map(|shared| shared.take_requests())
+            .unwrap_or_default();
+        let id = ids.alloc();
+        drop(ids);
+        self.apply_queued_requests(requests);
+
+        self.object_mgr
+            .insert_client_object(ObjectState::new(Object {
+                id,
+                interface,
+                version,
+            }))
+    }
+
+    pub(crate) fn make_generic_cb<P: Proxy, F: FnMut(EventCtx<D, P>) + Send + 'static>(
         mut cb: F,
     ) -> GenericCallback<D> {
         // Note: if `F` does not capture anything, this `Box::new` will not allocate.
@@ -721,9 +1790,70 @@ impl<D> Connection<D> {
     }
 }
 
+#[derive(Default)]
+struct SyncPoint {
+    done: bool,
+    dropped: bool,
+    waker: Option<Waker>,
+}
+
+/// Wakes the future returned by [`Connection::sync`] when notified or dropped.
+struct SyncNotifier(Arc<Mutex<SyncPoint>>);
+
+impl SyncNotifier {
+    fn notify(&self) {
+        let mut point = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        point.done = true;
+        if let Some(waker) = point.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for SyncNotifier {
+    fn drop(&mut self) {
+        let mut point = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        point.dropped = true;
+        if let Some(waker) = point.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Whether `proxy` refers to `object`, and not to an older object with the same ID.
+fn is_same_object<P: Proxy>(object: Object, proxy: P) -> bool {
+    object.id == proxy.id() && object.interface == P::INTERFACE
+}
+
+fn recv_error_to_io(err: RecvMessageError) -> io::Error {
+    match err {
+        RecvMessageError::Io(io) => io,
+        other => io::Error::new(io::ErrorKind::InvalidData, other),
+    }
+}
+
+/// Pass an event to the borrowed callback of an object, destroying the object if the event is a
+/// destructor.
+fn call_borrowed_cb<'a, D>(
+    obj: &mut ObjectState<D>,
+    opcode: u16,
+    args: &mut dyn ExactSizeIterator<Item = BorrowedArgValue<'a>>,
+) -> Result<(), EventError> {
+    let object = obj.object;
+    let cb = obj.borrowed_cb.as_mut().unwrap();
+    let result = cb(object, opcode, args);
+    if object.interface.events[opcode as usize].is_destructor {
+        obj.kill();
+    }
+    result.map_err(|_| EventError::InvalidData { object, opcode })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::MockCompositor;
+    use wayrs_core::transport::loopback::{self, Loopback};
+    use wayrs_core::MessageHeader;
 
     fn assert_send<T: Send>() {}
 
@@ -731,4 +1861,764 @@ mod tests {
     fn send() {
         assert_send::<Connection<()>>();
     }
+
+    /// Spawn a fake server which advertises a single `wl_output` global and responds to a sync
+    /// request.
+    fn spawn_fake_server(server: Loopback) -> std::thread::JoinHandle<()> {
+        use std::ffi::CString;
+
+        std::thread::spawn(move || {
+            let mut socket = BufferedSocket::from(server);
+            let mut pool = MessageBuffersPool::default();
+            let mut recv = |socket: &mut BufferedSocket<_>, opcode: u16| {
+                let header = socket.peek_message_header(IoMode::Blocking).unwrap();
+                assert_eq!(header.object_id, ObjectId::DISPLAY);
+                assert_eq!(header.opcode, opcode);
+                let signature = WlDisplay::INTERFACE.requests[opcode as usize].signature;
+                let mut msg = socket
+                    .recv_message(header, signature, &mut pool, IoMode::Blocking)
+                    .unwrap();
+                let Some(ArgValue::NewId(id)) = msg.args.pop() else {
+                    panic!("expected new_id");
+                };
+                id
+            };
+            let send = |socket: &mut BufferedSocket<_>, id, opcode, args| {
+                let msg = Message {
+                    header: MessageHeader {
+                        object_id: id,
+                        size: 0,
+                        opcode,
+                    },
+                    args,
+                };
+                socket
+                    .write_message(msg, &mut MessageBuffersPool::default(), IoMode::Blocking)
+                    .map_err(|e| e.err)
+                    .unwrap();
+            };
+
+            let registry = recv(&mut socket, 1);
+            let name = CString::new("wl_output").unwrap();
+            let args = vec![ArgValue::Uint(7), ArgValue::String(name), ArgValue::Uint(4)];
+            send(&mut socket, registry, 0, args);
+
+            let callback = recv(&mut socket, 0);
+            send(&mut socket, callback, 0, vec![ArgValue::Uint(0)]);
+            let args = vec![ArgValue::Uint(callback.as_u32())];
+            send(&mut socket, ObjectId::DISPLAY, 1, args);
+            socket.flush(IoMode::Blocking).unwrap();
+        })
+    }
+
+    fn assert_fake_globals(conn: &Connection<()>) {
+        assert_eq!(conn.globals().len(), 1);
+        assert_eq!(conn.globals()[0].name, 7);
+        assert!(conn.globals()[0].is::<WlOutput>());
+        assert_eq!(conn.globals()[0].version, 4);
+    }
+
+    #[test]
+    fn loopback_roundtrip() {
+        let (client, server) = loopback::pair().unwrap();
+        let server = spawn_fake_server(server);
+
+        let mut conn = Connection::<()>::with_transport(client);
+        conn.blocking_roundtrip().unwrap();
+        server.join().unwrap();
+        assert_fake_globals(&conn);
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn async_io_roundtrip() {
+        let (client, server) = loopback::pair().unwrap();
+        let server = spawn_fake_server(server);
+
+        let mut conn = Connection::<()>::with_transport(client);
+        async_io::block_on(conn.async_roundtrip()).unwrap();
+        server.join().unwrap();
+        assert_fake_globals(&conn);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_roundtrip() {
+        let (client, server) = loopback::pair().unwrap();
+        let server = spawn_fake_server(server);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let mut conn = Connection::<()>::with_transport(client);
+        runtime.block_on(conn.async_roundtrip()).unwrap();
+        server.join().unwrap();
+        assert_fake_globals(&conn);
+    }
+
+    #[test]
+    fn untyped_new_id_in_event() {
+        use wayrs_core::MessageDesc;
+
+        static FACTORY: Interface = Interface {
+            name: c"test_untyped_factory",
+            version: 1,
+            events: &[MessageDesc {
+                name: "created",
+                is_destructor: false,
+                since: 1,
+                signature: &[ArgType::AnyNewId],
+            }],
+            requests: &[],
+            errors: &[],
+        };
+        static PRODUCT: Interface = Interface {
+            name: c"test_untyped_product",
+            version: 3,
+            events: &[],
+            requests: &[],
+            errors: &[],
+        };
+
+        let (client, server) = loopback::pair().unwrap();
+        let mut server = BufferedSocket::from(server);
+        let mut conn = Connection::<()>::with_transport(client);
+
+        let factory = Object {
+            id: ObjectId::MIN_SERVER,
+            interface: &FACTORY,
+            version: 1,
+        };
+        conn.object_mgr.register_server_object(factory);
+        let product_id = ObjectId(NonZeroU32::new(ObjectId::MIN_SERVER.as_u32() + 1).unwrap());
+
+        let mut send_created = || {
+            let msg = Message {
+                header: MessageHeader {
+                    object_id: factory.id,
+                    size: 0,
+                    opcode: 0,
+                },
+                args: vec![ArgValue::AnyNewId(PRODUCT.name.into(), 2, product_id)],
+            };
+            server
+                .write_message(msg, &mut MessageBuffersPool::default(), IoMode::NonBlocking)
+                .map_err(|e| e.err)
+                .unwrap();
+            server.flush(IoMode::NonBlocking).unwrap();
+        };
+
+        send_created();
+        let err = conn.recv_events(IoMode::NonBlocking).unwrap_err();
+        assert!(matches!(
+            EventError::from_io(&err),
+            Some(EventError::UnknownInterface { object, opcode: 0, interface })
+                if *object == factory && interface.as_c_str() == PRODUCT.name
+        ));
+        assert!(conn.object_mgr.get_object_mut(product_id).is_none());
+
+        register_interface(&PRODUCT);
+        send_created();
+        conn.recv_events(IoMode::NonBlocking).unwrap();
+        let product = conn.object_mgr.get_object_mut(product_id).unwrap().object;
+        assert!(std::ptr::eq(product.interface, &PRODUCT));
+        assert_eq!(product.version, 2);
+    }
+    #[test]
+    fn protocol_error_is_typed() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        mock.add_global::<WlShm>(1);
+        mock.roundtrip(&mut conn, &mut ());
+        let shm: WlShm = conn.bind_singleton(1).unwrap();
+        mock.roundtrip(&mut conn, &mut ());
+
+        let args = vec![
+            ArgValue::Object(shm.id()),
+            ArgValue::Uint(wl_shm::Error::InvalidStride.into()),
+            ArgValue::String(c"bad stride".into()),
+        ];
+        mock.send_event(WlDisplay::INSTANCE, "error", args);
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+
+        let err = conn.recv_events(IoMode::NonBlocking).unwrap_err();
+        let err = ProtocolError::from_io(&err).unwrap();
+        assert_eq!(err.object, Some(Object::from(shm)));
+        assert_eq!(err.interface(), Some(WlShm::INTERFACE));
+        assert_eq!(err.code_name, Some("invalid_stride"));
+        assert_eq!(err.message.as_c_str(), c"bad stride");
+        assert!(conn.protocol_error().is_some());
+    }
+
+    #[test]
+    fn unknown_events_are_recoverable() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        let send_raw = |mock: &mut MockCompositor, object_id, opcode| {
+            let msg = Message {
+                header: MessageHeader {
+                    object_id: ObjectId(NonZeroU32::new(object_id).unwrap()),
+                    size: 0,
+                    opcode,
+                },
+                args: vec![ArgValue::Uint(0)],
+            };
+            mock.socket
+                .write_message(msg, &mut MessageBuffersPool::default(), IoMode::NonBlocking)
+                .map_err(|e| e.err)
+                .unwrap();
+            mock.socket.flush(IoMode::NonBlocking).unwrap();
+        };
+
+        send_raw(&mut mock, 100, 0);
+        send_raw(&mut mock, 1, 5);
+        let err = conn.recv_events(IoMode::NonBlocking).unwrap_err();
+        assert!(matches!(
+            EventError::from_io(&err),
+            Some(EventError::UnknownObject { object_id, opcode: 0 }) if object_id.as_u32() == 100
+        ));
+        let err = conn.recv_events(IoMode::NonBlocking).unwrap_err();
+        assert!(matches!(
+            EventError::from_io(&err),
+            Some(EventError::UnknownOpcode { object, opcode: 5 }) if object.id == ObjectId::DISPLAY
+        ));
+
+        conn.set_lenient(true);
+        send_raw(&mut mock, 100, 0);
+        send_raw(&mut mock, 1, 5);
+        let err = conn.recv_events(IoMode::NonBlocking).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut ());
+        assert_eq!(conn.globals().len(), 1);
+    }
+
+    #[test]
+    fn event_queues_are_dispatched_separately() {
+        let (mut mock, mut conn) = MockCompositor::new::<Vec<&'static str>>();
+        let mut state = Vec::new();
+        mock.add_global::<WlOutput>(4);
+        mock.add_global::<WlSeat>(7);
+        mock.roundtrip(&mut conn, &mut state);
+
+        let queue = conn.create_queue();
+        conn.bind_singleton_with_cb::<WlOutput, _>(4, |ctx| ctx.state.push("output"))
+            .unwrap();
+        let seat = conn
+            .bind_singleton_with_cb::<WlSeat, _>(7, |ctx| ctx.state.push("seat"))
+            .unwrap();
+        conn.set_queue_for(seat, queue);
+        mock.roundtrip(&mut conn, &mut state);
+
+        let output = mock.objects_of::<WlOutput>()[0];
+        mock.send_event(output, "done", vec![]);
+        mock.send_event(seat, "capabilities", vec![ArgValue::Uint(1)]);
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(state, ["output"]);
+
+        conn.dispatch_queue(queue, &mut state);
+        assert_eq!(state, ["output", "seat"]);
+    }
+
+    #[test]
+    fn ids_are_freed_without_dispatching_queue() {
+        let (mut mock, mut conn) = MockCompositor::new::<Vec<&'static str>>();
+        let mut state = Vec::new();
+        mock.add_global::<WlSeat>(7);
+        mock.roundtrip(&mut conn, &mut state);
+
+        let queue = conn.create_queue();
+        let seat = conn
+            .bind_singleton_with_cb::<WlSeat, _>(7, |ctx| ctx.state.push("seat"))
+            .unwrap();
+        conn.set_queue_for(seat, queue);
+        mock.roundtrip(&mut conn, &mut state);
+
+        // The event is still queued, so the ID is not freed
+        mock.send_event(seat, "capabilities", vec![ArgValue::Uint(1)]);
+        mock.roundtrip(&mut conn, &mut state);
+        seat.release(&mut conn);
+        mock.roundtrip(&mut conn, &mut state);
+        assert!(conn.object_mgr.get_object(seat.id()).is_some());
+
+        conn.dispatch_queue(queue, &mut state);
+        assert!(state.is_empty(), "events of dead objects are ignored");
+        assert!(conn.object_mgr.get_object(seat.id()).is_none());
+
+        // Without queued events, the ID is freed when any queue is dispatched
+        let seat: WlSeat = conn.bind_singleton(7).unwrap();
+        conn.set_queue_for(seat, queue);
+        seat.release(&mut conn);
+        mock.roundtrip(&mut conn, &mut state);
+        mock.roundtrip(&mut conn, &mut state);
+        assert!(conn.object_mgr.get_object(seat.id()).is_none());
+    }
+
+    #[test]
+    #[should_panic = "invalid event queue"]
+    fn foreign_queues_are_rejected() {
+        let (_, mut conn) = MockCompositor::new::<()>();
+        let (_, mut other) = MockCompositor::new::<()>();
+        let queue = other.create_queue();
+        conn.dispatch_queue(queue, &mut ());
+    }
+
+    #[test]
+    fn dispatch_trait() {
+        #[derive(Default)]
+        struct State {
+            outputs_done: Vec<WlOutput>,
+            seat_cb_called: bool,
+        }
+
+        impl crate::Dispatch<WlOutput> for State {
+            type UserData = ();
+
+            fn event(
+                &mut self,
+                _: &mut Connection<Self>,
+                output: WlOutput,
+                _: Option<&mut ()>,
+                event: wl_output::Event,
+            ) {
+                assert!(matches!(event, wl_output::Event::Done));
+                self.outputs_done.push(output);
+            }
+        }
+
+        impl crate::Dispatch<WlSeat> for State {
+            type UserData = ();
+
+            fn event(
+                &mut self,
+                _: &mut Connection<Self>,
+                _: WlSeat,
+                _: Option<&mut ()>,
+                _: wl_seat::Event,
+            ) {
+                panic!("dispatch must not be used when a callback is set");
+            }
+        }
+
+        let (mut mock, mut conn) = MockCompositor::new::<State>();
+        let mut state = State::default();
+        conn.register_dispatch::<WlOutput>();
+        conn.register_dispatch::<WlSeat>();
+        mock.add_global::<WlOutput>(4);
+        mock.add_global::<WlSeat>(7);
+        mock.roundtrip(&mut conn, &mut state);
+
+        let output: WlOutput = conn.bind_singleton(4).unwrap();
+        let seat = conn
+            .bind_singleton_with_cb::<WlSeat, _>(7, |ctx| ctx.state.seat_cb_called = true)
+            .unwrap();
+        mock.roundtrip(&mut conn, &mut state);
+
+        mock.send_event(output, "done", vec![]);
+        mock.send_event(seat, "capabilities", vec![ArgValue::Uint(1)]);
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(state.outputs_done, [output]);
+        assert!(state.seat_cb_called);
+    }
+
+    #[test]
+    fn user_data() {
+        use std::sync::Arc;
+
+        let (mut mock, mut conn) = MockCompositor::new::<Vec<u32>>();
+        let mut state = Vec::new();
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut state);
+
+        let output = conn
+            .bind_singleton_with_cb::<WlOutput, _>(4, |ctx| {
+                let (scale, _) = ctx.user_data::<(u32, Arc<()>)>().unwrap();
+                ctx.state.push(*scale);
+            })
+            .unwrap();
+        let data = Arc::new(());
+        conn.set_user_data(output, (2u32, data.clone()));
+        assert!(conn.user_data::<u64>(output).is_none());
+        conn.user_data_mut::<(u32, Arc<()>)>(output).unwrap().0 = 3;
+        mock.roundtrip(&mut conn, &mut state);
+
+        mock.send_event(output, "done", vec![]);
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(state, [3]);
+
+        output.release(&mut conn);
+        assert_eq!(Arc::strong_count(&data), 1);
+        assert!(conn.user_data::<(u32, Arc<()>)>(output).is_none());
+    }
+
+    #[test]
+    fn user_data_in_dispatch() {
+        struct State;
+
+        impl crate::Dispatch<WlOutput> for State {
+            type UserData = u32;
+
+            fn event(
+                &mut self,
+                conn: &mut Connection<Self>,
+                output: WlOutput,
+                data: Option<&mut u32>,
+                _: wl_output::Event,
+            ) {
+                assert!(conn.user_data::<u32>(output).is_none());
+                *data.unwrap() += 1;
+            }
+        }
+
+        let (mut mock, mut conn) = MockCompositor::new::<State>();
+        conn.register_dispatch::<WlOutput>();
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut State);
+
+        let output: WlOutput = conn.bind_singleton(4).unwrap();
+        conn.set_user_data(output, 0u32);
+        mock.roundtrip(&mut conn, &mut State);
+
+        mock.send_event(output, "done", vec![]);
+        mock.send_event(output, "done", vec![]);
+        mock.roundtrip(&mut conn, &mut State);
+        assert_eq!(conn.user_data::<u32>(output), Some(&2));
+    }
+
+    #[test]
+    fn user_data_of_reused_ids() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        mock.add_global::<WlCompositor>(6);
+        mock.roundtrip(&mut conn, &mut ());
+        let compositor: WlCompositor = conn.bind_singleton(6).unwrap();
+
+        let surface = compositor.create_surface(&mut conn);
+        conn.set_user_data(surface, 1u32);
+        surface.destroy(&mut conn);
+        mock.roundtrip(&mut conn, &mut ());
+
+        // The ID is reused by an object of another interface
+        let region = compositor.create_region(&mut conn);
+        assert_eq!(region.id(), surface.id());
+        conn.set_user_data(region, 2u32);
+        assert_eq!(conn.user_data::<u32>(region), Some(&2));
+        assert_eq!(conn.user_data::<u32>(surface), None);
+        assert_eq!(conn.user_data_mut::<u32>(surface), None);
+    }
+
+    #[test]
+    fn borrowed_callbacks() {
+        use crate::logger::ProtocolRecord;
+        use std::sync::Arc;
+
+        let (mut mock, mut conn) = MockCompositor::new::<Vec<u32>>();
+        let mut state = Vec::new();
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut state);
+
+        let output = conn
+            .bind_singleton_with_cb::<WlOutput, _>(4, |ctx| ctx.state.push(0))
+            .unwrap();
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let names2 = names.clone();
+        conn.set_borrowed_callback_for(output, move |_, event| match event {
+            wl_output::BorrowedEvent::Name(name) => {
+                names2
+                    .lock()
+                    .unwrap()
+                    .push(name.to_str().unwrap().to_owned());
+            }
+            wl_output::BorrowedEvent::Scale(scale) => {
+                names2.lock().unwrap().push(scale.to_string());
+            }
+            _ => (),
+        });
+        mock.roundtrip(&mut conn, &mut state);
+
+        mock.send_event(output, "name", vec![ArgValue::String(c"DP-1".into())]);
+        mock.send_event(output, "scale", vec![ArgValue::Int(2)]);
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(*names.lock().unwrap(), ["DP-1", "2"]);
+        assert!(state.is_empty());
+
+        // Logged events are still delivered to the borrowed callback
+        let logged = Arc::new(Mutex::new(0));
+        let logged2 = logged.clone();
+        conn.set_protocol_logger(move |_: &ProtocolRecord| *logged2.lock().unwrap() += 1);
+        conn.set_debug(true);
+        mock.send_event(output, "name", vec![ArgValue::String(c"DP-2".into())]);
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(*names.lock().unwrap(), ["DP-1", "2", "DP-2"]);
+        assert!(*logged.lock().unwrap() >= 1);
+        assert!(state.is_empty());
+    }
+
+    #[test]
+    fn borrowed_events_are_ordered_and_checked() {
+        use std::sync::Arc;
+
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut ());
+        let output: WlOutput = conn.bind_singleton(4).unwrap();
+        mock.roundtrip(&mut conn, &mut ());
+
+        // This event is queued before the borrowed callback is set
+        mock.send_event(output, "scale", vec![ArgValue::Int(1)]);
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+        conn.recv_events(IoMode::NonBlocking).unwrap();
+
+        let scales = Arc::new(Mutex::new(Vec::new()));
+        let scales2 = scales.clone();
+        conn.set_borrowed_callback_for(output, move |_, event| {
+            if let wl_output::BorrowedEvent::Scale(scale) = event {
+                scales2.lock().unwrap().push(scale);
+            }
+        });
+
+        // Queued behind the first event
+        mock.send_event(output, "scale", vec![ArgValue::Int(2)]);
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+        conn.recv_events(IoMode::NonBlocking).unwrap();
+        assert!(scales.lock().unwrap().is_empty());
+        conn.dispatch_events(&mut ());
+        assert_eq!(*scales.lock().unwrap(), [1, 2]);
+
+        // Nothing is queued anymore, so the callback is called right away
+        mock.send_event(output, "scale", vec![ArgValue::Int(3)]);
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+        conn.recv_events(IoMode::NonBlocking).unwrap();
+        assert_eq!(*scales.lock().unwrap(), [1, 2, 3]);
+
+        // Unknown subpixel value
+        mock.send_event(
+            output,
+            "geometry",
+            vec![
+                ArgValue::Int(0),
+                ArgValue::Int(0),
+                ArgValue::Int(0),
+                ArgValue::Int(0),
+                ArgValue::Int(100),
+                ArgValue::String(c"make".into()),
+                ArgValue::String(c"model".into()),
+                ArgValue::Int(0),
+            ],
+        );
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+        let err = conn.recv_events(IoMode::NonBlocking).unwrap_err();
+        assert!(matches!(
+            EventError::from_io(&err),
+            Some(EventError::InvalidData { object, opcode: 0 }) if *object == output.id()
+        ));
+
+        // The connection is still usable
+        mock.send_event(output, "scale", vec![ArgValue::Int(4)]);
+        mock.roundtrip(&mut conn, &mut ());
+        assert_eq!(*scales.lock().unwrap(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn request_version_is_checked() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        mock.add_global::<WlOutput>(2);
+        mock.roundtrip(&mut conn, &mut ());
+        let output: WlOutput = conn.bind_singleton(2).unwrap();
+
+        let Err(RequestError::VersionTooLow(err)) = output.try_release(&mut conn) else {
+            panic!("expected VersionTooLow");
+        };
+        assert_eq!(err.object, Object::from(output));
+        assert_eq!(err.since, 3);
+        assert_eq!(
+            err.to_string(),
+            format!("request wl_output.release requires version 3, but {output:?} has version 2")
+        );
+
+        mock.roundtrip(&mut conn, &mut ());
+        assert!(mock.take_requests().is_empty());
+    }
+
+    #[test]
+    fn objects_and_leaks() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        mock.add_global::<WlOutput>(4);
+        mock.add_global::<WlCompositor>(6);
+        mock.roundtrip(&mut conn, &mut ());
+        let output: WlOutput = conn.bind_singleton_with_cb(4, |_| ()).unwrap();
+        let compositor: WlCompositor = conn.bind_singleton(6).unwrap();
+        let callback = WlDisplay::INSTANCE.sync(&mut conn);
+        let surface = compositor.create_surface(&mut conn);
+
+        let objects: Vec<_> = conn.objects().collect();
+        assert_eq!(objects.len(), 6);
+        let info = objects
+            .iter()
+            .find(|obj| obj.object == output.id())
+            .unwrap();
+        assert_eq!(info.object.version, 4);
+        assert!(info.has_callback);
+        assert!(
+            !objects
+                .iter()
+                .find(|obj| obj.object == surface.id())
+                .unwrap()
+                .has_callback
+        );
+
+        // wl_compositor has no destructor, so it is not reported
+        assert_eq!(
+            conn.leak_report().unwrap(),
+            format!(
+                "3 objects were not destroyed:\n  wl_callback: 1 (ids [{}])\n  wl_output: 1 (ids [{}])\n  wl_surface: 1 (ids [{}])",
+                callback.id().as_u32(),
+                output.id().as_u32(),
+                surface.id().as_u32(),
+            )
+        );
+
+        output.release(&mut conn);
+        surface.destroy(&mut conn);
+        mock.roundtrip(&mut conn, &mut ());
+        assert!(conn.objects().all(|obj| obj.object != surface.id()));
+        assert!(conn.leak_report().is_none());
+    }
+
+    #[test]
+    fn leak_report_on_drop() {
+        use crate::logger::ProtocolRecord;
+        use std::sync::Mutex;
+
+        struct Logger(Arc<Mutex<Vec<String>>>);
+
+        impl ProtocolLogger for Logger {
+            fn log(&mut self, _: &ProtocolRecord<'_>) {}
+
+            fn diagnostic(&mut self, message: fmt::Arguments<'_>) {
+                self.0.lock().unwrap().push(message.to_string());
+            }
+        }
+
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        let diagnostics = Arc::new(Mutex::new(Vec::new()));
+        // The report goes to the logger which is set when the connection is dropped
+        conn.set_leak_report(true);
+        conn.set_protocol_logger(Logger(diagnostics.clone()));
+        mock.add_global::<WlCompositor>(6);
+        mock.roundtrip(&mut conn, &mut ());
+        let compositor: WlCompositor = conn.bind_singleton(6).unwrap();
+        compositor.create_surface(&mut conn);
+
+        let report = conn.leak_report().unwrap();
+        drop(conn);
+        assert_eq!(*diagnostics.lock().unwrap(), [report]);
+    }
+
+    #[test]
+    fn requests_for_dead_objects() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        mock.add_global::<WlCompositor>(6);
+        mock.roundtrip(&mut conn, &mut ());
+        let compositor: WlCompositor = conn.bind_singleton(6).unwrap();
+        let surface = compositor.create_surface(&mut conn);
+        let callback = WlDisplay::INSTANCE.sync(&mut conn);
+        mock.roundtrip(&mut conn, &mut ());
+        mock.take_requests();
+
+        // The callback is destroyed by the `done` event
+        assert!(matches!(
+            conn.try_set_callback_for(callback, |_| ()),
+            Err(RequestError::NonExistingObject(id)) if id == callback.id()
+        ));
+
+        surface.destroy(&mut conn);
+        let objects = conn.objects().count();
+        assert!(matches!(
+            surface.try_frame(&mut conn),
+            Err(RequestError::DeadObject(obj)) if obj == surface.id()
+        ));
+        assert!(matches!(
+            surface.try_commit(&mut conn),
+            Err(RequestError::DeadObject(_))
+        ));
+        // The object allocated for the failed request is freed
+        assert_eq!(conn.objects().count(), objects);
+
+        mock.roundtrip(&mut conn, &mut ());
+        let requests = mock.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].name, "destroy");
+    }
+
+    #[test]
+    fn timeouts() {
+        use std::time::Duration;
+
+        let (mut mock, mut conn) = MockCompositor::new::<Vec<WlOutput>>();
+        let mut state = Vec::new();
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut state);
+        let output = conn
+            .bind_singleton_with_cb::<WlOutput, _>(4, |ctx| ctx.state.push(ctx.proxy))
+            .unwrap();
+        mock.roundtrip(&mut conn, &mut state);
+
+        let err = conn
+            .recv_events_timeout(Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // The mock does not respond to the sync request until it dispatches the requests
+        mock.send_event(output, "done", vec![]);
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+        let err = conn
+            .blocking_roundtrip_timeout(Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        conn.dispatch_events(&mut state);
+        assert_eq!(state, [output]);
+
+        mock.dispatch_requests().unwrap();
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+        conn.recv_events_timeout(Duration::from_millis(10)).unwrap();
+    }
+
+    #[test]
+    fn sync_points() {
+        use std::future::Future;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake, Waker};
+
+        struct Flag(AtomicBool);
+
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let (mut mock, mut conn) = MockCompositor::new::<Vec<u32>>();
+        let mut state = Vec::new();
+
+        conn.sync_with(|_, state| state.push(1));
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut sync = std::pin::pin!(conn.sync());
+        conn.sync_with(|_, state| state.push(2));
+        assert!(sync.as_mut().poll(&mut cx).is_pending());
+
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(state, [1, 2]);
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(matches!(sync.as_mut().poll(&mut cx), Poll::Ready(Ok(()))));
+
+        let sync = conn.sync();
+        drop(conn);
+        assert!(matches!(
+            std::pin::pin!(sync).poll(&mut cx),
+            Poll::Ready(Err(_))
+        ));
+    }
 }
@@ -61,11 +61,39 @@ impl Debug for DebugMessage<'_> {
                     id.as_u32(),
                 )?,
                 ArgValue::String(x) | ArgValue::OptString(Some(x)) => write!(f, "{x:?}")?,
-                ArgValue::Array(_) => write!(f, "<array>")?,
-                ArgValue::Fd(x) => write!(f, "fd {}", x.as_raw_fd())?,
+                ArgValue::Array(bytes) => write_array(f, bytes)?,
+                ArgValue::Fd(x) => {
+                    let fd = x.as_raw_fd();
+                    write!(f, "fd {fd}")?;
+                    // Elsewhere there is no portable way to find the target, so only the number
+                    // is printed.
+                    #[cfg(target_os = "linux")]
+                    {
+                        if let Ok(target) = std::fs::read_link(format!("/proc/self/fd/{fd}")) {
+                            write!(f, " ({})", target.display())?;
+                        }
+                    }
+                }
             }
         }
 
         write!(f, ")")
     }
 }
+
+/// Arrays longer than this are truncated.
+const MAX_ARRAY_BYTES: usize = 64;
+
+fn write_array(f: &mut Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    write!(f, "array[{}] {{", bytes.len())?;
+    for (i, byte) in bytes.iter().take(MAX_ARRAY_BYTES).enumerate() {
+        if i != 0 {
+            f.write_str(" ")?;
+        }
+        write!(f, "{byte:02x}")?;
+    }
+    if bytes.len() > MAX_ARRAY_BYTES {
+        f.write_str(" ...")?;
+    }
+    f.write_str("}")
+}
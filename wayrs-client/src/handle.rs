@@ -0,0 +1,339 @@
+//! Sending requests from other threads
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::{AsFd, BorrowedFd, FromRawFd};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::object::{ClientIds, Object, ObjectState, Proxy};
+use crate::{Connection, EventCtx, RequestError};
+
+use wayrs_core::{ArgValue, Interface, Message, ObjectId};
+
+/// A type which can send requests: a [`Connection`] or a [`ConnectionHandle`].
+///
+/// Generated request methods accept any `RequestSink`. This trait is sealed.
+pub trait RequestSink<D>: private::Sealed {
+    #[doc(hidden)]
+    fn alloc_msg_args(&mut self) -> Vec<ArgValue>;
+
+    #[doc(hidden)]
+    fn send_request(&mut self, iface: &'static Interface, request: Message);
+
+    #[doc(hidden)]
+    fn try_send_request(
+        &mut self,
+        iface: &'static Interface,
+        request: Message,
+    ) -> Result<(), RequestError>;
+
+    #[doc(hidden)]
+    fn allocate_new_object<P: Proxy>(&mut self, version: u32) -> P;
+
+    #[doc(hidden)]
+    fn allocate_new_object_with_cb<P: Proxy, F: FnMut(EventCtx<D, P>) + Send + 'static>(
+        &mut self,
+        version: u32,
+        cb: F,
+    ) -> P;
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl<D> Sealed for crate::Connection<D> {}
+    impl<D> Sealed for super::ConnectionHandle<D> {}
+}
+
+impl<D> RequestSink<D> for Connection<D> {
+    fn alloc_msg_args(&mut self) -> Vec<ArgValue> {
+        Connection::alloc_msg_args(self)
+    }
+
+    fn send_request(&mut self, iface: &'static Interface, request: Message) {
+        Connection::send_request(self, iface, request);
+    }
+
+    fn try_send_request(
+        &mut self,
+        iface: &'static Interface,
+        request: Message,
+    ) -> Result<(), RequestError> {
+        Connection::try_send_request(self, iface, request)
+    }
+
+    fn allocate_new_object<P: Proxy>(&mut self, version: u32) -> P {
+        Connection::allocate_new_object(self, version)
+    }
+
+    fn allocate_new_object_with_cb<P: Proxy, F: FnMut(EventCtx<D, P>) + Send + 'static>(
+        &mut self,
+        version: u32,
+        cb: F,
+    ) -> P {
+        Connection::allocate_new_object_with_cb(self, version, cb)
+    }
+}
+
+/// A handle which can send requests from other threads.
+///
+/// Created with [`Connection::handle`]. Requests sent with a handle are queued and applied by the
+/// connection the next time it sends a request or flushes. To make sure the requests are flushed
+/// promptly, poll [`Connection::wakeup_fd`] along with the connection and call
+/// [`Connection::flush`] when it becomes readable.
+///
+/// Objects can be allocated with a handle too. Note that the connection does not know about such
+/// objects until the requests which create them are applied. Object IDs must be used in the order
+/// they are allocated, so other allocations block until the request which creates the object is
+/// sent with the handle.
+///
+/// Requests sent after the connection is dropped are discarded. Requests for objects which were
+/// destroyed by the time the requests are applied are discarded too.
+pub struct ConnectionHandle<D> {
+    shared: Weak<Shared<D>>,
+    client_ids: Arc<ClientIds>,
+    // An object allocated for the next request. Its ID is reserved until the request is queued.
+    new_object: Option<ObjectState<D>>,
+}
+
+/// The state shared between a connection and its handles.
+pub(crate) struct Shared<D> {
+    requests: Mutex<Vec<QueuedRequest<D>>>,
+    // An eventfd which is readable while there are queued requests.
+    wakeup: File,
+}
+
+pub(crate) struct QueuedRequest<D> {
+    pub new_object: Option<ObjectState<D>>,
+    pub iface: &'static Interface,
+    pub request: Message,
+}
+
+impl<D> Shared<D> {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            requests: Mutex::new(Vec::new()),
+            // SAFETY: `eventfd` returned a new valid file descriptor.
+            wakeup: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    pub fn wakeup_fd(&self) -> BorrowedFd<'_> {
+        self.wakeup.as_fd()
+    }
+
+    /// Take all the queued requests.
+    pub fn take_requests(&self) -> Vec<QueuedRequest<D>> {
+        // Reset the eventfd before taking the requests, so that a wakeup is never lost. Fails
+        // with `WouldBlock` if there were no wakeups.
+        let _ = (&self.wakeup).read(&mut [0; 8]);
+        mem::take(&mut *self.requests.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn push_request(&self, request: QueuedRequest<D>) {
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        if requests.is_empty() {
+            // Can only fail if the counter overflows, which means the connection is already woken.
+            let _ = (&self.wakeup).write(&1u64.to_ne_bytes());
+        }
+        requests.push(request);
+    }
+}
+
+impl<D> ConnectionHandle<D> {
+    pub(crate) fn new(shared: &Arc<Shared<D>>, client_ids: Arc<ClientIds>) -> Self {
+        Self {
+            shared: Arc::downgrade(shared),
+            client_ids,
+            new_object: None,
+        }
+    }
+
+    fn alloc_object_state(&mut self, interface: &'static Interface, version: u32) -> ObjectId {
+        assert!(
+            self.new_object.is_none(),
+            "allocated object was not sent in a request"
+        );
+        let id = self.client_ids.reserve();
+        let state = ObjectState::new(Object {
+            id,
+            interface,
+            version,
+        });
+        self.new_object = Some(state);
+        id
+    }
+}
+
+impl<D> Clone for ConnectionHandle<D> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            client_ids: self.client_ids.clone(),
+            new_object: None,
+        }
+    }
+}
+
+impl<D> Drop for ConnectionHandle<D> {
+    fn drop(&mut self) {
+        // Free the ID of an object which was allocated but never sent
+        if let Some(new_object) = self.new_object.take() {
+            self.client_ids.release(new_object.object.id, || false);
+        }
+    }
+}
+
+impl<D> RequestSink<D> for ConnectionHandle<D> {
+    fn alloc_msg_args(&mut self) -> Vec<ArgValue> {
+        Vec::new()
+    }
+
+    fn send_request(&mut self, iface: &'static Interface, request: Message) {
+        // The objects are not known here, so the request is checked by the connection
+        let _ = self.try_send_request(iface, request);
+    }
+
+    fn try_send_request(
+        &mut self,
+        iface: &'static Interface,
+        request: Message,
+    ) -> Result<(), RequestError> {
+        let Some(new_object) = self.new_object.take() else {
+            if let Some(shared) = self.shared.upgrade() {
+                shared.push_request(QueuedRequest {
+                    new_object: None,
+                    iface,
+                    request,
+                });
+            }
+            return Ok(());
+        };
+        let id = new_object.object.id;
+        self.client_ids.release(id, || {
+            let Some(shared) = self.shared.upgrade() else {
+                return false;
+            };
+            shared.push_request(QueuedRequest {
+                new_object: Some(new_object),
+                iface,
+                request,
+            });
+            true
+        });
+        Ok(())
+    }
+
+    fn allocate_new_object<P: Proxy>(&mut self, version: u32) -> P {
+        P::new(self.alloc_object_state(P::INTERFACE, version), version)
+    }
+
+    fn allocate_new_object_with_cb<P: Proxy, F: FnMut(EventCtx<D, P>) + Send + 'static>(
+        &mut self,
+        version: u32,
+        cb: F,
+    ) -> P {
+        let id = self.alloc_object_state(P::INTERFACE, version);
+        self.new_object.as_mut().unwrap().cb = Some(Connection::make_generic_cb(cb));
+        P::new(id, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::*;
+    use crate::testing::MockCompositor;
+
+    #[test]
+    fn requests_from_handle() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        let name = mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut ());
+        assert!(conn.wakeup_fd().is_none());
+
+        let mut handle = conn.handle().unwrap();
+        let registry = conn.registry();
+        let output = std::thread::spawn(move || {
+            let output: WlOutput = registry.bind(&mut handle, name, 4);
+            output.release(&mut handle);
+            output
+        })
+        .join()
+        .unwrap();
+        assert!(conn.wakeup_fd().is_some());
+
+        mock.roundtrip(&mut conn, &mut ());
+        let requests = mock.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].object, output.id());
+        assert_eq!(requests[0].name, "release");
+    }
+
+    #[test]
+    fn ids_are_used_in_order() {
+        use std::sync::mpsc;
+        use wayrs_core::MessageHeader;
+
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        mock.add_global::<WlCompositor>(6);
+        mock.roundtrip(&mut conn, &mut ());
+        let compositor: WlCompositor = conn.bind_singleton(6).unwrap();
+        let mut handle = conn.handle().unwrap();
+
+        let surface1 = compositor.create_surface(&mut handle);
+        let surface2 = compositor.create_surface(&mut conn);
+        let surface3 = compositor.create_surface(&mut handle);
+
+        // The connection allocates an object while a handle is between allocating and sending
+        let (tx, rx) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let surface: WlSurface = handle.allocate_new_object(6);
+            tx.send(()).unwrap();
+            // Send the request only once the connection is blocked on the reserved ID
+            while !handle.client_ids.has_waiters() {
+                std::thread::yield_now();
+            }
+            let request = Message {
+                header: MessageHeader {
+                    object_id: compositor.id(),
+                    size: 0,
+                    opcode: 0,
+                },
+                args: vec![ArgValue::NewId(surface.id())],
+            };
+            handle.send_request(WlCompositor::INTERFACE, request);
+            handle
+        });
+        rx.recv().unwrap();
+        let region = compositor.create_region(&mut conn);
+        let mut handle = thread.join().unwrap();
+
+        // The ID of an object which was not sent is freed
+        let unsent: WlSurface = handle.allocate_new_object(6);
+        drop(handle);
+        let surface5 = compositor.create_surface(&mut conn);
+        assert_eq!(surface5.id(), unsent.id());
+
+        mock.roundtrip(&mut conn, &mut ());
+        let created: Vec<_> = mock
+            .take_requests()
+            .into_iter()
+            .map(|request| match request.args[..] {
+                [ArgValue::NewId(id)] => id,
+                _ => panic!("unexpected request {}", request.name),
+            })
+            .collect();
+        let ids: Vec<_> = created.iter().map(|id| id.as_u32()).collect();
+        let mut sorted = ids.clone();
+        sorted.sort_unstable();
+        assert_eq!(ids, sorted);
+        assert_eq!(created[..3], [surface1.id(), surface2.id(), surface3.id()]);
+        assert_eq!(created[4..], [region.id(), surface5.id()]);
+    }
+}
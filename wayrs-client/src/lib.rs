@@ -3,13 +3,32 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
 pub mod global;
+pub mod logger;
 pub mod object;
 pub mod protocol;
+pub mod reactor;
 
+#[cfg(feature = "calloop")]
+#[cfg_attr(docsrs, doc(cfg(feature = "calloop")))]
+pub mod calloop;
+#[cfg(feature = "mio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "mio")))]
+pub mod mio;
+
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod testing;
+
+mod connect;
 mod connection;
 mod debug_message;
+mod handle;
 
-pub use connection::{ConnectError, Connection};
+pub use connect::{ConnectError, ConnectOptions};
+pub use connection::{
+    Connection, EventError, EventQueue, ProtocolError, RequestError, VersionTooLow,
+};
+pub use handle::{ConnectionHandle, RequestSink};
 
 #[doc(hidden)]
 pub use wayrs_scanner as _private_scanner;
@@ -51,6 +70,22 @@ pub struct EventCtx<'a, D, P: object::Proxy> {
     pub event: P::Event,
 }
 
+impl<D, P: object::Proxy> EventCtx<'_, D, P> {
+    /// Get the user data of the object which received the event.
+    ///
+    /// See [`Connection::set_user_data`].
+    #[must_use]
+    pub fn user_data<T: 'static>(&self) -> Option<&T> {
+        self.conn.user_data(self.proxy)
+    }
+
+    /// Get a mutable reference to the user data of the object which received the event.
+    #[must_use]
+    pub fn user_data_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.conn.user_data_mut(self.proxy)
+    }
+}
+
 impl<D, P: object::Proxy> fmt::Debug for EventCtx<'_, D, P>
 where
     P: fmt::Debug,
@@ -64,6 +99,56 @@ where
     }
 }
 
+/// Statically dispatched event handling, an alternative to callbacks.
+///
+/// Implement this trait for your state type and call
+/// [`Connection::register_dispatch`] to dispatch the events of all objects of interface `P`
+/// which do not have a callback to [`event`](Self::event).
+///
+/// ```no_run
+/// use wayrs_client::protocol::*;
+/// use wayrs_client::{Connection, Dispatch};
+///
+/// struct State;
+///
+/// impl Dispatch<WlOutput> for State {
+///     type UserData = ();
+///
+///     fn event(
+///         &mut self,
+///         _: &mut Connection<Self>,
+///         output: WlOutput,
+///         _: Option<&mut ()>,
+///         event: wl_output::Event,
+///     ) {
+///         if let wl_output::Event::Done = event {
+///             println!("{output:?} is ready");
+///         }
+///     }
+/// }
+///
+/// let mut conn = Connection::<State>::connect().unwrap();
+/// conn.register_dispatch::<WlOutput>();
+/// ```
+pub trait Dispatch<P: object::Proxy>: Sized {
+    /// The type of the [user data](Connection::set_user_data) of the objects. Use `()` if the
+    /// objects do not have user data.
+    type UserData: Send + 'static;
+
+    /// Handle an event.
+    ///
+    /// `data` is the user data of the object, or `None` if it does not have user data of type
+    /// [`UserData`](Self::UserData). The data is not accessible with [`Connection::user_data`]
+    /// while the event is handled.
+    fn event(
+        &mut self,
+        conn: &mut Connection<Self>,
+        proxy: P,
+        data: Option<&mut Self::UserData>,
+        event: P::Event,
+    );
+}
+
 #[doc(hidden)]
 pub mod interface {
     pub use crate::core::{Interface, MessageDesc};
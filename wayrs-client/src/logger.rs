@@ -0,0 +1,410 @@
+//! Protocol logging
+//!
+//! When debugging is enabled, every sent request and received event is passed to a
+//! [`ProtocolLogger`] as a [`ProtocolRecord`]. By default the messages are printed to stderr, use
+//! [`Connection::set_protocol_logger`](crate::Connection::set_protocol_logger) to send them
+//! elsewhere.
+//!
+//! Debugging is enabled if `WAYLAND_DEBUG` environment variable is set, and can be toggled at
+//! runtime with [`Connection::set_debug`](crate::Connection::set_debug). `WAYLAND_DEBUG` may
+//! contain a comma-separated list of interfaces to log, see [`InterfaceFilter`].
+//!
+//! Diagnostics, such as events discarded in lenient mode, are passed to
+//! [`ProtocolLogger::diagnostic`] while debugging is enabled. Warnings, such as the capture warning
+//! and the [leak report](crate::Connection::set_leak_report), are passed there even if debugging
+//! is disabled.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use wayrs_core::{ArgValue, Message, MessageDesc};
+
+use crate::debug_message::DebugMessage;
+use crate::object::Object;
+
+/// The direction of a logged message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A request sent by the client.
+    Request,
+    /// An event received from the server.
+    Event,
+}
+
+/// A logged message.
+///
+/// The [`Display`](fmt::Display) implementation renders the message the same way the default
+/// logger does, e.g. `-> wl_surface@3v6.attach(5, 0, 0)`.
+pub struct ProtocolRecord<'a> {
+    direction: Direction,
+    timestamp: SystemTime,
+    object: Object,
+    message: &'a Message,
+}
+
+impl<'a> ProtocolRecord<'a> {
+    pub(crate) fn new(direction: Direction, object: Object, message: &'a Message) -> Self {
+        Self {
+            direction,
+            timestamp: SystemTime::now(),
+            object,
+            message,
+        }
+    }
+
+    /// Whether this is a request or an event.
+    #[must_use]
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// The time the message was sent or received.
+    #[must_use]
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// The object the message belongs to.
+    #[must_use]
+    pub fn object(&self) -> Object {
+        self.object
+    }
+
+    /// The description of the message, from the interface of the object.
+    #[must_use]
+    pub fn desc(&self) -> &'static MessageDesc {
+        let opcode = self.message.header.opcode as usize;
+        match self.direction {
+            Direction::Request => &self.object.interface.requests[opcode],
+            Direction::Event => &self.object.interface.events[opcode],
+        }
+    }
+
+    /// The name of the message.
+    #[must_use]
+    pub fn message_name(&self) -> &'static str {
+        self.desc().name
+    }
+
+    /// The decoded arguments.
+    #[must_use]
+    pub fn args(&self) -> &'a [ArgValue] {
+        &self.message.args
+    }
+}
+
+impl fmt::Debug for ProtocolRecord<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProtocolRecord")
+            .field("direction", &self.direction)
+            .field("timestamp", &self.timestamp)
+            .field("object", &self.object)
+            .field("message", &self.message_name())
+            .field("args", &self.message.args)
+            .finish()
+    }
+}
+
+impl fmt::Display for ProtocolRecord<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let is_event = self.direction == Direction::Event;
+        if !is_event {
+            f.write_str("-> ")?;
+        }
+        write!(
+            f,
+            "{:?}",
+            DebugMessage::new(self.message, is_event, self.object)
+        )
+    }
+}
+
+/// A receiver of protocol messages.
+///
+/// Implemented for closures which accept `&ProtocolRecord`.
+pub trait ProtocolLogger: Send {
+    /// Log a message.
+    fn log(&mut self, record: &ProtocolRecord<'_>);
+
+    /// Log a diagnostic which is not tied to a single message, such as a discarded event.
+    ///
+    /// Warnings, such as the capture warning and the leak report, are sent here even if debugging
+    /// is disabled.
+    ///
+    /// Does nothing by default.
+    fn diagnostic(&mut self, message: fmt::Arguments<'_>) {
+        let _ = message;
+    }
+}
+
+impl<F: FnMut(&ProtocolRecord<'_>) + Send> ProtocolLogger for F {
+    fn log(&mut self, record: &ProtocolRecord<'_>) {
+        self(record);
+    }
+}
+
+/// The default logger, which prints messages to stderr.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StderrLogger;
+
+impl ProtocolLogger for StderrLogger {
+    fn log(&mut self, record: &ProtocolRecord<'_>) {
+        match record.direction {
+            Direction::Request => eprintln!("[wayrs]  {record}"),
+            Direction::Event => eprintln!("[wayrs] {record}"),
+        }
+    }
+
+    fn diagnostic(&mut self, message: fmt::Arguments<'_>) {
+        eprintln!("[wayrs] {message}");
+    }
+}
+
+/// A logger which emits `tracing` events at the debug level, with `wayrs` target.
+///
+/// The events have `direction`, `object`, `interface` and `message` fields. Diagnostics are
+/// emitted at the warn level.
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TracingLogger;
+
+#[cfg(feature = "tracing")]
+impl ProtocolLogger for TracingLogger {
+    fn log(&mut self, record: &ProtocolRecord<'_>) {
+        tracing::debug!(
+            target: "wayrs",
+            direction = ?record.direction,
+            object = record.object.id.as_u32(),
+            interface = %record.object.interface.name.to_string_lossy(),
+            message = record.message_name(),
+            "{record}",
+        );
+    }
+
+    fn diagnostic(&mut self, message: fmt::Arguments<'_>) {
+        tracing::warn!(target: "wayrs", "{message}");
+    }
+}
+
+/// A set of interfaces to log.
+///
+/// Parsed from a comma-separated list of interface names, in which `*` matches any sequence of
+/// characters. For example, `wl_pointer,xdg_*` logs only pointer and `xdg_*` messages. `1`,
+/// `client` and `*` match all interfaces.
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceFilter {
+    // `None` matches all interfaces.
+    patterns: Option<Vec<String>>,
+}
+
+impl InterfaceFilter {
+    /// A filter which matches all interfaces.
+    #[must_use]
+    pub fn all() -> Self {
+        Self { patterns: None }
+    }
+
+    /// Parse a comma-separated list of patterns.
+    #[must_use]
+    pub fn parse(list: &str) -> Self {
+        let mut patterns = Vec::new();
+        for pattern in list.split(',').map(str::trim) {
+            match pattern {
+                "" => (),
+                "1" | "client" | "*" => return Self::all(),
+                pattern => patterns.push(pattern.to_owned()),
+            }
+        }
+        Self {
+            patterns: Some(patterns),
+        }
+    }
+
+    /// Get the filter from `WAYLAND_DEBUG` environment variable.
+    ///
+    /// Returns `None` if the variable is not set, or if it is set to `0` or `server`, which
+    /// `libwayland` uses to enable only server-side logging.
+    #[must_use]
+    pub fn from_env() -> Option<Self> {
+        let value = std::env::var("WAYLAND_DEBUG").ok()?;
+        match value.trim() {
+            "0" | "server" => None,
+            value => Some(Self::parse(value)),
+        }
+    }
+
+    /// Check whether an interface matches this filter.
+    #[must_use]
+    pub fn matches(&self, interface: &CStr) -> bool {
+        let Some(patterns) = &self.patterns else {
+            return true;
+        };
+        let name = interface.to_bytes();
+        patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), name))
+    }
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+/// A logger which can be shared with the object manager, which reports leaks when dropped.
+#[derive(Clone)]
+pub(crate) struct SharedLogger(Arc<Mutex<Box<dyn ProtocolLogger>>>);
+
+impl SharedLogger {
+    pub fn lock(&self) -> MutexGuard<'_, Box<dyn ProtocolLogger>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The logging state of a connection.
+pub(crate) struct ProtocolLog {
+    pub enabled: bool,
+    pub filter: InterfaceFilter,
+    pub logger: SharedLogger,
+}
+
+impl ProtocolLog {
+    pub fn from_env() -> Self {
+        let filter = InterfaceFilter::from_env();
+        Self {
+            enabled: filter.is_some(),
+            filter: filter.unwrap_or_default(),
+            logger: SharedLogger(Arc::new(Mutex::new(Box::new(StderrLogger)))),
+        }
+    }
+
+    pub fn set_logger(&mut self, logger: Box<dyn ProtocolLogger>) {
+        *self.logger.lock() = logger;
+    }
+
+    pub fn is_enabled_for(&self, object: Object) -> bool {
+        self.enabled && self.filter.matches(object.interface.name)
+    }
+
+    pub fn message(&mut self, direction: Direction, object: Object, message: &Message) {
+        if self.is_enabled_for(object) {
+            self.logger
+                .lock()
+                .log(&ProtocolRecord::new(direction, object, message));
+        }
+    }
+
+    pub fn diagnostic(&mut self, message: fmt::Arguments<'_>) {
+        if self.enabled {
+            self.logger.lock().diagnostic(message);
+        }
+    }
+
+    /// Send a diagnostic to the logger even if debugging is disabled.
+    pub fn warning(&mut self, message: fmt::Arguments<'_>) {
+        self.logger.lock().diagnostic(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::*;
+    use crate::testing::MockCompositor;
+
+    #[test]
+    fn interface_filter() {
+        let filter = InterfaceFilter::parse("wl_pointer, xdg_*");
+        assert!(filter.matches(c"wl_pointer"));
+        assert!(filter.matches(c"xdg_toplevel"));
+        assert!(!filter.matches(c"wl_surface"));
+        assert!(!filter.matches(c"zxdg_output_v1"));
+
+        let filter = InterfaceFilter::parse("*_v1");
+        assert!(filter.matches(c"zxdg_output_v1"));
+        assert!(!filter.matches(c"wl_output"));
+
+        assert!(InterfaceFilter::parse("1").matches(c"wl_output"));
+        assert!(InterfaceFilter::parse("client").matches(c"wl_output"));
+        assert!(!InterfaceFilter::parse("").matches(c"wl_output"));
+    }
+    #[test]
+    fn protocol_logger() {
+        use crate::logger::{Direction, InterfaceFilter, ProtocolRecord};
+        use std::sync::{Arc, Mutex};
+
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let records2 = records.clone();
+        conn.set_protocol_logger(move |record: &ProtocolRecord| {
+            records2
+                .lock()
+                .unwrap()
+                .push((record.direction(), record.to_string()));
+        });
+        conn.set_debug(true);
+        conn.set_debug_filter(InterfaceFilter::parse("wl_out*"));
+
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut ());
+        let output: WlOutput = conn.bind_singleton(4).unwrap();
+        mock.roundtrip(&mut conn, &mut ());
+        mock.send_event(output, "scale", vec![ArgValue::Int(2)]);
+        mock.roundtrip(&mut conn, &mut ());
+        output.release(&mut conn);
+
+        conn.set_debug(false);
+        mock.send_event(output, "done", vec![]);
+        mock.roundtrip(&mut conn, &mut ());
+
+        assert_eq!(
+            *records.lock().unwrap(),
+            [
+                (Direction::Event, format!("{output:?}.scale(2)")),
+                (Direction::Request, format!("-> {output:?}.release()")),
+            ]
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        use std::sync::{Arc, Mutex};
+
+        struct Logger(Arc<Mutex<Vec<String>>>);
+
+        impl ProtocolLogger for Logger {
+            fn log(&mut self, _: &ProtocolRecord<'_>) {}
+
+            fn diagnostic(&mut self, message: fmt::Arguments<'_>) {
+                self.0.lock().unwrap().push(message.to_string());
+            }
+        }
+
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        let diagnostics = Arc::new(Mutex::new(Vec::new()));
+        conn.set_protocol_logger(Logger(diagnostics.clone()));
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut ());
+        let output: WlOutput = conn.bind_singleton(4).unwrap();
+        let mut handle = conn.handle().unwrap();
+        output.release(&mut conn);
+
+        // Not reported while debugging is disabled
+        output.release(&mut handle);
+        mock.roundtrip(&mut conn, &mut ());
+        assert!(diagnostics.lock().unwrap().is_empty());
+
+        conn.set_debug(true);
+        output.release(&mut handle);
+        mock.roundtrip(&mut conn, &mut ());
+        let diagnostics = diagnostics.lock().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].starts_with("discarding request from handle"));
+    }
+}
@@ -0,0 +1,381 @@
+//! `mio` integration
+
+use std::io;
+use std::os::fd::AsRawFd;
+
+use ::mio::event::{Event, Source};
+use ::mio::unix::SourceFd;
+use ::mio::{Interest, Registry, Token};
+
+use crate::{Connection, EventError, IoMode};
+
+/// A `mio` event source for a [`Connection`].
+///
+/// Since `mio` has no hooks into the event loop, [`before_poll`](Self::before_poll) must be called
+/// before each [`Poll::poll`](::mio::Poll::poll) and [`handle_event`](Self::handle_event) for each
+/// event with the registered token. The [`wakeup_fd`](Connection::wakeup_fd) of the connection is
+/// registered with the same token, so requests sent with handles are flushed as well:
+///
+/// ```no_run
+/// # use wayrs_client::{Connection, mio::WaylandSource};
+/// # fn run(conn: Connection<()>, state: &mut ()) -> std::io::Result<()> {
+/// const WAYLAND: mio::Token = mio::Token(0);
+///
+/// let mut poll = mio::Poll::new()?;
+/// let mut events = mio::Events::with_capacity(16);
+/// let mut source = WaylandSource::new(conn);
+/// poll.registry()
+///     .register(&mut source, WAYLAND, mio::Interest::READABLE)?;
+///
+/// loop {
+///     source.before_poll(poll.registry())?;
+///     poll.poll(&mut events, None)?;
+///     for event in &events {
+///         if event.token() == WAYLAND {
+///             source.handle_event(event, state)?;
+///         }
+///     }
+/// }
+/// # }
+/// ```
+pub struct WaylandSource<D> {
+    conn: Connection<D>,
+    registration: Option<(Token, Interest)>,
+    wakeup_registered: bool,
+}
+
+impl<D> WaylandSource<D> {
+    #[must_use]
+    pub fn new(conn: Connection<D>) -> Self {
+        Self {
+            conn,
+            registration: None,
+            wakeup_registered: false,
+        }
+    }
+
+    /// Get a reference to the connection.
+    #[must_use]
+    pub fn conn(&self) -> &Connection<D> {
+        &self.conn
+    }
+
+    /// Get a mutable reference to the connection.
+    #[must_use]
+    pub fn conn_mut(&mut self) -> &mut Connection<D> {
+        &mut self.conn
+    }
+
+    /// Get the connection back.
+    #[must_use]
+    pub fn into_inner(self) -> Connection<D> {
+        self.conn
+    }
+
+    /// Flush the connection before polling.
+    ///
+    /// If the socket buffer is full, the source is reregistered to wait for the socket to become
+    /// writable, so requests are never stuck in the buffer. The wakeup fd of handles created
+    /// since the source was registered is registered here as well.
+    pub fn before_poll(&mut self, registry: &Registry) -> io::Result<()> {
+        if let Some((token, _)) = self.registration {
+            self.register_wakeup(registry, token)?;
+        }
+
+        let would_block = match self.conn.flush(IoMode::NonBlocking) {
+            Ok(()) => false,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => true,
+            Err(e) => return Err(e),
+        };
+
+        if let Some((token, interests)) = self.registration {
+            // The socket is writable most of the time, so wait for it only if needed
+            let wanted = if would_block {
+                interests.add(Interest::WRITABLE)
+            } else {
+                interests.remove(Interest::WRITABLE).unwrap_or(interests)
+            };
+            if wanted != interests {
+                self.reregister(registry, token, wanted)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receive events if the socket is readable, dispatch them and flush the connection.
+    ///
+    /// Since the source is edge-triggered, the socket is read until it would block, even if some
+    /// events are reported as [`EventError`]. The first error is returned after the received
+    /// events are dispatched.
+    pub fn handle_event(&mut self, event: &Event, state: &mut D) -> io::Result<()> {
+        let mut result = Ok(());
+
+        if event.is_readable() || event.is_read_closed() {
+            loop {
+                match self.conn.recv_events(IoMode::NonBlocking) {
+                    Ok(()) => break,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        let recoverable = EventError::from_io(&e).is_some();
+                        if result.is_ok() {
+                            result = Err(e);
+                        }
+                        if !recoverable {
+                            break;
+                        }
+                    }
+                }
+            }
+            self.conn.dispatch_events(state);
+        }
+
+        // The event may come from the wakeup fd, so flush regardless of the readiness
+        match self.conn.flush(IoMode::NonBlocking) {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock && result.is_ok() => result = Err(e),
+            _ => (),
+        }
+
+        result
+    }
+
+    /// Register the wakeup fd of the connection with `token`, if it exists and was not registered
+    /// yet.
+    fn register_wakeup(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        if !self.wakeup_registered {
+            if let Some(fd) = self.conn.wakeup_fd() {
+                SourceFd(&fd.as_raw_fd()).register(registry, token, Interest::READABLE)?;
+                self.wakeup_registered = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D> Source for WaylandSource<D> {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.conn.as_raw_fd()).register(registry, token, interests)?;
+        self.registration = Some((token, interests));
+        self.register_wakeup(registry, token)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.conn.as_raw_fd()).reregister(registry, token, interests)?;
+        self.registration = Some((token, interests));
+        if self.wakeup_registered {
+            if let Some(fd) = self.conn.wakeup_fd() {
+                SourceFd(&fd.as_raw_fd()).reregister(registry, token, Interest::READABLE)?;
+            }
+            Ok(())
+        } else {
+            self.register_wakeup(registry, token)
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.conn.as_raw_fd()).deregister(registry)?;
+        self.registration = None;
+        if self.wakeup_registered {
+            if let Some(fd) = self.conn.wakeup_fd() {
+                SourceFd(&fd.as_raw_fd()).deregister(registry)?;
+            }
+            self.wakeup_registered = false;
+        }
+        Ok(())
+    }
+}
+
+impl<D> Source for Connection<D> {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use ::mio::{Events, Poll};
+    use wayrs_core::ArgValue;
+
+    use super::*;
+    use crate::protocol::*;
+    use crate::testing::MockCompositor;
+
+    const WAYLAND: Token = Token(0);
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+    fn poll_once<D>(poll: &mut Poll, source: &mut WaylandSource<D>, state: &mut D) {
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, TIMEOUT).unwrap();
+        assert!(!events.is_empty(), "timed out");
+        for event in &events {
+            assert_eq!(event.token(), WAYLAND);
+            source.handle_event(event, state).unwrap();
+        }
+    }
+
+    #[test]
+    fn dispatch_cycle() {
+        let (mut mock, mut conn) = MockCompositor::new::<Vec<i32>>();
+        let mut state = Vec::new();
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut state);
+
+        let mut poll = Poll::new().unwrap();
+        let mut source = WaylandSource::new(conn);
+        poll.registry()
+            .register(&mut source, WAYLAND, Interest::READABLE)
+            .unwrap();
+
+        // The bind request is flushed before polling
+        let output = source
+            .conn_mut()
+            .bind_singleton_with_cb::<WlOutput, _>(4, |ctx| {
+                if let wl_output::Event::Scale(scale) = ctx.event {
+                    ctx.state.push(scale);
+                }
+            })
+            .unwrap();
+        source.before_poll(poll.registry()).unwrap();
+        assert_eq!(mock.dispatch_requests().unwrap(), 1);
+
+        mock.send_event(output, "scale", vec![ArgValue::Int(2)]);
+        mock.send_event(output, "scale", vec![ArgValue::Int(3)]);
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+        poll_once(&mut poll, &mut source, &mut state);
+        assert_eq!(state, [2, 3]);
+    }
+
+    #[test]
+    fn events_after_errors_are_received() {
+        let (mut mock, mut conn) = MockCompositor::new::<Vec<i32>>();
+        let mut state = Vec::new();
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut state);
+        let output = conn
+            .bind_singleton_with_cb::<WlOutput, _>(4, |ctx| {
+                if let wl_output::Event::Scale(scale) = ctx.event {
+                    ctx.state.push(scale);
+                }
+            })
+            .unwrap();
+        mock.roundtrip(&mut conn, &mut state);
+
+        let mut poll = Poll::new().unwrap();
+        let mut source = WaylandSource::new(conn);
+        poll.registry()
+            .register(&mut source, WAYLAND, Interest::READABLE)
+            .unwrap();
+
+        // The client does not know about this object
+        let unknown: WlOutput = mock.new_object(4);
+        mock.send_event(unknown, "scale", vec![ArgValue::Int(1)]);
+        mock.send_event(output, "scale", vec![ArgValue::Int(2)]);
+        mock.socket.flush(IoMode::NonBlocking).unwrap();
+
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, TIMEOUT).unwrap();
+        let event = events.iter().next().unwrap();
+        let err = source.handle_event(event, &mut state).unwrap_err();
+        assert!(matches!(
+            EventError::from_io(&err),
+            Some(EventError::UnknownObject { .. })
+        ));
+        assert_eq!(state, [2]);
+    }
+
+    #[test]
+    fn writable_interest_on_would_block() {
+        // The loopback transport never blocks, so use a real socket
+        let (client, mut server) = UnixStream::pair().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let conn = Connection::<()>::with_transport(client);
+        let registry = conn.registry();
+
+        let mut poll = Poll::new().unwrap();
+        let mut source = WaylandSource::new(conn);
+        poll.registry()
+            .register(&mut source, WAYLAND, Interest::READABLE)
+            .unwrap();
+
+        // Fill the socket buffer
+        loop {
+            for _ in 0..100 {
+                let _: WlOutput = registry.bind(source.conn_mut(), 1, 1);
+            }
+            source.before_poll(poll.registry()).unwrap();
+            if source.registration.unwrap().1.is_writable() {
+                break;
+            }
+        }
+
+        // The requests are flushed once the socket becomes writable
+        let mut buf = vec![0; 64 * 1024];
+        while source.registration.unwrap().1.is_writable() {
+            while server.read(&mut buf).is_ok() {}
+            poll_once(&mut poll, &mut source, &mut ());
+            source.before_poll(poll.registry()).unwrap();
+        }
+        assert_eq!(source.registration, Some((WAYLAND, Interest::READABLE)));
+    }
+
+    #[test]
+    fn handle_requests_are_flushed() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        let name = mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut ());
+
+        let mut poll = Poll::new().unwrap();
+        let mut source = WaylandSource::new(conn);
+        poll.registry()
+            .register(&mut source, WAYLAND, Interest::READABLE)
+            .unwrap();
+
+        // The wakeup fd is registered once a handle is created
+        let mut handle = source.conn_mut().handle().unwrap();
+        source.before_poll(poll.registry()).unwrap();
+
+        let registry = source.conn().registry();
+        let output = std::thread::spawn(move || {
+            let output: WlOutput = registry.bind(&mut handle, name, 4);
+            output
+        })
+        .join()
+        .unwrap();
+
+        poll_once(&mut poll, &mut source, &mut ());
+        assert_eq!(mock.dispatch_requests().unwrap(), 1);
+        assert_eq!(mock.objects_of::<WlOutput>(), [output]);
+    }
+}
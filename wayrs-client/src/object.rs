@@ -1,16 +1,22 @@
 //! Client side object representation
 
+use std::any::Any;
 use std::borrow::Borrow;
 use std::cmp;
-use std::fmt::{self, Debug};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fmt::{self, Debug, Write};
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::num::NonZeroU32;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
 
-use crate::connection::GenericCallback;
+use crate::connection::{BorrowedCallback, EventQueue, GenericCallback};
+use crate::logger::SharedLogger;
 use crate::protocol::WlDisplay;
 
 pub use wayrs_core::ObjectId;
-use wayrs_core::{Interface, Message, MessageBuffersPool};
+use wayrs_core::{BorrowedArgValue, Interface, Message, MessageBuffersPool};
 
 /// A Wayland object.
 ///
@@ -89,16 +95,184 @@ impl Debug for Object {
     }
 }
 
+static INTERFACES: RwLock<Vec<&'static Interface>> = RwLock::new(Vec::new());
+
+/// Register an interface in the process-wide interface registry.
+///
+/// Events with untyped `new_id` arguments create objects of an interface which is only known by
+/// name. Such events can be handled only if the interface is registered. Interfaces of objects
+/// created with untyped `new_id` requests (like `wl_registry.bind`) are registered automatically.
+pub fn register_interface(interface: &'static Interface) {
+    if lookup_interface(interface.name).is_none() {
+        INTERFACES
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(interface);
+    }
+}
+
+/// Find an interface in the process-wide interface registry by its name.
+///
+/// See [`register_interface`].
+#[must_use]
+pub fn lookup_interface(name: &CStr) -> Option<&'static Interface> {
+    INTERFACES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|iface| iface.name == name)
+        .copied()
+}
+
+/// A live object, see [`Connection::objects`](crate::Connection::objects).
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct ObjectInfo {
+    pub object: Object,
+    /// Whether a callback is set for this object.
+    pub has_callback: bool,
+}
+
 pub(crate) struct ObjectManager<D> {
-    vacant_ids: Vec<ObjectId>,
+    client_ids: Arc<ClientIds>,
     client_objects: Vec<Option<ObjectState<D>>>,
     server_objects: Vec<Option<ObjectState<D>>>,
+    // Receives the report of the objects which were not destroyed on drop.
+    pub leak_logger: Option<SharedLogger>,
 }
 
 pub(crate) struct ObjectState<D> {
     pub object: Object,
     pub is_alive: bool,
     pub cb: Option<GenericCallback<D>>,
+    pub borrowed_cb: Option<BorrowedCallback>,
+    pub queue: EventQueue,
+    pub user_data: Option<Box<dyn Any + Send>>,
+    // The number of received events of this object which are waiting in a queue.
+    pub queued_events: usize,
+}
+
+impl<D> ObjectState<D> {
+    pub fn new(object: Object) -> Self {
+        Self {
+            object,
+            is_alive: true,
+            cb: None,
+            borrowed_cb: None,
+            queue: EventQueue::DEFAULT,
+            user_data: None,
+            queued_events: 0,
+        }
+    }
+
+    /// Mark the object as dead, dropping its user data and borrowed callback.
+    pub fn kill(&mut self) {
+        self.is_alive = false;
+        self.borrowed_cb = None;
+        self.user_data = None;
+    }
+}
+
+/// Allocator of client object IDs, shared with connection handles.
+///
+/// The compositor requires new IDs to be used in the order they are allocated. A handle reserves
+/// the ID it allocates until the request which creates the object is queued, and other
+/// allocations wait for it.
+pub(crate) struct ClientIds {
+    state: Mutex<IdsState>,
+    released: Condvar,
+}
+
+pub(crate) struct IdsState {
+    vacant: Vec<ObjectId>,
+    next: u32,
+    reserved: bool,
+    // The number of threads waiting for the reserved ID to be released.
+    waiters: usize,
+}
+
+impl IdsState {
+    pub fn alloc(&mut self) -> ObjectId {
+        self.vacant.pop().unwrap_or_else(|| {
+            let id = ObjectId(NonZeroU32::new(self.next).unwrap());
+            self.next += 1;
+            id
+        })
+    }
+}
+
+impl ClientIds {
+    fn new() -> Self {
+        Self {
+            // 0 is the null object and 1 is the display
+            state: Mutex::new(IdsState {
+                vacant: Vec::new(),
+                next: 2,
+                reserved: false,
+                waiters: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Lock the allocator, waiting until the reserved ID is released.
+    pub fn lock(&self) -> MutexGuard<'_, IdsState> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.reserved {
+            state.waiters += 1;
+            state = self
+                .released
+                .wait_while(state, |state| state.reserved)
+                .unwrap_or_else(|e| e.into_inner());
+            state.waiters -= 1;
+        }
+        state
+    }
+
+    #[cfg(test)]
+    pub fn has_waiters(&self) -> bool {
+        self.state.lock().unwrap().waiters > 0
+    }
+
+    /// Allocate an ID and reserve it until [`release`](Self::release) is called.
+    pub fn reserve(&self) -> ObjectId {
+        let mut state = self.lock();
+        state.reserved = true;
+        state.alloc()
+    }
+
+    /// Release the reserved ID. `queue` is called with the allocator locked, and returns whether
+    /// the request which creates the object was queued. If it was not, the ID is freed.
+    pub fn release(&self, id: ObjectId, queue: impl FnOnce() -> bool) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !queue() {
+            state.vacant.push(id);
+        }
+        state.reserved = false;
+        let has_waiters = state.waiters > 0;
+        drop(state);
+        if has_waiters {
+            self.released.notify_all();
+        }
+    }
+
+    fn free(&self, id: ObjectId) {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .vacant
+            .push(id);
+    }
+}
+
+impl<D> Drop for ObjectManager<D> {
+    fn drop(&mut self) {
+        if let Some(logger) = &self.leak_logger {
+            if let Some(report) = self.leak_report() {
+                logger.lock().diagnostic(format_args!("{report}"));
+            }
+        }
+    }
 }
 
 #[doc(hidden)]
@@ -115,6 +289,9 @@ pub struct WrongObject;
 pub trait Proxy: TryFrom<Object, Error = WrongObject> + Copy {
     type Event;
 
+    /// Same as [`Event`](Self::Event), but strings and arrays are borrowed.
+    type BorrowedEvent<'a>;
+
     const INTERFACE: &'static Interface;
 
     #[doc(hidden)]
@@ -127,6 +304,13 @@ pub trait Proxy: TryFrom<Object, Error = WrongObject> + Copy {
         pool: &mut MessageBuffersPool,
     ) -> Result<Self::Event, BadMessage>;
 
+    #[doc(hidden)]
+    fn parse_event_borrowed<'a>(
+        opcode: u16,
+        args: impl ExactSizeIterator<Item = BorrowedArgValue<'a>>,
+        version: u32,
+    ) -> Result<Self::BorrowedEvent<'a>, BadMessage>;
+
     fn id(&self) -> ObjectId;
 
     fn version(&self) -> u32;
@@ -145,69 +329,63 @@ impl<P: Proxy> From<P> for Object {
 impl<D> ObjectManager<D> {
     pub fn new() -> Self {
         let mut this = Self {
-            vacant_ids: Vec::new(),
+            client_ids: Arc::new(ClientIds::new()),
             client_objects: Vec::with_capacity(16),
             server_objects: Vec::new(),
+            leak_logger: None,
         };
 
         // Dummy NULL object
         this.client_objects.push(None);
 
         // Display
-        this.client_objects.push(Some(ObjectState {
-            object: WlDisplay::INSTANCE.into(),
-            is_alive: true,
-            cb: None,
-        }));
+        this.client_objects
+            .push(Some(ObjectState::new(WlDisplay::INSTANCE.into())));
 
         this
     }
 
-    pub fn clear_callbacks<D2>(self) -> ObjectManager<D2> {
+    pub fn clear_callbacks<D2>(mut self) -> ObjectManager<D2> {
         let map = |x: ObjectState<D>| ObjectState {
             object: x.object,
             is_alive: x.is_alive,
             cb: None,
+            borrowed_cb: None,
+            queue: x.queue,
+            user_data: x.user_data,
+            queued_events: x.queued_events,
         };
         ObjectManager {
-            vacant_ids: self.vacant_ids,
-            client_objects: self
-                .client_objects
+            client_ids: self.client_ids.clone(),
+            client_objects: mem::take(&mut self.client_objects)
                 .into_iter()
                 .map(|x| x.map(map))
                 .collect(),
-            server_objects: self
-                .server_objects
+            server_objects: mem::take(&mut self.server_objects)
                 .into_iter()
                 .map(|x| x.map(map))
                 .collect(),
+            leak_logger: self.leak_logger.take(),
         }
     }
 
-    pub fn alloc_client_object(
-        &mut self,
-        interface: &'static Interface,
-        version: u32,
-    ) -> &mut ObjectState<D> {
-        let id = self.vacant_ids.pop().unwrap_or_else(|| {
-            let id = self.client_objects.len() as u32;
-            self.client_objects.push(None);
-            ObjectId(NonZeroU32::new(id).unwrap())
-        });
+    pub fn client_ids(&self) -> Arc<ClientIds> {
+        self.client_ids.clone()
+    }
 
+    /// Insert a client object with an ID allocated from [`client_ids`](Self::client_ids).
+    pub fn insert_client_object(&mut self, state: ObjectState<D>) -> &mut ObjectState<D> {
+        let id = state.object.id;
         assert!(id.created_by_client());
-        let obj = self.client_objects.get_mut(id.0.get() as usize).unwrap();
-        assert!(obj.is_none());
 
-        obj.insert(ObjectState {
-            object: Object {
-                id,
-                interface,
-                version,
-            },
-            is_alive: true,
-            cb: None,
-        })
+        let index = id.as_u32() as usize;
+        while index >= self.client_objects.len() {
+            self.client_objects.push(None);
+        }
+
+        let obj = &mut self.client_objects[index];
+        assert!(obj.is_none());
+        obj.insert(state)
     }
 
     pub fn register_server_object(&mut self, object: Object) -> &mut ObjectState<D> {
@@ -219,11 +397,19 @@ impl<D> ObjectManager<D> {
             self.server_objects.push(None);
         }
 
-        self.server_objects[index].insert(ObjectState {
-            object,
-            is_alive: true,
-            cb: None,
-        })
+        self.server_objects[index].insert(ObjectState::new(object))
+    }
+
+    pub fn get_object(&self, id: ObjectId) -> Option<&ObjectState<D>> {
+        if id.created_by_client() {
+            self.client_objects
+                .get(id.as_u32() as usize)
+                .and_then(Option::as_ref)
+        } else {
+            self.server_objects
+                .get((id.as_u32() - ObjectId::MIN_SERVER.as_u32()) as usize)
+                .and_then(Option::as_ref)
+        }
     }
 
     pub fn get_object_mut(&mut self, id: ObjectId) -> Option<&mut ObjectState<D>> {
@@ -238,10 +424,58 @@ impl<D> ObjectManager<D> {
         }
     }
 
+    /// Iterate over all live objects.
+    pub fn live_objects(&self) -> impl Iterator<Item = &ObjectState<D>> {
+        self.client_objects
+            .iter()
+            .chain(&self.server_objects)
+            .flatten()
+            .filter(|obj| obj.is_alive)
+    }
+
+    /// Describe live client objects which could have been destroyed, grouped by interface.
+    ///
+    /// Objects which are destroyed by the server, such as `wl_callback`, are included too, since
+    /// they are leaked if the client forgets about them. Returns `None` if there are no leaks.
+    pub fn leak_report(&self) -> Option<String> {
+        let mut leaks = BTreeMap::<&CStr, Vec<u32>>::new();
+        for obj in self.client_objects.iter().flatten() {
+            let Object {
+                id,
+                interface,
+                version,
+            } = obj.object;
+            let destructible = interface
+                .requests
+                .iter()
+                .any(|req| req.is_destructor && req.since <= version)
+                || interface.events.iter().any(|event| event.is_destructor);
+            if obj.is_alive && destructible {
+                leaks.entry(interface.name).or_default().push(id.as_u32());
+            }
+        }
+
+        if leaks.is_empty() {
+            return None;
+        }
+
+        let total: usize = leaks.values().map(Vec::len).sum();
+        let mut report = format!("{total} objects were not destroyed:");
+        for (interface, ids) in leaks {
+            let _ = write!(
+                report,
+                "\n  {}: {} (ids {ids:?})",
+                interface.to_string_lossy(),
+                ids.len()
+            );
+        }
+        Some(report)
+    }
+
     /// Call it only on client-created objects in response to `wl_display.delete_id`.
     pub fn delete_client_object(&mut self, id: ObjectId) {
         assert!(id.created_by_client());
         *self.client_objects.get_mut(id.as_u32() as usize).unwrap() = None;
-        self.vacant_ids.push(id);
+        self.client_ids.free(id);
     }
 }
@@ -1,3 +1,3 @@
 //! The core Wayland protocol
 
-crate::generate!("wayland.xml");
+crate::_private_scanner::generate!(crate, core_protocol, interfaces = wayrs_core::protocol);
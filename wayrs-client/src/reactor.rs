@@ -0,0 +1,143 @@
+//! Runtime-agnostic async support
+//!
+//! The async methods of [`Connection`](crate::Connection) need to know when the Wayland socket
+//! becomes readable or writable. This is abstracted by the [`Reactor`] trait, so the connection
+//! can be driven by any async runtime.
+//!
+//! Two reactors are provided: [`Tokio`] (requires `tokio` feature) and [`AsyncIo`] (requires
+//! `async-io` feature, works with `smol` and other runtimes based on `async-io`). If no reactor
+//! was set with [`Connection::set_reactor`](crate::Connection::set_reactor), one of these is
+//! created automatically. When both features are enabled, [`Tokio`] is used if the connection
+//! is polled from within a Tokio runtime.
+//!
+//! The async methods are available regardless of the enabled features, so that a custom reactor
+//! can be used. Without a reactor, they fail at runtime with
+//! [`Unsupported`](io::ErrorKind::Unsupported).
+
+use std::io;
+use std::os::fd::BorrowedFd;
+use std::task::{Context, Poll};
+
+/// The kind of readiness to wait for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interest {
+    Readable,
+    Writable,
+}
+
+/// A source of readiness notifications for a file descriptor.
+pub trait Reactor: Send {
+    /// Perform a non-blocking IO operation when the file descriptor is ready.
+    ///
+    /// `op` must be retried every time the file descriptor becomes ready for `interest`, until it
+    /// returns something other than [`WouldBlock`](io::ErrorKind::WouldBlock). Its result is then
+    /// returned as `Poll::Ready`. While waiting, `cx` must be woken up when the readiness changes.
+    fn poll_io(
+        &mut self,
+        cx: &mut Context<'_>,
+        interest: Interest,
+        op: &mut dyn FnMut() -> io::Result<()>,
+    ) -> Poll<io::Result<()>>;
+}
+
+/// A [`Reactor`] backed by Tokio.
+#[cfg(feature = "tokio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+#[derive(Debug)]
+pub struct Tokio(tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>);
+
+#[cfg(feature = "tokio")]
+impl Tokio {
+    /// Register a file descriptor in the current Tokio runtime.
+    ///
+    /// The file descriptor is duplicated. Must be called from within a Tokio runtime.
+    pub fn new(fd: BorrowedFd<'_>) -> io::Result<Self> {
+        tokio::io::unix::AsyncFd::new(fd.try_clone_to_owned()?).map(Self)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Reactor for Tokio {
+    fn poll_io(
+        &mut self,
+        cx: &mut Context<'_>,
+        interest: Interest,
+        op: &mut dyn FnMut() -> io::Result<()>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let ready = match interest {
+                Interest::Readable => self.0.poll_read_ready_mut(cx),
+                Interest::Writable => self.0.poll_write_ready_mut(cx),
+            };
+            let mut guard = match ready {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Ok(result) = guard.try_io(|_| op()) {
+                return Poll::Ready(result);
+            }
+        }
+    }
+}
+
+/// A [`Reactor`] backed by `async-io`, which is used by `smol`.
+#[cfg(feature = "async-io")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async-io")))]
+#[derive(Debug)]
+pub struct AsyncIo(async_io::Async<std::os::fd::OwnedFd>);
+
+#[cfg(feature = "async-io")]
+impl AsyncIo {
+    /// Register a file descriptor in the `async-io` reactor.
+    ///
+    /// The file descriptor is duplicated.
+    pub fn new(fd: BorrowedFd<'_>) -> io::Result<Self> {
+        // `Async::new` would put the socket into non-blocking mode, which would break blocking
+        // operations on the connection.
+        async_io::Async::new_nonblocking(fd.try_clone_to_owned()?).map(Self)
+    }
+}
+
+#[cfg(feature = "async-io")]
+impl Reactor for AsyncIo {
+    fn poll_io(
+        &mut self,
+        cx: &mut Context<'_>,
+        interest: Interest,
+        op: &mut dyn FnMut() -> io::Result<()>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            match op() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => return Poll::Ready(result),
+            }
+            let ready = match interest {
+                Interest::Readable => self.0.poll_readable(cx),
+                Interest::Writable => self.0.poll_writable(cx),
+            };
+            match ready {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Create a reactor using one of the enabled backends.
+#[allow(unused_variables, unreachable_code)]
+pub(crate) fn default_reactor(fd: BorrowedFd<'_>) -> io::Result<Box<dyn Reactor>> {
+    #[cfg(feature = "tokio")]
+    if cfg!(not(feature = "async-io")) || tokio::runtime::Handle::try_current().is_ok() {
+        return Ok(Box::new(Tokio::new(fd)?));
+    }
+
+    #[cfg(feature = "async-io")]
+    return Ok(Box::new(AsyncIo::new(fd)?));
+
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "no async reactor: enable `tokio` or `async-io` feature or use Connection::set_reactor()",
+    ))
+}
@@ -0,0 +1,462 @@
+//! A mock compositor for testing client code without a running Wayland compositor
+//!
+//! [`MockCompositor`] is connected to a [`Connection`] with an in-process
+//! [`loopback`](wayrs_core::transport::loopback) transport. It implements `wl_display` and
+//! `wl_registry`, advertises the globals you add, records all other requests and lets you send
+//! arbitrary events to the client.
+//!
+//! Everything runs on the current thread, so tests are deterministic. Instead of
+//! [`Connection::blocking_roundtrip`], which would wait for the mock forever, use
+//! [`MockCompositor::roundtrip`].
+//!
+//! ```
+//! use wayrs_client::protocol::*;
+//! use wayrs_client::testing::MockCompositor;
+//! use wayrs_client::core::ArgValue;
+//!
+//! let (mut mock, mut conn) = MockCompositor::new::<Vec<wl_seat::Capability>>();
+//! mock.add_global::<WlSeat>(7);
+//!
+//! let mut state = Vec::new();
+//! mock.roundtrip(&mut conn, &mut state);
+//! conn.bind_singleton_with_cb::<WlSeat, _>(7, |ctx| {
+//!     if let wl_seat::Event::Capabilities(caps) = ctx.event {
+//!         ctx.state.push(caps);
+//!     }
+//! })
+//! .unwrap();
+//! mock.roundtrip(&mut conn, &mut state);
+//!
+//! let seat = mock.objects_of::<WlSeat>()[0];
+//! mock.send_event(seat, "capabilities", vec![ArgValue::Uint(1)]);
+//! mock.roundtrip(&mut conn, &mut state);
+//!
+//! assert_eq!(state, [wl_seat::Capability::Pointer]);
+//! ```
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::num::NonZeroU32;
+
+use crate::object::{Object, Proxy};
+use crate::protocol::*;
+use crate::Connection;
+
+use wayrs_core::transport::loopback::{self, Loopback};
+use wayrs_core::transport::{BufferedSocket, PeekHeaderError};
+use wayrs_core::{
+    ArgType, ArgValue, Interface, IoMode, Message, MessageBuffersPool, MessageHeader,
+};
+
+pub use wayrs_core::ObjectId;
+
+/// A scriptable fake compositor.
+///
+/// See the [module level documentation](self) for more info.
+pub struct MockCompositor {
+    pub(crate) socket: BufferedSocket<Loopback>,
+    msg_buffers_pool: MessageBuffersPool,
+    objects: HashMap<ObjectId, Object>,
+    next_server_id: u32,
+    registries: Vec<ObjectId>,
+    globals: Vec<MockGlobal>,
+    next_global_name: u32,
+    serial: u32,
+    requests: Vec<ReceivedRequest>,
+}
+
+struct MockGlobal {
+    name: u32,
+    interface: &'static Interface,
+    version: u32,
+}
+
+/// A request received by the [`MockCompositor`].
+#[derive(Debug)]
+pub struct ReceivedRequest {
+    /// The object this request was sent to.
+    pub object: Object,
+    /// The name of the request, as in the protocol XML file.
+    pub name: &'static str,
+    pub opcode: u16,
+    pub args: Vec<ArgValue>,
+}
+
+impl MockCompositor {
+    /// Create a mock compositor and a connection to it.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the loopback transport cannot be created.
+    #[must_use]
+    pub fn new<D>() -> (Self, Connection<D>) {
+        let (client, server) = loopback::pair().expect("could not create loopback transport");
+
+        let mut objects = HashMap::new();
+        objects.insert(
+            ObjectId::DISPLAY,
+            Object {
+                id: ObjectId::DISPLAY,
+                interface: WlDisplay::INTERFACE,
+                version: 1,
+            },
+        );
+
+        let this = Self {
+            socket: BufferedSocket::from(server),
+            msg_buffers_pool: MessageBuffersPool::default(),
+            objects,
+            next_server_id: ObjectId::MIN_SERVER.as_u32(),
+            registries: Vec::new(),
+            globals: Vec::new(),
+            next_global_name: 1,
+            serial: 0,
+            requests: Vec::new(),
+        };
+
+        (this, Connection::with_transport(client))
+    }
+
+    /// Advertise a new global. Returns the name of the global.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `version` is zero or larger than the version of `P`'s interface.
+    pub fn add_global<P: Proxy>(&mut self, version: u32) -> u32 {
+        assert!(version >= 1 && version <= P::INTERFACE.version);
+
+        let name = self.next_global_name;
+        self.next_global_name += 1;
+        self.globals.push(MockGlobal {
+            name,
+            interface: P::INTERFACE,
+            version,
+        });
+
+        for registry in self.registries.clone() {
+            self.send_global(registry, name, P::INTERFACE, version);
+        }
+
+        name
+    }
+
+    /// Remove a global, notifying all registries.
+    pub fn remove_global(&mut self, name: u32) {
+        self.globals.retain(|g| g.name != name);
+        for registry in self.registries.clone() {
+            self.send_event(
+                Self::registry_object(registry),
+                "global_remove",
+                vec![ArgValue::Uint(name)],
+            );
+        }
+    }
+
+    /// Get the next serial number.
+    pub fn next_serial(&mut self) -> u32 {
+        self.serial = self.serial.wrapping_add(1);
+        self.serial
+    }
+
+    /// Get all alive objects.
+    pub fn objects(&self) -> impl Iterator<Item = Object> + '_ {
+        self.objects.values().copied()
+    }
+
+    /// Get all alive objects of a given interface, sorted by ID.
+    #[must_use]
+    pub fn objects_of<P: Proxy>(&self) -> Vec<P> {
+        let mut objects: Vec<P> = self
+            .objects
+            .values()
+            .filter_map(|obj| P::try_from(*obj).ok())
+            .collect();
+        objects.sort_by_key(|p| p.id());
+        objects
+    }
+
+    /// Take all recorded requests, in the order they were received.
+    ///
+    /// `wl_display` and `wl_registry` requests are handled internally and are not recorded.
+    pub fn take_requests(&mut self) -> Vec<ReceivedRequest> {
+        std::mem::take(&mut self.requests)
+    }
+
+    /// Create a server-side object, which can be sent to the client in an event as a "new_id"
+    /// argument.
+    pub fn new_object<P: Proxy>(&mut self, version: u32) -> P {
+        let id = ObjectId(NonZeroU32::new(self.next_server_id).unwrap());
+        self.next_server_id += 1;
+        self.objects.insert(
+            id,
+            Object {
+                id,
+                interface: P::INTERFACE,
+                version,
+            },
+        );
+        P::new(id, version)
+    }
+
+    /// Send an event to the client. The event is looked up by its name.
+    ///
+    /// If the event is a destructor, the object is removed.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the object does not exist, the interface does not have an event
+    /// named `event` or the number of arguments does not match the signature.
+    pub fn send_event(&mut self, object: impl Into<Object>, event: &str, args: Vec<ArgValue>) {
+        let object = object.into();
+        assert!(
+            self.objects.contains_key(&object.id),
+            "attempt to send event for non-existing object {object:?}"
+        );
+
+        let (opcode, desc) = object
+            .interface
+            .events
+            .iter()
+            .enumerate()
+            .find(|(_, desc)| desc.name == event)
+            .unwrap_or_else(|| panic!("{object:?} does not have event {event}"));
+        assert_eq!(
+            desc.signature.len(),
+            args.len(),
+            "wrong number of arguments for {object:?}.{event}"
+        );
+
+        let msg = Message {
+            header: MessageHeader {
+                object_id: object.id,
+                size: 0,
+                opcode: opcode as u16,
+            },
+            args,
+        };
+        if let Err(e) =
+            self.socket
+                .write_message(msg, &mut self.msg_buffers_pool, IoMode::NonBlocking)
+        {
+            panic!("failed to send event: {}", e.err);
+        }
+
+        if desc.is_destructor {
+            self.remove_object(object.id);
+        }
+    }
+
+    /// Flush the client, receive and handle all requests and dispatch the resulting events. This
+    /// is repeated until the client stops sending requests.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the client sends a malformed request or IO fails.
+    pub fn roundtrip<D>(&mut self, conn: &mut Connection<D>, state: &mut D) {
+        loop {
+            conn.flush(IoMode::NonBlocking)
+                .expect("failed to flush the connection");
+            let handled = self
+                .dispatch_requests()
+                .expect("failed to receive requests");
+            self.socket
+                .flush(IoMode::NonBlocking)
+                .expect("failed to flush events");
+
+            match conn.recv_events(IoMode::NonBlocking) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => panic!("failed to receive events: {e}"),
+            }
+            conn.dispatch_events(state);
+
+            if handled == 0 {
+                break;
+            }
+        }
+    }
+
+    /// Receive and handle all pending requests. Returns the number of requests received.
+    ///
+    /// `wl_display.sync`, `wl_display.get_registry` and `wl_registry.bind` are handled
+    /// internally, other requests are recorded and can be retrieved with
+    /// [`take_requests`](Self::take_requests).
+    pub fn dispatch_requests(&mut self) -> io::Result<usize> {
+        let mut handled = 0;
+        loop {
+            let header = match self.socket.peek_message_header(IoMode::NonBlocking) {
+                Ok(header) => header,
+                Err(PeekHeaderError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(PeekHeaderError::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+
+            let object = *self.objects.get(&header.object_id).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "request for unknown object")
+            })?;
+            let desc = object
+                .interface
+                .requests
+                .get(header.opcode as usize)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown opcode"))?;
+
+            let request = self
+                .socket
+                .recv_message(
+                    header,
+                    desc.signature,
+                    &mut self.msg_buffers_pool,
+                    IoMode::NonBlocking,
+                )
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            handled += 1;
+
+            for (arg, arg_ty) in request.args.iter().zip(desc.signature) {
+                if let (ArgValue::NewId(id), ArgType::NewId(interface)) = (arg, arg_ty) {
+                    self.objects.insert(
+                        *id,
+                        Object {
+                            id: *id,
+                            interface,
+                            version: object.version,
+                        },
+                    );
+                }
+            }
+
+            if object.interface == WlDisplay::INTERFACE {
+                self.handle_display_request(request);
+            } else if object.interface == WlRegistry::INTERFACE {
+                self.handle_registry_request(request)?;
+            } else {
+                if desc.is_destructor {
+                    self.remove_object(object.id);
+                }
+                self.requests.push(ReceivedRequest {
+                    object,
+                    name: desc.name,
+                    opcode: header.opcode,
+                    args: request.args,
+                });
+            }
+        }
+        Ok(handled)
+    }
+
+    fn handle_display_request(&mut self, mut request: Message) {
+        let Some(ArgValue::NewId(new_id)) = request.args.pop() else {
+            unreachable!()
+        };
+        match request.header.opcode {
+            // sync
+            0 => {
+                let serial = self.next_serial();
+                let callback = self.objects[&new_id];
+                self.send_event(callback, "done", vec![ArgValue::Uint(serial)]);
+            }
+            // get_registry
+            1 => {
+                self.registries.push(new_id);
+                let globals: Vec<_> = self
+                    .globals
+                    .iter()
+                    .map(|g| (g.name, g.interface, g.version))
+                    .collect();
+                for (name, interface, version) in globals {
+                    self.send_global(new_id, name, interface, version);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn handle_registry_request(&mut self, mut request: Message) -> io::Result<()> {
+        let Some(ArgValue::AnyNewId(interface, version, id)) = request.args.pop() else {
+            unreachable!()
+        };
+        let Some(ArgValue::Uint(name)) = request.args.pop() else {
+            unreachable!()
+        };
+
+        let global = self
+            .globals
+            .iter()
+            .find(|g| g.name == name)
+            .filter(|g| g.interface.name == interface.as_ref() && version <= g.version)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid bind request"))?;
+
+        self.objects.insert(
+            id,
+            Object {
+                id,
+                interface: global.interface,
+                version,
+            },
+        );
+        Ok(())
+    }
+
+    fn send_global(
+        &mut self,
+        registry: ObjectId,
+        name: u32,
+        interface: &'static Interface,
+        version: u32,
+    ) {
+        self.send_event(
+            Self::registry_object(registry),
+            "global",
+            vec![
+                ArgValue::Uint(name),
+                ArgValue::String(CString::from(interface.name)),
+                ArgValue::Uint(version),
+            ],
+        );
+    }
+
+    fn remove_object(&mut self, id: ObjectId) {
+        self.objects.remove(&id);
+        if id.created_by_client() {
+            self.send_event(
+                WlDisplay::INSTANCE,
+                "delete_id",
+                vec![ArgValue::Uint(id.as_u32())],
+            );
+        }
+    }
+
+    fn registry_object(id: ObjectId) -> Object {
+        Object {
+            id,
+            interface: WlRegistry::INTERFACE,
+            version: 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_recorded() {
+        let (mut mock, mut conn) = MockCompositor::new::<()>();
+        mock.add_global::<WlOutput>(4);
+        mock.roundtrip(&mut conn, &mut ());
+
+        let output: WlOutput = conn.bind_singleton(4).unwrap();
+        assert!(mock.objects_of::<WlOutput>().is_empty());
+        mock.roundtrip(&mut conn, &mut ());
+        assert_eq!(mock.objects_of::<WlOutput>(), [output]);
+
+        output.release(&mut conn);
+        mock.roundtrip(&mut conn, &mut ());
+
+        let requests = mock.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].object, output.id());
+        assert_eq!(requests[0].name, "release");
+        assert!(mock.objects_of::<WlOutput>().is_empty());
+    }
+}
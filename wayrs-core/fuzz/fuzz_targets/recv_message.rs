@@ -0,0 +1,72 @@
+//! Feed arbitrary bytes to `BufferedSocket::recv_message`.
+//!
+//! The first byte is the number of arguments, the following bytes select their types and the
+//! rest is the incoming data.
+
+#![no_main]
+
+use std::time::Duration;
+
+use libfuzzer_sys::fuzz_target;
+use wayrs_core::transport::capture::{Direction, Record, Replay};
+use wayrs_core::transport::{BufferedSocket, RecvMessageError};
+use wayrs_core::{ArgType, Interface, IoMode, MessageBuffersPool};
+
+static DUMMY: Interface = Interface {
+    name: c"dummy",
+    version: 1,
+    events: &[],
+    requests: &[],
+    errors: &[],
+};
+
+fn arg_type(t: u8) -> ArgType {
+    match t % 11 {
+        0 => ArgType::Int,
+        1 => ArgType::Uint,
+        2 => ArgType::Fixed,
+        3 => ArgType::Object,
+        4 => ArgType::OptObject,
+        5 => ArgType::NewId(&DUMMY),
+        6 => ArgType::AnyNewId,
+        7 => ArgType::String,
+        8 => ArgType::OptString,
+        9 => ArgType::Array,
+        _ => ArgType::Fd,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&args_cnt, data)) = data.split_first() else {
+        return;
+    };
+    let args_cnt = (args_cnt % 8) as usize;
+    if data.len() < args_cnt {
+        return;
+    }
+    let (types, bytes) = data.split_at(args_cnt);
+    let signature: Vec<ArgType> = types.iter().copied().map(arg_type).collect();
+
+    let record = Record {
+        direction: Direction::Received,
+        timestamp: Duration::ZERO,
+        fds: 4,
+        bytes: bytes.to_vec(),
+    };
+    let mut socket = BufferedSocket::from(Replay::from_records(vec![record]).unwrap());
+    let mut pool = MessageBuffersPool::default();
+
+    while let Ok(header) = socket.peek_message_header(IoMode::NonBlocking) {
+        match socket.recv_message(header, &signature, &mut pool, IoMode::NonBlocking) {
+            Ok(msg) => pool.reuse_args(msg.args),
+            // The message was skipped, the stream must still be in sync
+            Err(
+                RecvMessageError::ArgOutOfBounds { .. }
+                | RecvMessageError::UnexpectedNull { .. }
+                | RecvMessageError::NullInString { .. }
+                | RecvMessageError::TrailingBytes { .. },
+            ) => (),
+            Err(_) => break,
+        }
+    }
+});
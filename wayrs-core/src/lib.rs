@@ -6,9 +6,11 @@ use std::borrow::Cow;
 use std::ffi::{CStr, CString};
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::io;
 use std::num::NonZeroU32;
 use std::os::fd::OwnedFd;
 
+pub mod protocol;
 mod ring_buffer;
 pub mod transport;
 
@@ -82,6 +84,16 @@ pub struct Message {
     pub args: Vec<ArgValue>,
 }
 
+/// A Wayland message which borrows its strings and arrays from the socket buffer
+///
+/// Returned by [`BufferedSocket::recv_message_borrowed`](transport::BufferedSocket::recv_message_borrowed).
+/// Decoding a message this way does not allocate.
+#[derive(Debug)]
+pub struct BorrowedMessage<'a> {
+    pub header: MessageHeader,
+    pub args: transport::BorrowedArgs<'a>,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ArgType {
     Int,
@@ -134,6 +146,61 @@ impl ArgValue {
             Self::Fd(_) => 0,
         }
     }
+
+    /// Borrow the strings and arrays of this value. File descriptors are duplicated.
+    pub fn try_borrow(&self) -> io::Result<BorrowedArgValue<'_>> {
+        Ok(match self {
+            Self::Int(x) => BorrowedArgValue::Int(*x),
+            Self::Uint(x) => BorrowedArgValue::Uint(*x),
+            Self::Fixed(x) => BorrowedArgValue::Fixed(*x),
+            Self::Object(x) => BorrowedArgValue::Object(*x),
+            Self::OptObject(x) => BorrowedArgValue::OptObject(*x),
+            Self::NewId(x) => BorrowedArgValue::NewId(*x),
+            Self::AnyNewId(iface, version, id) => BorrowedArgValue::AnyNewId(iface, *version, *id),
+            Self::String(x) => BorrowedArgValue::String(x),
+            Self::OptString(x) => BorrowedArgValue::OptString(x.as_deref()),
+            Self::Array(x) => BorrowedArgValue::Array(x),
+            Self::Fd(x) => BorrowedArgValue::Fd(x.try_clone()?),
+        })
+    }
+}
+
+/// A borrowed version of [`ArgValue`]
+#[derive(Debug)]
+pub enum BorrowedArgValue<'a> {
+    Int(i32),
+    Uint(u32),
+    Fixed(Fixed),
+    Object(ObjectId),
+    OptObject(Option<ObjectId>),
+    NewId(ObjectId),
+    AnyNewId(&'a CStr, u32, ObjectId),
+    String(&'a CStr),
+    OptString(Option<&'a CStr>),
+    Array(&'a [u8]),
+    Fd(OwnedFd),
+}
+
+impl BorrowedArgValue<'_> {
+    /// Copy the borrowed data, converting this value into an [`ArgValue`].
+    #[must_use]
+    pub fn into_owned(self) -> ArgValue {
+        match self {
+            Self::Int(x) => ArgValue::Int(x),
+            Self::Uint(x) => ArgValue::Uint(x),
+            Self::Fixed(x) => ArgValue::Fixed(x),
+            Self::Object(x) => ArgValue::Object(x),
+            Self::OptObject(x) => ArgValue::OptObject(x),
+            Self::NewId(x) => ArgValue::NewId(x),
+            Self::AnyNewId(iface, version, id) => {
+                ArgValue::AnyNewId(Cow::Owned(iface.to_owned()), version, id)
+            }
+            Self::String(x) => ArgValue::String(x.to_owned()),
+            Self::OptString(x) => ArgValue::OptString(x.map(CStr::to_owned)),
+            Self::Array(x) => ArgValue::Array(x.to_vec()),
+            Self::Fd(x) => ArgValue::Fd(x),
+        }
+    }
 }
 
 /// Signed 24.8 decimal number
@@ -204,6 +271,16 @@ pub struct Interface {
     pub version: u32,
     pub events: &'static [MessageDesc],
     pub requests: &'static [MessageDesc],
+    /// The entries of the interface's `error` enum, if it has one.
+    pub errors: &'static [ErrorDesc],
+}
+
+impl Interface {
+    /// Get the name of an error code from the interface's `error` enum.
+    #[must_use]
+    pub fn error_name(&self, code: u32) -> Option<&'static str> {
+        self.errors.iter().find(|e| e.value == code).map(|e| e.name)
+    }
 }
 
 /// A "description" of a single Wayland event or request
@@ -211,9 +288,18 @@ pub struct Interface {
 pub struct MessageDesc {
     pub name: &'static str,
     pub is_destructor: bool,
+    /// The version of the interface this message was introduced in.
+    pub since: u32,
     pub signature: &'static [ArgType],
 }
 
+/// A "description" of a single entry of an interface's `error` enum
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorDesc {
+    pub name: &'static str,
+    pub value: u32,
+}
+
 impl PartialEq for &'static Interface {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
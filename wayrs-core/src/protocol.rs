@@ -0,0 +1,5 @@
+//! Descriptions of the core Wayland protocol interfaces
+//!
+//! These are shared by the protocol bindings of `wayrs-client` and `wayrs-server`.
+
+wayrs_scanner::generate_interfaces!(crate, core_protocol);
@@ -1,12 +1,11 @@
 use std::io::{IoSlice, IoSliceMut};
-use std::num::NonZeroU32;
-
-use crate::ObjectId;
 
 pub struct RingBuffer {
     bytes: Box<[u8]>,
     offset: usize,
     len: usize,
+    /// Holds the last message read with [`Self::read_contiguous`] if it wrapped around.
+    scratch: Vec<u8>,
 }
 
 impl RingBuffer {
@@ -15,9 +14,24 @@ impl RingBuffer {
             bytes: Box::from(vec![0; size]),
             offset: 0,
             len: 0,
+            scratch: Vec::new(),
         }
     }
 
+    pub fn capacity(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Grow the buffer to `size` bytes, keeping its contents.
+    pub fn grow(&mut self, size: usize) {
+        assert!(size >= self.bytes.len());
+
+        let mut bytes = vec![0; size].into_boxed_slice();
+        self.peek_bytes(&mut bytes[..self.len]);
+        self.bytes = bytes;
+        self.offset = 0;
+    }
+
     pub fn move_head(&mut self, n: usize) {
         self.len += n;
     }
@@ -76,9 +90,24 @@ impl RingBuffer {
         }
     }
 
-    pub fn read_bytes(&mut self, buf: &mut [u8]) {
-        self.peek_bytes(buf);
-        self.move_tail(buf.len());
+    /// Consume `n` bytes and return them as a single slice.
+    ///
+    /// If the bytes wrap around the end of the buffer, they are copied to a scratch buffer.
+    pub fn read_contiguous(&mut self, n: usize) -> &[u8] {
+        assert!(self.readable_len() >= n);
+
+        let start = self.offset;
+        if start + n > self.bytes.len() {
+            self.scratch.resize(n, 0);
+            let mut scratch = std::mem::take(&mut self.scratch);
+            self.peek_bytes(&mut scratch);
+            self.scratch = scratch;
+            self.move_tail(n);
+            return &self.scratch;
+        }
+
+        self.move_tail(n);
+        &self.bytes[start..][..n]
     }
 
     pub fn get_writeable_iov<'b, 'a: 'b>(
@@ -130,20 +159,24 @@ impl RingBuffer {
     pub fn write_uint(&mut self, val: u32) {
         self.write_bytes(&val.to_ne_bytes());
     }
+}
 
-    pub fn read_int(&mut self) -> i32 {
-        let mut buf = [0; 4];
-        self.read_bytes(&mut buf);
-        i32::from_ne_bytes(buf)
-    }
-
-    pub fn read_uint(&mut self) -> u32 {
-        let mut buf = [0; 4];
-        self.read_bytes(&mut buf);
-        u32::from_ne_bytes(buf)
-    }
-
-    pub fn read_id(&mut self) -> Option<ObjectId> {
-        NonZeroU32::new(self.read_uint()).map(ObjectId)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_contiguous_wrapped() {
+        let mut buf = RingBuffer::new(8);
+        buf.write_bytes(&[0, 1, 2, 3, 4, 5]);
+        assert_eq!(buf.read_contiguous(4), [0, 1, 2, 3]);
+        buf.write_bytes(&[6, 7, 8, 9]);
+        assert_eq!(buf.read_contiguous(6), [4, 5, 6, 7, 8, 9]);
+        assert!(buf.is_empty());
+
+        // The buffer itself is not rearranged.
+        buf.write_bytes(&[10, 11]);
+        assert_eq!(buf.bytes[..4], [8, 9, 10, 11]);
+        assert_eq!(buf.read_contiguous(2), [10, 11]);
     }
 }
@@ -1,8 +1,7 @@
 //! Wayland transport methods
 
-use std::borrow::Cow;
-use std::collections::VecDeque;
-use std::ffi::CString;
+use std::collections::{vec_deque, VecDeque};
+use std::ffi::CStr;
 use std::fmt;
 use std::io::{self, IoSlice, IoSliceMut};
 use std::num::NonZeroU32;
@@ -10,9 +9,13 @@ use std::os::fd::{AsRawFd, OwnedFd, RawFd};
 
 use crate::ring_buffer::RingBuffer;
 use crate::{
-    ArgType, ArgValue, Fixed, IoMode, Message, MessageBuffersPool, MessageHeader, ObjectId,
+    ArgType, ArgValue, BorrowedArgValue, BorrowedMessage, Fixed, IoMode, Message,
+    MessageBuffersPool, MessageHeader, ObjectId,
 };
 
+pub mod capture;
+pub mod loopback;
+
 mod unix;
 
 pub const BYTES_OUT_LEN: usize = 4096;
@@ -20,6 +23,9 @@ pub const BYTES_IN_LEN: usize = BYTES_OUT_LEN * 2;
 pub const FDS_OUT_LEN: usize = 28;
 pub const FDS_IN_LEN: usize = FDS_OUT_LEN * 2;
 
+/// The default limit for the sizes of the buffers, see [`BufferedSocket::set_max_buffer_size`].
+pub const DEFAULT_MAX_BUFFER_SIZE: usize = 64 * 1024;
+
 /// A buffered Wayland socket
 ///
 /// Handles message marshalling and unmarshalling. This struct is generic over [`Transport`], which
@@ -32,6 +38,7 @@ pub struct BufferedSocket<T> {
     bytes_out: RingBuffer,
     fds_in: VecDeque<OwnedFd>,
     fds_out: VecDeque<OwnedFd>,
+    max_buffer_size: usize,
 }
 
 /// An abstraction over Wayland transport methods
@@ -48,6 +55,25 @@ pub trait Transport {
     ) -> io::Result<usize>;
 }
 
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn pollable_fd(&self) -> RawFd {
+        (**self).pollable_fd()
+    }
+
+    fn send(&mut self, bytes: &[IoSlice], fds: &[OwnedFd], mode: IoMode) -> io::Result<usize> {
+        (**self).send(bytes, fds, mode)
+    }
+
+    fn recv(
+        &mut self,
+        bytes: &mut [IoSliceMut],
+        fds: &mut VecDeque<OwnedFd>,
+        mode: IoMode,
+    ) -> io::Result<usize> {
+        (**self).recv(bytes, fds, mode)
+    }
+}
+
 impl<T: Transport> AsRawFd for BufferedSocket<T> {
     fn as_raw_fd(&self) -> RawFd {
         self.socket.pollable_fd()
@@ -62,6 +88,7 @@ impl<T: Transport> From<T> for BufferedSocket<T> {
             bytes_out: RingBuffer::new(BYTES_OUT_LEN),
             fds_in: VecDeque::new(),
             fds_out: VecDeque::new(),
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
         }
     }
 }
@@ -72,14 +99,86 @@ pub struct SendMessageError {
     pub err: io::Error,
 }
 
+/// A message could not be sent because it exceeds the limits of the socket buffers.
+///
+/// This error is returned wrapped in an [`io::Error`] of kind
+/// [`InvalidInput`](io::ErrorKind::InvalidInput). Use [`BufferLimitError::from_io`] to extract it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferLimitError {
+    /// The message is larger than the maximum buffer size or the maximum message size allowed by
+    /// the wire format.
+    TooManyBytes { size: usize, limit: usize },
+    /// The message has more file descriptors than can be sent at once.
+    TooManyFds { count: usize, limit: usize },
+}
+
+impl BufferLimitError {
+    /// Get a reference to a `BufferLimitError` if `error` wraps one.
+    #[must_use]
+    pub fn from_io(error: &io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl std::error::Error for BufferLimitError {}
+
+impl fmt::Display for BufferLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyBytes { size, limit } => {
+                write!(
+                    f,
+                    "message of {size} bytes exceeds the limit of {limit} bytes"
+                )
+            }
+            Self::TooManyFds { count, limit } => {
+                write!(
+                    f,
+                    "message with {count} fds exceeds the limit of {limit} fds"
+                )
+            }
+        }
+    }
+}
+
+impl From<BufferLimitError> for io::Error {
+    fn from(value: BufferLimitError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, value)
+    }
+}
+
 /// An error occured while trying to receive a message
+///
+/// Argument indices start at zero. Apart from [`Io`](Self::Io), [`TooManyFds`](Self::TooManyFds),
+/// [`TooManyBytes`](Self::TooManyBytes) and [`InvalidSize`](Self::InvalidSize), the malformed
+/// message is skipped, so the following messages can still be received.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum RecvMessageError {
     Io(io::Error),
     TooManyFds,
     TooManyBytes,
-    UnexpectedNull,
-    NullInString,
+    /// The size in the header is smaller than the header itself or is not a multiple of 4.
+    InvalidSize {
+        size: u16,
+    },
+    /// The argument does not fit into the message.
+    ArgOutOfBounds {
+        arg: usize,
+    },
+    /// The argument is null, but it is not nullable.
+    UnexpectedNull {
+        arg: usize,
+    },
+    /// The string argument contains a null byte or is not null-terminated.
+    NullInString {
+        arg: usize,
+    },
+    /// The message is larger than its arguments.
+    TrailingBytes {
+        size: u16,
+        consumed: usize,
+    },
 }
 
 impl std::error::Error for RecvMessageError {}
@@ -90,8 +189,23 @@ impl fmt::Display for RecvMessageError {
             Self::Io(error) => write!(f, "io: {error}"),
             Self::TooManyFds => f.write_str("message has too many file descriptors"),
             Self::TooManyBytes => f.write_str("message is too large"),
-            Self::UnexpectedNull => f.write_str("message contains unexpected null"),
-            Self::NullInString => f.write_str("message contains null byte in a string"),
+            Self::InvalidSize { size } => write!(f, "message has invalid size {size}"),
+            Self::ArgOutOfBounds { arg } => {
+                write!(f, "argument {arg} exceeds the message size")
+            }
+            Self::UnexpectedNull { arg } => {
+                write!(f, "message contains unexpected null in argument {arg}")
+            }
+            Self::NullInString { arg } => {
+                write!(
+                    f,
+                    "message contains null byte in a string in argument {arg}"
+                )
+            }
+            Self::TrailingBytes { size, consumed } => write!(
+                f,
+                "message size is {size}, but its arguments only take {consumed} bytes"
+            ),
         }
     }
 }
@@ -115,14 +229,43 @@ impl fmt::Display for PeekHeaderError {
 }
 
 impl<T: Transport> BufferedSocket<T> {
-    /// Write a single Wayland message into the intevnal buffer.
+    /// Set the maximum size of the incoming and outgoing buffers.
     ///
-    /// Flushes the buffer if neccessary. On failure, ownership of the message is returned.
+    /// The buffers start small and grow as needed, up to this limit. Messages larger than the
+    /// limit cannot be sent or received. The default is [`DEFAULT_MAX_BUFFER_SIZE`]. Buffers never
+    /// shrink, so lowering the limit does not free any memory.
+    pub fn set_max_buffer_size(&mut self, size: usize) {
+        self.max_buffer_size = size;
+    }
+
+    /// Get the maximum size of the incoming and outgoing buffers.
+    #[must_use]
+    pub fn max_buffer_size(&self) -> usize {
+        self.max_buffer_size
+    }
+
+    /// Grow the incoming and outgoing buffers to at least `size` bytes, capped by the
+    /// [maximum buffer size](Self::set_max_buffer_size).
+    ///
+    /// Can be used to avoid reallocations when a lot of data is expected.
+    pub fn reserve(&mut self, size: usize) {
+        let size = size.min(self.max_buffer_size);
+        if self.bytes_in.capacity() < size {
+            self.bytes_in.grow(size);
+        }
+        if self.bytes_out.capacity() < size {
+            self.bytes_out.grow(size);
+        }
+    }
+
+    /// Write a single Wayland message into the intevnal buffer.
     ///
-    /// # Panics
+    /// Flushes the buffer if neccessary. If flushing would block, the buffer grows instead, up to
+    /// the [maximum buffer size](Self::set_max_buffer_size). On failure, ownership of the message
+    /// is returned.
     ///
-    /// This function panics if the message size is larger than `BYTES_OUT_LEN` or it contains more
-    /// than `FDS_OUT_LEN` file descriptors.
+    /// If the message is larger than the maximum buffer size or it contains more than
+    /// `FDS_OUT_LEN` file descriptors, a [`BufferLimitError`] is returned.
     pub fn write_message(
         &mut self,
         msg: Message,
@@ -137,14 +280,34 @@ impl<T: Transport> BufferedSocket<T> {
             .filter(|arg| matches!(arg, ArgValue::Fd(_)))
             .count();
 
-        // Check size and flush if neccessary
-        assert!(size <= BYTES_OUT_LEN);
-        assert!(fds_cnt <= FDS_OUT_LEN);
+        // Check size and flush or grow the buffer if neccessary
+        let limit = self.max_buffer_size.min(u16::MAX as usize);
+        if size > limit {
+            let err = BufferLimitError::TooManyBytes { size, limit }.into();
+            return Err(SendMessageError { msg, err });
+        }
+        if fds_cnt > FDS_OUT_LEN {
+            let err = BufferLimitError::TooManyFds {
+                count: fds_cnt,
+                limit: FDS_OUT_LEN,
+            }
+            .into();
+            return Err(SendMessageError { msg, err });
+        }
         if size > self.bytes_out.writable_len() || fds_cnt + self.fds_out.len() > FDS_OUT_LEN {
-            if let Err(err) = self.flush(mode) {
-                return Err(SendMessageError { msg, err });
+            match self.flush(mode) {
+                Ok(()) => (),
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        && fds_cnt + self.fds_out.len() <= FDS_OUT_LEN
+                        && self.bytes_out.readable_len() + size <= self.max_buffer_size => {}
+                Err(err) => return Err(SendMessageError { msg, err }),
             }
         }
+        let required = self.bytes_out.readable_len() + size;
+        if required > self.bytes_out.capacity() {
+            self.bytes_out.grow(self.grown_size(required));
+        }
 
         // Header
         self.bytes_out.write_uint(msg.header.object_id.0.get());
@@ -203,6 +366,9 @@ impl<T: Transport> BufferedSocket<T> {
     ///
     /// Fills the internal buffer if needed. `header` must be the value returned by
     /// [`Self::peek_message_header`] right before calling this function.
+    ///
+    /// The arguments are validated against the signature and the size of the message. See
+    /// [`RecvMessageError`] for the possible errors.
     pub fn recv_message(
         &mut self,
         header: MessageHeader,
@@ -210,59 +376,97 @@ impl<T: Transport> BufferedSocket<T> {
         msg_pool: &mut MessageBuffersPool,
         mode: IoMode,
     ) -> Result<Message, RecvMessageError> {
-        // Check size and fill buffer if necessary
+        let msg = self.recv_message_borrowed(header, signature, mode)?;
+        let mut args = msg_pool.get_args();
+        args.extend(msg.args.map(BorrowedArgValue::into_owned));
+        Ok(Message { header, args })
+    }
+
+    /// Receive the entire next message without copying its strings and arrays.
+    ///
+    /// This is the same as [`Self::recv_message`], except that the returned message borrows the
+    /// internal buffer, so it must be dropped before the socket can be used again. The message is
+    /// fully validated before it is returned, so iterating over its arguments cannot fail.
+    pub fn recv_message_borrowed<'a>(
+        &'a mut self,
+        header: MessageHeader,
+        signature: &'a [ArgType],
+        mode: IoMode,
+    ) -> Result<BorrowedMessage<'a>, RecvMessageError> {
         let fds_cnt = signature
             .iter()
             .filter(|arg| matches!(arg, ArgType::Fd))
             .count();
-        if header.size as usize > BYTES_IN_LEN {
+        self.fill_message(header, fds_cnt, mode)?;
+
+        // Consume the message. If it turns out to be malformed, it is skipped along with its file
+        // descriptors.
+        let bytes = self.bytes_in.read_contiguous(header.size as usize);
+        let fds = self.fds_in.drain(..fds_cnt);
+        let body = &bytes[MessageHeader::SIZE..];
+
+        let mut reader = ArgReader { body, arg: 0 };
+        for (i, arg_type) in signature.iter().enumerate() {
+            if *arg_type != ArgType::Fd {
+                reader.arg = i;
+                reader.read_arg(arg_type)?;
+            }
+        }
+        if !reader.body.is_empty() {
+            return Err(RecvMessageError::TrailingBytes {
+                size: header.size,
+                consumed: header.size as usize - reader.body.len(),
+            });
+        }
+
+        Ok(BorrowedMessage {
+            header,
+            args: BorrowedArgs {
+                reader: ArgReader { body, arg: 0 },
+                signature: signature.iter(),
+                fds,
+            },
+        })
+    }
+
+    /// Discard the next message without decoding it.
+    ///
+    /// `header` must be the value returned by [`Self::peek_message_header`] right before calling
+    /// this function. File descriptors are not discarded, since their number is not known without
+    /// the signature of the message.
+    pub fn skip_message(
+        &mut self,
+        header: MessageHeader,
+        mode: IoMode,
+    ) -> Result<(), RecvMessageError> {
+        self.fill_message(header, 0, mode)?;
+        self.bytes_in.move_tail(header.size as usize);
+        Ok(())
+    }
+
+    /// Check the size of the message and fill the buffers until the entire message is available.
+    fn fill_message(
+        &mut self,
+        header: MessageHeader,
+        fds_cnt: usize,
+        mode: IoMode,
+    ) -> Result<(), RecvMessageError> {
+        if (header.size as usize) < MessageHeader::SIZE || header.size % 4 != 0 {
+            return Err(RecvMessageError::InvalidSize { size: header.size });
+        }
+        if header.size as usize > self.max_buffer_size {
             return Err(RecvMessageError::TooManyBytes);
         }
+        if header.size as usize > self.bytes_in.capacity() {
+            self.bytes_in.grow(self.grown_size(header.size as usize));
+        }
         if fds_cnt > FDS_IN_LEN {
             return Err(RecvMessageError::TooManyFds);
         }
         while header.size as usize > self.bytes_in.readable_len() || fds_cnt > self.fds_in.len() {
             self.fill_incoming_buf(mode).map_err(RecvMessageError::Io)?;
         }
-
-        // Consume header
-        self.bytes_in.move_tail(MessageHeader::SIZE);
-
-        let mut args = msg_pool.get_args();
-        for arg_type in signature {
-            args.push(match arg_type {
-                ArgType::Int => ArgValue::Int(self.bytes_in.read_int()),
-                ArgType::Uint => ArgValue::Uint(self.bytes_in.read_uint()),
-                ArgType::Fixed => ArgValue::Fixed(Fixed(self.bytes_in.read_int())),
-                ArgType::Object => ArgValue::Object(
-                    self.bytes_in
-                        .read_id()
-                        .ok_or(RecvMessageError::UnexpectedNull)?,
-                ),
-                ArgType::OptObject => ArgValue::OptObject(self.bytes_in.read_id()),
-                ArgType::NewId(_interface) => ArgValue::NewId(
-                    self.bytes_in
-                        .read_id()
-                        .ok_or(RecvMessageError::UnexpectedNull)?,
-                ),
-                ArgType::AnyNewId => ArgValue::AnyNewId(
-                    Cow::Owned(self.recv_string()?),
-                    self.bytes_in.read_uint(),
-                    self.bytes_in
-                        .read_id()
-                        .ok_or(RecvMessageError::UnexpectedNull)?,
-                ),
-                ArgType::String => ArgValue::String(self.recv_string()?),
-                ArgType::OptString => ArgValue::OptString(match self.bytes_in.read_uint() {
-                    0 => None,
-                    len => Some(self.recv_string_with_len(len)?),
-                }),
-                ArgType::Array => ArgValue::Array(self.recv_array()),
-                ArgType::Fd => ArgValue::Fd(self.fds_in.pop_front().unwrap()),
-            });
-        }
-
-        Ok(Message { header, args })
+        Ok(())
     }
 
     /// Flush all pending messages.
@@ -294,6 +498,14 @@ impl<T: Transport> BufferedSocket<T> {
         &mut self.socket
     }
 
+    /// The new size of a buffer which has to hold at least `required` bytes.
+    fn grown_size(&self, required: usize) -> usize {
+        required
+            .next_power_of_two()
+            .min(self.max_buffer_size)
+            .max(required)
+    }
+
     fn fill_incoming_buf(&mut self, mode: IoMode) -> io::Result<()> {
         if self.bytes_in.is_full() {
             return Ok(());
@@ -317,35 +529,264 @@ impl<T: Transport> BufferedSocket<T> {
         let padding = ((4 - (len % 4)) % 4) as usize;
         self.bytes_out.write_bytes(&[0, 0, 0][..padding]);
     }
+}
 
-    fn recv_array(&mut self) -> Vec<u8> {
-        let len = self.bytes_in.read_uint() as usize;
+/// An iterator over the arguments of a [`BorrowedMessage`]
+#[derive(Debug)]
+pub struct BorrowedArgs<'a> {
+    reader: ArgReader<'a>,
+    signature: std::slice::Iter<'a, ArgType>,
+    fds: vec_deque::Drain<'a, OwnedFd>,
+}
 
-        let mut buf = vec![0; len];
-        self.bytes_in.read_bytes(&mut buf);
+impl<'a> Iterator for BorrowedArgs<'a> {
+    type Item = BorrowedArgValue<'a>;
 
-        let padding = (4 - (len % 4)) % 4;
-        self.bytes_in.move_tail(padding);
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.signature.next()? {
+            ArgType::Fd => Some(BorrowedArgValue::Fd(self.fds.next().unwrap())),
+            // The message was validated when it was received
+            arg_type => Some(self.reader.read_arg(arg_type).unwrap()),
+        }
+    }
 
-        buf
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.signature.size_hint()
     }
+}
 
-    fn recv_string_with_len(&mut self, len: u32) -> Result<CString, RecvMessageError> {
-        let mut buf = vec![0; len as usize];
-        self.bytes_in.read_bytes(&mut buf);
+impl ExactSizeIterator for BorrowedArgs<'_> {}
 
-        let padding = (4 - (len % 4)) % 4;
-        self.bytes_in.move_tail(padding as usize);
+/// Reads the arguments from the body of a single message, making sure they do not exceed the
+/// message size.
+#[derive(Debug)]
+struct ArgReader<'a> {
+    body: &'a [u8],
+    arg: usize,
+}
 
-        CString::from_vec_with_nul(buf).map_err(|_| RecvMessageError::NullInString)
+impl<'a> ArgReader<'a> {
+    fn read_arg(&mut self, arg_type: &ArgType) -> Result<BorrowedArgValue<'a>, RecvMessageError> {
+        Ok(match arg_type {
+            ArgType::Int => BorrowedArgValue::Int(self.read_int()?),
+            ArgType::Uint => BorrowedArgValue::Uint(self.read_uint()?),
+            ArgType::Fixed => BorrowedArgValue::Fixed(Fixed(self.read_int()?)),
+            ArgType::Object => BorrowedArgValue::Object(self.read_id()?),
+            ArgType::OptObject => BorrowedArgValue::OptObject(self.read_opt_id()?),
+            ArgType::NewId(_interface) => BorrowedArgValue::NewId(self.read_id()?),
+            ArgType::AnyNewId => {
+                BorrowedArgValue::AnyNewId(self.read_string()?, self.read_uint()?, self.read_id()?)
+            }
+            ArgType::String => BorrowedArgValue::String(self.read_string()?),
+            ArgType::OptString => BorrowedArgValue::OptString(self.read_opt_string()?),
+            ArgType::Array => BorrowedArgValue::Array(self.read_array()?),
+            ArgType::Fd => unreachable!("file descriptors are not a part of the message body"),
+        })
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], RecvMessageError> {
+        if n > self.body.len() {
+            return Err(RecvMessageError::ArgOutOfBounds { arg: self.arg });
+        }
+        let (bytes, rest) = self.body.split_at(n);
+        self.body = rest;
+        Ok(bytes)
+    }
+
+    fn read_uint(&mut self) -> Result<u32, RecvMessageError> {
+        Ok(u32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_int(&mut self) -> Result<i32, RecvMessageError> {
+        Ok(i32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_opt_id(&mut self) -> Result<Option<ObjectId>, RecvMessageError> {
+        Ok(NonZeroU32::new(self.read_uint()?).map(ObjectId))
+    }
+
+    fn read_id(&mut self) -> Result<ObjectId, RecvMessageError> {
+        self.read_opt_id()?
+            .ok_or(RecvMessageError::UnexpectedNull { arg: self.arg })
+    }
+
+    /// Read `len` bytes followed by padding.
+    fn read_padded(&mut self, len: usize) -> Result<&'a [u8], RecvMessageError> {
+        if len > self.body.len() {
+            return Err(RecvMessageError::ArgOutOfBounds { arg: self.arg });
+        }
+        Ok(&self.take(len.next_multiple_of(4))?[..len])
     }
 
-    fn recv_string(&mut self) -> Result<CString, RecvMessageError> {
-        let len = self.bytes_in.read_uint();
-        if len == 0 {
-            Err(RecvMessageError::UnexpectedNull)
-        } else {
-            self.recv_string_with_len(len)
+    fn read_array(&mut self) -> Result<&'a [u8], RecvMessageError> {
+        let len = self.read_uint()? as usize;
+        self.read_padded(len)
+    }
+
+    fn read_opt_string(&mut self) -> Result<Option<&'a CStr>, RecvMessageError> {
+        match self.read_uint()? as usize {
+            0 => Ok(None),
+            len => {
+                let bytes = self.read_padded(len)?;
+                CStr::from_bytes_with_nul(bytes)
+                    .map(Some)
+                    .map_err(|_| RecvMessageError::NullInString { arg: self.arg })
+            }
+        }
+    }
+
+    fn read_string(&mut self) -> Result<&'a CStr, RecvMessageError> {
+        self.read_opt_string()?
+            .ok_or(RecvMessageError::UnexpectedNull { arg: self.arg })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn array_msg(len: usize) -> Message {
+        Message {
+            header: MessageHeader {
+                object_id: ObjectId::DISPLAY,
+                size: 0,
+                opcode: 0,
+            },
+            args: vec![ArgValue::Array(vec![7; len])],
         }
     }
+
+    #[test]
+    fn buffers_grow_up_to_limit() {
+        let (a, b) = loopback::pair().unwrap();
+        let mut a = BufferedSocket::from(a);
+        let mut b = BufferedSocket::from(b);
+        let mut pool = MessageBuffersPool::default();
+
+        a.write_message(array_msg(BYTES_IN_LEN * 2), &mut pool, IoMode::NonBlocking)
+            .map_err(|e| e.err)
+            .unwrap();
+        a.flush(IoMode::NonBlocking).unwrap();
+
+        let header = b.peek_message_header(IoMode::NonBlocking).unwrap();
+        let msg = b
+            .recv_message(header, &[ArgType::Array], &mut pool, IoMode::NonBlocking)
+            .unwrap();
+        assert!(matches!(&msg.args[0], ArgValue::Array(a) if a.len() == BYTES_IN_LEN * 2));
+
+        a.set_max_buffer_size(BYTES_OUT_LEN);
+        let err = a
+            .write_message(array_msg(BYTES_OUT_LEN), &mut pool, IoMode::NonBlocking)
+            .unwrap_err()
+            .err;
+        assert_eq!(
+            BufferLimitError::from_io(&err),
+            Some(&BufferLimitError::TooManyBytes {
+                size: BYTES_OUT_LEN + 12,
+                limit: BYTES_OUT_LEN,
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_messages_are_skipped() {
+        let (mut a, b) = loopback::pair().unwrap();
+        let mut b = BufferedSocket::from(b);
+        let mut pool = MessageBuffersPool::default();
+
+        let raw = |size: u16, args: &[u32]| {
+            let mut bytes = Vec::new();
+            bytes.extend_from_slice(&1u32.to_ne_bytes());
+            bytes.extend_from_slice(&((size as u32) << 16).to_ne_bytes());
+            for arg in args {
+                bytes.extend_from_slice(&arg.to_ne_bytes());
+            }
+            bytes
+        };
+        let mut bytes = Vec::new();
+        // A string which is longer than the message
+        bytes.extend(raw(16, &[100, 0]));
+        // A non-nullable null object
+        bytes.extend(raw(12, &[0]));
+        // A message with an extra argument
+        bytes.extend(raw(16, &[1, 2]));
+        // A valid message
+        bytes.extend(raw(12, &[42]));
+        a.send(&[IoSlice::new(&bytes)], &[], IoMode::NonBlocking)
+            .unwrap();
+
+        let mut recv = |signature: &[ArgType]| {
+            let header = b.peek_message_header(IoMode::NonBlocking).unwrap();
+            b.recv_message(header, signature, &mut pool, IoMode::NonBlocking)
+        };
+        assert!(matches!(
+            recv(&[ArgType::String]),
+            Err(RecvMessageError::ArgOutOfBounds { arg: 0 })
+        ));
+        assert!(matches!(
+            recv(&[ArgType::Object]),
+            Err(RecvMessageError::UnexpectedNull { arg: 0 })
+        ));
+        assert!(matches!(
+            recv(&[ArgType::Uint]),
+            Err(RecvMessageError::TrailingBytes {
+                size: 16,
+                consumed: 12
+            })
+        ));
+        let msg = recv(&[ArgType::Uint]).unwrap();
+        assert!(matches!(msg.args[0], ArgValue::Uint(42)));
+
+        a.send(&[IoSlice::new(&raw(6, &[]))], &[], IoMode::NonBlocking)
+            .unwrap();
+        assert!(matches!(
+            recv(&[]),
+            Err(RecvMessageError::InvalidSize { size: 6 })
+        ));
+    }
+
+    #[test]
+    fn borrowed_message_wrapping_around_buffer() {
+        let (a, b) = loopback::pair().unwrap();
+        let mut a = BufferedSocket::from(a);
+        let mut b = BufferedSocket::from(b);
+        let mut pool = MessageBuffersPool::default();
+
+        // The second message starts right before the end of the incoming ring buffer
+        a.write_message(array_msg(BYTES_IN_LEN - 24), &mut pool, IoMode::NonBlocking)
+            .map_err(|e| e.err)
+            .unwrap();
+        let msg = Message {
+            header: MessageHeader {
+                object_id: ObjectId::DISPLAY,
+                size: 0,
+                opcode: 1,
+            },
+            args: vec![
+                ArgValue::String(c"hello".into()),
+                ArgValue::Array(vec![1, 2, 3, 4, 5]),
+            ],
+        };
+        a.write_message(msg, &mut pool, IoMode::NonBlocking)
+            .map_err(|e| e.err)
+            .unwrap();
+        a.flush(IoMode::NonBlocking).unwrap();
+
+        let header = b.peek_message_header(IoMode::NonBlocking).unwrap();
+        b.recv_message(header, &[ArgType::Array], &mut pool, IoMode::NonBlocking)
+            .unwrap();
+
+        let header = b.peek_message_header(IoMode::NonBlocking).unwrap();
+        let signature = [ArgType::String, ArgType::Array];
+        let mut msg = b
+            .recv_message_borrowed(header, &signature, IoMode::NonBlocking)
+            .unwrap();
+        assert_eq!(msg.args.len(), 2);
+        assert!(matches!(msg.args.next(), Some(BorrowedArgValue::String(s)) if s == c"hello"));
+        assert!(matches!(
+            msg.args.next(),
+            Some(BorrowedArgValue::Array(&[1, 2, 3, 4, 5]))
+        ));
+        assert!(msg.args.next().is_none());
+    }
 }
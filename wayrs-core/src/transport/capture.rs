@@ -0,0 +1,357 @@
+//! Capturing and replaying Wayland traffic
+//!
+//! [`Recorder`] wraps another [`Transport`] and logs all the data flowing through it to a capture
+//! file. [`Replay`] is a transport which feeds the incoming data of a capture back to a
+//! connection, which is useful to reproduce bugs on compositors you do not run.
+//!
+//! `wayrs-client` records a capture when `WAYRS_CAPTURE=<path>` environment variable is set and
+//! capturing is enabled with `ConnectOptions::use_capture`. To replay it, create a connection with
+//! `Connection::with_transport(Replay::new(file)?)`.
+//!
+//! # File format
+//!
+//! All integers are little-endian. A capture starts with the magic bytes `WAYRSCAP` followed by
+//! a `u32` format version, currently [`VERSION`]. The rest of the file is a sequence of records:
+//!
+//! | Field       | Type            | Description                                              |
+//! |-------------|-----------------|----------------------------------------------------------|
+//! | `direction` | `u8`            | `0` if the data was sent, `1` if it was received         |
+//! | `timestamp` | `u64`           | Microseconds since the recording started                 |
+//! | `fds`       | `u32`           | Number of file descriptors transferred with the data     |
+//! | `len`       | `u32`           | Number of bytes transferred                              |
+//! | `bytes`     | `[u8; len]`     | The bytes                                                |
+//!
+//! Each record corresponds to a single successful [`Transport::send`] or [`Transport::recv`] call,
+//! so message boundaries are not preserved. The contents of the file descriptors are not recorded.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use super::Transport;
+use crate::IoMode;
+
+const MAGIC: &[u8; 8] = b"WAYRSCAP";
+
+/// The current version of the capture file format.
+pub const VERSION: u32 = 1;
+
+/// The direction of a captured chunk of data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A single record of a capture file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub direction: Direction,
+    /// Time since the recording started.
+    pub timestamp: Duration,
+    /// The number of file descriptors transferred with the data.
+    pub fds: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// A transport wrapper which records all the traffic to a capture file.
+///
+/// Failing to write the capture does not affect the wrapped transport, the first error is
+/// returned by [`finish`](Self::finish).
+#[derive(Debug)]
+pub struct Recorder<T, W> {
+    transport: T,
+    writer: W,
+    start: Instant,
+    error: Option<io::Error>,
+}
+
+impl<T: Transport, W: Write> Recorder<T, W> {
+    /// Wrap a transport, writing the capture to `writer`.
+    ///
+    /// This writes the file header immediately.
+    pub fn new(transport: T, mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self {
+            transport,
+            writer,
+            start: Instant::now(),
+            error: None,
+        })
+    }
+
+    /// Get a reference to the wrapped transport.
+    #[must_use]
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Flush the capture and return the wrapped transport and writer.
+    ///
+    /// Returns the first error which occurred while writing the capture, if any.
+    pub fn finish(mut self) -> io::Result<(T, W)> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        self.writer.flush()?;
+        Ok((self.transport, self.writer))
+    }
+
+    fn record<'a>(
+        &mut self,
+        direction: Direction,
+        fds: usize,
+        len: usize,
+        chunks: impl Iterator<Item = &'a [u8]>,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let timestamp = self.start.elapsed().as_micros() as u64;
+        let mut buf = Vec::with_capacity(17 + len);
+        buf.push(match direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        });
+        buf.extend_from_slice(&timestamp.to_le_bytes());
+        buf.extend_from_slice(&(fds as u32).to_le_bytes());
+        buf.extend_from_slice(&(len as u32).to_le_bytes());
+
+        let mut left = len;
+        for chunk in chunks {
+            let n = chunk.len().min(left);
+            buf.extend_from_slice(&chunk[..n]);
+            left -= n;
+        }
+
+        if let Err(error) = self.writer.write_all(&buf) {
+            self.error = Some(error);
+        }
+    }
+}
+
+impl<T: Transport, W: Write> Transport for Recorder<T, W> {
+    fn pollable_fd(&self) -> RawFd {
+        self.transport.pollable_fd()
+    }
+
+    fn send(&mut self, bytes: &[IoSlice], fds: &[OwnedFd], mode: IoMode) -> io::Result<usize> {
+        let sent = self.transport.send(bytes, fds, mode)?;
+        self.record(
+            Direction::Sent,
+            fds.len(),
+            sent,
+            bytes.iter().map(|slice| &**slice),
+        );
+        Ok(sent)
+    }
+
+    fn recv(
+        &mut self,
+        bytes: &mut [IoSliceMut],
+        fds: &mut VecDeque<OwnedFd>,
+        mode: IoMode,
+    ) -> io::Result<usize> {
+        let fds_before = fds.len();
+        let read = self.transport.recv(bytes, fds, mode)?;
+        self.record(
+            Direction::Received,
+            fds.len() - fds_before,
+            read,
+            bytes.iter().map(|slice| &**slice),
+        );
+        Ok(read)
+    }
+}
+
+/// Read all records of a capture.
+pub fn read_records(mut reader: impl Read) -> io::Result<Vec<Record>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a wayrs capture file"));
+    }
+
+    let mut version = [0; 4];
+    reader.read_exact(&mut version)?;
+    if u32::from_le_bytes(version) != VERSION {
+        return Err(invalid("unsupported capture file version"));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let mut direction = [0; 1];
+        match reader.read_exact(&mut direction) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let direction = match direction[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => return Err(invalid("invalid record direction")),
+        };
+
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        let timestamp = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let fds = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let len = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+        let mut bytes = vec![0; len as usize];
+        reader.read_exact(&mut bytes)?;
+
+        records.push(Record {
+            direction,
+            timestamp: Duration::from_micros(timestamp),
+            fds,
+            bytes,
+        });
+    }
+
+    Ok(records)
+}
+
+/// A transport which replays the received data of a capture.
+///
+/// Received records are returned as fast as possible, timestamps are ignored. Since the contents
+/// of file descriptors are not captured, each received file descriptor is replaced with
+/// `/dev/null`. Sent data is discarded. Once all the records are replayed, receiving fails with
+/// [`BrokenPipe`](io::ErrorKind::BrokenPipe).
+#[derive(Debug)]
+pub struct Replay {
+    incoming: VecDeque<Record>,
+    // Offset into the first incoming record.
+    offset: usize,
+    // A socket with unread data, so that the pollable fd is always readable.
+    pollable: (UnixStream, UnixStream),
+}
+
+impl Replay {
+    /// Create a replay transport from a capture.
+    pub fn new(reader: impl Read) -> io::Result<Self> {
+        Self::from_records(read_records(reader)?)
+    }
+
+    /// Create a replay transport from a list of records. Only received records are used.
+    pub fn from_records(records: Vec<Record>) -> io::Result<Self> {
+        let pollable = UnixStream::pair()?;
+        (&pollable.0).write_all(&[0])?;
+        Ok(Self {
+            incoming: records
+                .into_iter()
+                .filter(|r| r.direction == Direction::Received)
+                .collect(),
+            offset: 0,
+            pollable,
+        })
+    }
+}
+
+impl Transport for Replay {
+    fn pollable_fd(&self) -> RawFd {
+        self.pollable.1.as_raw_fd()
+    }
+
+    fn send(&mut self, bytes: &[IoSlice], _fds: &[OwnedFd], _mode: IoMode) -> io::Result<usize> {
+        Ok(bytes.iter().map(|slice| slice.len()).sum())
+    }
+
+    fn recv(
+        &mut self,
+        bytes: &mut [IoSliceMut],
+        fds: &mut VecDeque<OwnedFd>,
+        _mode: IoMode,
+    ) -> io::Result<usize> {
+        let Some(record) = self.incoming.front_mut() else {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "end of capture"));
+        };
+
+        if self.offset == 0 {
+            for _ in 0..record.fds {
+                fds.push_back(File::open("/dev/null")?.into());
+            }
+        }
+
+        let mut read = 0;
+        for slice in bytes {
+            let src = &record.bytes[self.offset..];
+            let n = slice.len().min(src.len());
+            slice[..n].copy_from_slice(&src[..n]);
+            self.offset += n;
+            read += n;
+        }
+
+        if self.offset == record.bytes.len() {
+            self.incoming.pop_front();
+            self.offset = 0;
+        }
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{loopback, BufferedSocket};
+    use crate::{ArgType, ArgValue, Message, MessageBuffersPool, MessageHeader, ObjectId};
+
+    #[test]
+    fn record_and_replay() {
+        let mut capture = Vec::new();
+        let (client, server) = loopback::pair().unwrap();
+        let mut client = BufferedSocket::from(Recorder::new(client, &mut capture).unwrap());
+        let mut server = BufferedSocket::from(server);
+        let mut pool = MessageBuffersPool::default();
+
+        let msg = |opcode| Message {
+            header: MessageHeader {
+                object_id: ObjectId::DISPLAY,
+                size: 0,
+                opcode,
+            },
+            args: vec![ArgValue::Uint(opcode as u32)],
+        };
+
+        client
+            .write_message(msg(1), &mut pool, IoMode::NonBlocking)
+            .map_err(|e| e.err)
+            .unwrap();
+        client.flush(IoMode::NonBlocking).unwrap();
+        server
+            .write_message(msg(2), &mut pool, IoMode::NonBlocking)
+            .map_err(|e| e.err)
+            .unwrap();
+        server.flush(IoMode::NonBlocking).unwrap();
+        let header = client.peek_message_header(IoMode::NonBlocking).unwrap();
+        client
+            .recv_message(header, &[ArgType::Uint], &mut pool, IoMode::NonBlocking)
+            .unwrap();
+
+        drop(client);
+
+        let records = read_records(capture.as_slice()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(records[0].bytes.len(), 12);
+        assert_eq!(records[1].direction, Direction::Received);
+        assert_eq!(records[1].bytes.len(), 12);
+
+        let mut replay = BufferedSocket::from(Replay::new(capture.as_slice()).unwrap());
+        let header = replay.peek_message_header(IoMode::NonBlocking).unwrap();
+        assert_eq!(header.opcode, 2);
+        let msg = replay
+            .recv_message(header, &[ArgType::Uint], &mut pool, IoMode::NonBlocking)
+            .unwrap();
+        assert!(matches!(msg.args[0], ArgValue::Uint(2)));
+        assert!(replay.peek_message_header(IoMode::NonBlocking).is_err());
+    }
+}
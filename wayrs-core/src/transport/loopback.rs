@@ -0,0 +1,220 @@
+//! In-process Wayland transport
+//!
+//! A pair of connected [`Loopback`] transports passes bytes and file descriptors through in-memory
+//! buffers, without involving a Wayland socket. This is mostly useful for testing: one end can be
+//! given to a client connection and the other end can be driven by a scripted fake server.
+
+use std::collections::VecDeque;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use super::Transport;
+use crate::IoMode;
+
+/// One end of an in-process transport, created with [`pair`].
+///
+/// Sending never blocks: the buffers grow as needed. File descriptors are duplicated when sent,
+/// so the receiving end gets its own copies, just like with `SCM_RIGHTS`.
+///
+/// [`pollable_fd`](Transport::pollable_fd) becomes readable when there is data to receive or when
+/// the other end was dropped, so this transport can be used with event loops.
+#[derive(Debug)]
+pub struct Loopback {
+    incoming: Arc<Channel>,
+    outgoing: Arc<Channel>,
+    // A socket connected to the other end. It is used only to make the pollable fd readable: one
+    // byte is written to it when the peer's incoming buffer becomes non-empty, and the byte is
+    // consumed once this end's incoming buffer becomes empty again.
+    notify: UnixStream,
+}
+
+#[derive(Debug, Default)]
+struct Channel {
+    state: Mutex<ChannelState>,
+    cond: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    bytes: VecDeque<u8>,
+    fds: VecDeque<OwnedFd>,
+    closed: bool,
+}
+
+/// Create a pair of connected in-process transports.
+pub fn pair() -> io::Result<(Loopback, Loopback)> {
+    let (notify_a, notify_b) = UnixStream::pair()?;
+    notify_a.set_nonblocking(true)?;
+    notify_b.set_nonblocking(true)?;
+
+    let a_to_b = Arc::new(Channel::default());
+    let b_to_a = Arc::new(Channel::default());
+
+    let a = Loopback {
+        incoming: b_to_a.clone(),
+        outgoing: a_to_b.clone(),
+        notify: notify_a,
+    };
+    let b = Loopback {
+        incoming: a_to_b,
+        outgoing: b_to_a,
+        notify: notify_b,
+    };
+
+    Ok((a, b))
+}
+
+impl Channel {
+    fn lock(&self) -> MutexGuard<'_, ChannelState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        self.outgoing.lock().closed = true;
+        self.outgoing.cond.notify_all();
+        self.incoming.lock().closed = true;
+        // `notify` is closed here, which makes the peer's pollable fd readable.
+    }
+}
+
+impl Transport for Loopback {
+    fn pollable_fd(&self) -> RawFd {
+        self.notify.as_raw_fd()
+    }
+
+    fn send(&mut self, bytes: &[IoSlice], fds: &[OwnedFd], _mode: IoMode) -> io::Result<usize> {
+        let fds = fds
+            .iter()
+            .map(OwnedFd::try_clone)
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut state = self.outgoing.lock();
+        if state.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "peer disconnected",
+            ));
+        }
+
+        let was_empty = state.bytes.is_empty();
+        let mut sent = 0;
+        for slice in bytes {
+            state.bytes.extend(slice.iter());
+            sent += slice.len();
+        }
+        state.fds.extend(fds);
+
+        if was_empty && sent > 0 {
+            // The socket buffer never has more than one pending byte, so this cannot block.
+            let _ = self.notify.write(&[0]);
+            self.outgoing.cond.notify_all();
+        }
+
+        Ok(sent)
+    }
+
+    fn recv(
+        &mut self,
+        bytes: &mut [IoSliceMut],
+        fds: &mut VecDeque<OwnedFd>,
+        mode: IoMode,
+    ) -> io::Result<usize> {
+        let mut state = self.incoming.lock();
+
+        while state.bytes.is_empty() {
+            if state.closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "peer disconnected",
+                ));
+            }
+            match mode {
+                IoMode::Blocking => {
+                    state = self
+                        .incoming
+                        .cond
+                        .wait(state)
+                        .unwrap_or_else(|e| e.into_inner());
+                }
+                IoMode::NonBlocking => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+
+        let mut read = 0;
+        for slice in bytes {
+            let n = slice.len().min(state.bytes.len());
+            for (dst, src) in slice.iter_mut().zip(state.bytes.drain(..n)) {
+                *dst = src;
+            }
+            read += n;
+        }
+        fds.extend(state.fds.drain(..));
+
+        if state.bytes.is_empty() {
+            let _ = self.notify.read(&mut [0]);
+        }
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{BufferedSocket, PeekHeaderError};
+    use crate::{ArgType, ArgValue, Message, MessageBuffersPool, MessageHeader, ObjectId};
+    use std::fs::File;
+
+    #[test]
+    fn bytes_and_fds() {
+        let (a, b) = pair().unwrap();
+        let mut a = BufferedSocket::from(a);
+        let mut b = BufferedSocket::from(b);
+        let mut pool = MessageBuffersPool::default();
+
+        let file = File::open("/dev/null").unwrap();
+        let msg = Message {
+            header: MessageHeader {
+                object_id: ObjectId::DISPLAY,
+                size: 0,
+                opcode: 3,
+            },
+            args: vec![ArgValue::Uint(42), ArgValue::Fd(file.into())],
+        };
+        a.write_message(msg, &mut pool, IoMode::NonBlocking)
+            .map_err(|e| e.err)
+            .unwrap();
+
+        assert!(matches!(
+            b.peek_message_header(IoMode::NonBlocking),
+            Err(PeekHeaderError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock
+        ));
+
+        a.flush(IoMode::NonBlocking).unwrap();
+        let header = b.peek_message_header(IoMode::NonBlocking).unwrap();
+        assert_eq!(header.object_id, ObjectId::DISPLAY);
+        assert_eq!(header.opcode, 3);
+        assert_eq!(header.size, 12);
+
+        let msg = b
+            .recv_message(
+                header,
+                &[ArgType::Uint, ArgType::Fd],
+                &mut pool,
+                IoMode::NonBlocking,
+            )
+            .unwrap();
+        assert!(matches!(msg.args[0], ArgValue::Uint(42)));
+        assert!(matches!(msg.args[1], ArgValue::Fd(_)));
+
+        drop(a);
+        assert!(matches!(
+            b.peek_message_header(IoMode::Blocking),
+            Err(PeekHeaderError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe
+        ));
+    }
+}
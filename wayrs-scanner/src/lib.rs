@@ -5,7 +5,7 @@
 use std::{ffi::CString, path::PathBuf};
 
 use proc_macro2::{Ident, Span, TokenStream};
-use quote::{format_ident, quote};
+use quote::{format_ident, quote, ToTokens};
 use wayrs_proto_parser::*;
 
 mod mini_syn;
@@ -18,10 +18,15 @@ const FROZEN_IFACES: &[&str] = &["wl_display", "wl_registry", "wl_callback", "wl
 #[derive(Debug)]
 struct MacroArgs {
     crate_root: Ident,
-    path: String,
+    source: Source,
+    /// The path to the module generated with `generate_interfaces!` for the same file, if the
+    /// interface descriptions should not be generated inline.
+    interfaces: Option<TokenStream>,
 }
 
 impl MacroArgs {
+    /// Parse `crate_root, <source>` or `crate_root, <source>, interfaces = some::module`, where
+    /// `<source>` is a path literal or `core_protocol`.
     fn parse(input: TokenStream) -> Option<Self> {
         let mut tokens = input.into_iter();
 
@@ -33,59 +38,192 @@ impl MacroArgs {
             return None;
         };
 
-        let Some(proc_macro2::TokenTree::Group(group)) = tokens.next() else {
-            return None;
+        // The literal is wrapped in an invisible group when passed through `macro_rules`
+        let path_lit = match tokens.next()? {
+            proc_macro2::TokenTree::Ident(ident) if ident == "core_protocol" => None,
+            proc_macro2::TokenTree::Literal(lit) => Some(lit),
+            proc_macro2::TokenTree::Group(group)
+                if group.delimiter() == proc_macro2::Delimiter::None =>
+            {
+                let mut group = group.stream().into_iter();
+                let Some(proc_macro2::TokenTree::Literal(lit)) = group.next() else {
+                    return None;
+                };
+                if group.next().is_some() {
+                    return None;
+                }
+                Some(lit)
+            }
+            _ => return None,
         };
 
-        if tokens.next().is_some() || group.delimiter() != proc_macro2::Delimiter::None {
-            return None;
+        let source = match path_lit {
+            Some(lit) => Source::Path(mini_syn::parse_lit_str_cooked(&lit.to_string())?),
+            None => Source::Core,
+        };
+
+        let interfaces = match tokens.next() {
+            None => None,
+            Some(proc_macro2::TokenTree::Punct(comma)) if comma.as_char() == ',' => {
+                match (tokens.next(), tokens.next()) {
+                    (
+                        Some(proc_macro2::TokenTree::Ident(key)),
+                        Some(proc_macro2::TokenTree::Punct(eq)),
+                    ) if key == "interfaces" && eq.as_char() == '=' => {
+                        let module: TokenStream = tokens.collect();
+                        if module.is_empty() {
+                            return None;
+                        }
+                        Some(module)
+                    }
+                    _ => return None,
+                }
+            }
+            Some(_) => return None,
+        };
+
+        Some(Self {
+            crate_root,
+            source,
+            interfaces,
+        })
+    }
+}
+
+/// Where the protocol is read from.
+#[derive(Debug)]
+enum Source {
+    /// A file, relative to the crate root.
+    Path(String),
+    /// The core Wayland protocol, bundled with this crate.
+    Core,
+}
+
+impl Source {
+    fn read(&self) -> String {
+        match self {
+            Self::Path(path) => {
+                let path = match std::env::var_os("CARGO_MANIFEST_DIR") {
+                    Some(manifest) => {
+                        let mut full = PathBuf::from(manifest);
+                        full.push(path);
+                        full
+                    }
+                    None => PathBuf::from(path),
+                };
+                std::fs::read_to_string(path).expect("could not read the file")
+            }
+            Self::Core => include_str!("../wayland.xml").to_owned(),
         }
+    }
+}
 
-        let mut group = group.stream().into_iter();
+/// Which side of the connection the code is generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    /// `wayrs-client`: proxies which send requests and receive events.
+    Client,
+    /// `wayrs-server`: resources which receive requests and send events.
+    Server,
+}
 
-        let Some(proc_macro2::TokenTree::Literal(path_lit)) = group.next() else {
-            return None;
-        };
+impl Side {
+    /// Messages received by this side.
+    fn incoming<'a, 'b>(self, iface: &'a Interface<'b>) -> &'a [Message<'b>] {
+        match self {
+            Self::Client => &iface.events,
+            Self::Server => &iface.requests,
+        }
+    }
 
-        if group.next().is_some() {
-            return None;
+    /// Messages sent by this side.
+    fn outgoing<'a, 'b>(self, iface: &'a Interface<'b>) -> &'a [Message<'b>] {
+        match self {
+            Self::Client => &iface.requests,
+            Self::Server => &iface.events,
         }
+    }
 
-        let path = mini_syn::parse_lit_str_cooked(&path_lit.to_string())?;
+    /// The name of the object trait, `Proxy` or `Resource`.
+    fn object_trait(self) -> Ident {
+        match self {
+            Self::Client => format_ident!("Proxy"),
+            Self::Server => format_ident!("Resource"),
+        }
+    }
 
-        Some(Self { crate_root, path })
+    /// The name of the incoming messages enum, `Event` or `Request`.
+    fn incoming_enum(self) -> Ident {
+        match self {
+            Self::Client => format_ident!("Event"),
+            Self::Server => format_ident!("Request"),
+        }
     }
 }
 
 #[doc(hidden)]
 #[proc_macro]
 pub fn generate(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    generate_for_side(input, Side::Client)
+}
+
+#[doc(hidden)]
+#[proc_macro]
+pub fn generate_server(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    generate_for_side(input, Side::Server)
+}
+
+/// Generate only the interface descriptions, which can be shared by the code generated for both
+/// sides with `interfaces = <module>` argument. The first argument is the path to `wayrs_core`.
+#[doc(hidden)]
+#[proc_macro]
+pub fn generate_interfaces(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let Some(args) = MacroArgs::parse(input.into()) else {
         return quote! { compile_error!("invalid macro arguments") }.into();
     };
+    if args.interfaces.is_some() {
+        return quote! { compile_error!("invalid macro arguments") }.into();
+    }
+
+    let file = args.source.read();
+    let protocol = match parse_protocol_file(&file) {
+        Ok(protocol) => protocol,
+        Err(err) => return err.into(),
+    };
 
-    let path = match std::env::var_os("CARGO_MANIFEST_DIR") {
-        Some(manifest) => {
-            let mut full = PathBuf::from(manifest);
-            full.push(&args.path);
-            full
+    let core_path = args.crate_root.to_token_stream();
+    let modules = protocol.interfaces.iter().map(|iface| {
+        let mod_doc = gen_doc(iface.description.as_ref(), None, None);
+        let mod_name = Ident::new(&iface.name, Span::call_site());
+        let desc = gen_interface_desc(iface, &core_path, static_interface_ref);
+        quote! {
+            #mod_doc
+            pub mod #mod_name {
+                #![allow(clippy::empty_docs)]
+
+                pub static INTERFACE: #core_path::Interface = #desc;
+            }
         }
-        None => PathBuf::from(&args.path),
+    });
+
+    quote! { #(#modules)* }.into()
+}
+
+fn generate_for_side(input: proc_macro::TokenStream, side: Side) -> proc_macro::TokenStream {
+    let Some(args) = MacroArgs::parse(input.into()) else {
+        return quote! { compile_error!("invalid macro arguments") }.into();
     };
 
-    let file = std::fs::read_to_string(path).expect("could not read the file");
-    let protocol = match parse_protocol(&file) {
+    let file = args.source.read();
+    let protocol = match parse_protocol_file(&file) {
         Ok(protocol) => protocol,
-        Err(err) => {
-            let err = format!("error parsing the protocol file: {err}");
-            return quote!(compile_error!(#err);).into();
-        }
+        Err(err) => return err.into(),
     };
 
     let modules = protocol
         .interfaces
         .iter()
-        .map(|i| gen_interface(i, &args.crate_root));
+        .map(|i| gen_interface(i, &args.crate_root, args.interfaces.as_ref(), side));
 
     let x = quote! { #(#modules)* };
     // {
@@ -95,6 +233,14 @@ pub fn generate(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     x.into()
 }
 
+/// Parse a protocol file. Errors are returned as `compile_error!` invocations.
+fn parse_protocol_file(file: &str) -> Result<Protocol<'_>, TokenStream> {
+    parse_protocol(file).map_err(|err| {
+        let err = format!("error parsing the protocol file: {err}");
+        quote!(compile_error!(#err);)
+    })
+}
+
 fn make_ident(name: impl AsRef<str>) -> Ident {
     Ident::new_raw(name.as_ref(), Span::call_site())
 }
@@ -113,137 +259,322 @@ fn make_proxy_path(iface: impl AsRef<str>) -> TokenStream {
     quote! { super::#proxy_name }
 }
 
-fn gen_interface(iface: &Interface, wayrs_client_path: &Ident) -> TokenStream {
-    let mod_doc = gen_doc(iface.description.as_ref(), None, None);
-    let mod_name = Ident::new(&iface.name, Span::call_site());
-
-    let proxy_name = make_pascal_case_ident(&iface.name);
-    let proxy_name_str = snake_to_pascal(&iface.name);
-
-    let raw_iface_name = &iface.name;
+/// Generate an `Interface` expression. `core_path` is the path to `wayrs_core`, `interface_ref`
+/// maps the name of another interface in the same file to a `&'static Interface` expression.
+fn gen_interface_desc(
+    iface: &Interface,
+    core_path: &TokenStream,
+    interface_ref: fn(&str) -> TokenStream,
+) -> TokenStream {
     let raw_iface_name_cstr =
         CString::new(iface.name.as_bytes()).expect("null byte in interface name");
     let iface_version = iface.version;
 
     let gen_msg_gesc = |msg: &Message| {
-        let args = msg.args.iter().map(map_arg_to_argtype);
+        let args = msg
+            .args
+            .iter()
+            .map(|arg| map_arg_to_argtype(arg, interface_ref));
         let name = &msg.name;
         let is_destructor = msg.kind.as_deref() == Some("destructor");
+        let since = msg.since;
         quote! {
-            #wayrs_client_path::core::MessageDesc {
+            #core_path::MessageDesc {
                 name: #name,
                 is_destructor: #is_destructor,
-                signature: &[ #( #wayrs_client_path::core::ArgType::#args, )* ]
+                since: #since,
+                signature: &[ #( #core_path::ArgType::#args, )* ]
             }
         }
     };
     let events_desc = iface.events.iter().map(gen_msg_gesc);
     let requests_desc = iface.requests.iter().map(gen_msg_gesc);
-
-    let event_args_structs = iface
-        .events
+    let errors_desc = iface
+        .enums
         .iter()
-        .filter(|event| event.args.len() > 1)
-        .map(|event| {
-            let struct_name = format_ident!("{}Args", make_pascal_case_ident(&event.name));
-            let arg_name = event.args.iter().map(|arg| make_ident(&arg.name));
-            let arg_ty = event
-                .args
-                .iter()
-                .map(|arg| arg.as_event_ty(wayrs_client_path));
-            let summary = event
-                .args
-                .iter()
-                .map(|arg| arg.summary.as_ref().map(|s| quote!(#[doc = #s])));
-            let clone_derive = event
-                .args
-                .iter()
-                .all(|arg| arg.is_clone())
-                .then(|| quote!(, Clone));
-            let copy_derive = event
-                .args
-                .iter()
-                .all(|arg| arg.is_copy())
-                .then(|| quote!(, Copy));
+        .filter(|e| e.name == "error")
+        .flat_map(|e| &e.items)
+        .map(|item| {
+            let name = &item.name;
+            let value = item.value;
             quote! {
-                #[derive(Debug #clone_derive #copy_derive)]
-                pub struct #struct_name { #( #summary pub #arg_name: #arg_ty, )* }
+                #core_path::ErrorDesc {
+                    name: #name,
+                    value: #value,
+                }
             }
         });
 
-    let event_enum_options = iface.events.iter().map(|event| {
-        let event_name = make_pascal_case_ident(&event.name);
+    quote! {
+        #core_path::Interface {
+            name: #raw_iface_name_cstr,
+            version: #iface_version,
+            events: &[ #(#events_desc,)* ],
+            requests: &[ #(#requests_desc,)* ],
+            errors: &[ #(#errors_desc,)* ],
+        }
+    }
+}
+
+fn gen_interface(
+    iface: &Interface,
+    wayrs_client_path: &Ident,
+    interfaces: Option<&TokenStream>,
+    side: Side,
+) -> TokenStream {
+    let mod_doc = gen_doc(iface.description.as_ref(), None, None);
+    let mod_name = Ident::new(&iface.name, Span::call_site());
+
+    let proxy_name = make_pascal_case_ident(&iface.name);
+    let proxy_name_str = snake_to_pascal(&iface.name);
+
+    let object_trait = side.object_trait();
+    let incoming_enum = side.incoming_enum();
+    let incoming = side.incoming(iface);
+    let outgoing = side.outgoing(iface);
+
+    let raw_iface_name = &iface.name;
+
+    let incoming_args_structs = incoming.iter().filter(|msg| msg.args.len() > 1).map(|msg| {
+        let struct_name = format_ident!("{}Args", make_pascal_case_ident(&msg.name));
+        let arg_name = msg.args.iter().map(|arg| make_ident(&arg.name));
+        let arg_ty = msg
+            .args
+            .iter()
+            .map(|arg| arg.as_incoming_ty(wayrs_client_path, side));
+        let summary = msg
+            .args
+            .iter()
+            .map(|arg| arg.summary.as_ref().map(|s| quote!(#[doc = #s])));
+        let clone_derive = msg
+            .args
+            .iter()
+            .all(|arg| arg.is_clone())
+            .then(|| quote!(, Clone));
+        let copy_derive = msg
+            .args
+            .iter()
+            .all(|arg| arg.is_copy(side))
+            .then(|| quote!(, Copy));
+        quote! {
+            #[derive(Debug #clone_derive #copy_derive)]
+            pub struct #struct_name { #( #summary pub #arg_name: #arg_ty, )* }
+        }
+    });
+
+    let incoming_enum_options = incoming.iter().map(|msg| {
+        let msg_name = make_pascal_case_ident(&msg.name);
         let doc = gen_doc(
-            event.description.as_ref(),
-            Some(event.since),
-            event.deprecated_since,
+            msg.description.as_ref(),
+            Some(msg.since),
+            msg.deprecated_since,
         );
-        match event.args.as_slice() {
-            [] => quote! { #doc #event_name },
+        match msg.args.as_slice() {
+            [] => quote! { #doc #msg_name },
             [_, _, ..] => {
-                let struct_name = format_ident!("{event_name}Args");
-                quote! { #doc #event_name(#struct_name) }
+                let struct_name = format_ident!("{msg_name}Args");
+                quote! { #doc #msg_name(#struct_name) }
             }
             [arg] => {
-                let event_ty = arg.as_event_ty(wayrs_client_path);
+                let arg_ty = arg.as_incoming_ty(wayrs_client_path, side);
                 let arg_name = &arg.name;
                 let name_doc = quote!(#[doc = #arg_name]);
                 let summary = arg
                     .summary
                     .as_ref()
                     .map(|s| quote!(#[doc = "\n"] #[doc = #s]));
-                quote! { #doc #event_name(#name_doc #summary #event_ty) }
+                quote! { #doc #msg_name(#name_doc #summary #arg_ty) }
             }
         }
     });
 
-    let event_decoding = iface.events.iter().enumerate().map(|(opcode, event)| {
-        let event_name = make_pascal_case_ident(&event.name);
+    let decode_arg = |arg: &Argument| {
+        let arg_name = make_ident(&arg.name);
+        match &arg.arg_type {
+            ArgType::NewId { iface: Some(_) } => match side {
+                Side::Client => quote! { Proxy::new(#arg_name, __self_version) },
+                Side::Server => quote! { Resource::new(__client, #arg_name, __self_version) },
+            },
+            ArgType::NewId { iface: None } => {
+                let [interface, version, id] = any_new_id_idents(&arg_name);
+                match side {
+                    Side::Client => quote! {
+                        #wayrs_client_path::object::Object {
+                            id: #id,
+                            interface: match #wayrs_client_path::object::lookup_interface(&#interface) {
+                                Some(interface) => interface,
+                                None => return Err(#wayrs_client_path::object::BadMessage),
+                            },
+                            version: #version,
+                        }
+                    },
+                    Side::Server => quote! {
+                        #wayrs_client_path::object::NewId {
+                            interface: #interface.into_owned(),
+                            version: #version,
+                            id: #id,
+                        }
+                    },
+                }
+            }
+            ArgType::Enum(_) => quote! {
+                match #arg_name.try_into() {
+                    Ok(val) => val,
+                    Err(_) => return Err(#wayrs_client_path::object::BadMessage),
+                }
+            },
+            _ => quote!(#arg_name),
+        }
+    };
+    let arg_pattern = |arg: &Argument| {
+        let arg_name = make_ident(&arg.name);
+        match &arg.arg_type {
+            ArgType::NewId { iface: None } => {
+                let [interface, version, id] = any_new_id_idents(&arg_name);
+                quote!(#interface, #version, #id)
+            }
+            _ => quote!(#arg_name),
+        }
+    };
+
+    let incoming_decoding = incoming.iter().enumerate().map(|(opcode, msg)| {
+        let msg_name = make_pascal_case_ident(&msg.name);
         let opcode = opcode as u16;
-        let arg_ty_rev = event.args.iter().rev().map(|x| map_arg_to_argval(x, true));
-        let arg_names = event.args.iter().map(|arg| make_ident(&arg.name));
-        let arg_names_rev = event.args.iter().rev().map(|arg| make_ident(&arg.name));
-        let arg_decode = event.args.iter().map(|arg| {
-            let arg_name = make_ident(&arg.name);
-            match &arg.arg_type {
-                ArgType::NewId{ iface: Some(_) } => {
-                    quote! { Proxy::new(#arg_name, __self_version) }
-                },
-                ArgType::Enum(_) => quote! {
-                    match #arg_name.try_into() {
-                        Ok(val) => val,
-                        Err(_) => return Err(#wayrs_client_path::object::BadMessage),
-                    }
-                },
-                _ => quote!(#arg_name),
+        let arg_ty_rev = msg.args.iter().rev().map(map_arg_to_argval);
+        let arg_names = msg.args.iter().map(|arg| make_ident(&arg.name));
+        let arg_decode = msg.args.iter().map(decode_arg);
+        let arg_pattern_rev = msg.args.iter().rev().map(arg_pattern);
+        let args_len = msg.args.len();
+        let retval = match args_len {
+            0 => quote!(#incoming_enum::#msg_name),
+            1 => quote!(#incoming_enum::#msg_name(#( #arg_decode )*)),
+            _ => {
+                let struct_name = format_ident!("{msg_name}Args");
+                quote!(#incoming_enum::#msg_name(#struct_name { #( #arg_names: #arg_decode, )* }))
+            }
+        };
+        quote! {
+            #opcode => {
+                if __msg.args.len() != #args_len {
+                    return Err(#wayrs_client_path::object::BadMessage);
+                }
+                #( let Some(#wayrs_client_path::core::ArgValue::#arg_ty_rev(#arg_pattern_rev)) = __msg.args.pop() else { return Err(#wayrs_client_path::object::BadMessage) }; )*
+                __pool.reuse_args(__msg.args);
+                Ok(#retval)
+            }
+        }
+    });
+
+    let incoming_exhaustiveness =
+        (!FROZEN_IFACES.contains(&iface.name.as_str())).then(|| quote! { #[non_exhaustive] });
+
+    // Events which contain strings or arrays get a separate variant in `BorrowedEvent`. If there
+    // are no such events, `BorrowedEvent` is an alias of `Event`.
+    let has_borrowed_args = |msg: &&Message| msg.args.iter().any(|arg| arg.is_borrowed());
+    let borrowed_event = if side == Side::Server {
+        quote!()
+    } else if !incoming.iter().any(|msg| has_borrowed_args(&msg)) {
+        quote! {
+            #[doc = "Same as [`Event`], since none of the events contain strings or arrays."]
+            pub type BorrowedEvent<'a> = Event;
+        }
+    } else {
+        let structs = incoming
+            .iter()
+            .filter(|msg| msg.args.len() > 1)
+            .filter(has_borrowed_args)
+            .map(|msg| {
+                let struct_name =
+                    format_ident!("{}BorrowedArgs", make_pascal_case_ident(&msg.name));
+                let owned_name = format!("[`{}Args`]", make_pascal_case_ident(&msg.name));
+                let arg_name = msg.args.iter().map(|arg| make_ident(&arg.name));
+                let arg_ty = msg
+                    .args
+                    .iter()
+                    .map(|arg| arg.as_borrowed_ty(wayrs_client_path));
+                let summary = msg
+                    .args
+                    .iter()
+                    .map(|arg| arg.summary.as_ref().map(|s| quote!(#[doc = #s])));
+                quote! {
+                    #[doc = "Same as "]
+                    #[doc = #owned_name]
+                    #[doc = ", but borrows strings and arrays."]
+                    #[derive(Debug)]
+                    pub struct #struct_name<'a> { #( #summary pub #arg_name: #arg_ty, )* }
+                }
+            });
+        let options = incoming.iter().map(|msg| {
+            let msg_name = make_pascal_case_ident(&msg.name);
+            let borrowed = has_borrowed_args(&msg);
+            match msg.args.as_slice() {
+                [] => quote! { #msg_name },
+                [_, _, ..] if borrowed => {
+                    let struct_name = format_ident!("{msg_name}BorrowedArgs");
+                    quote! { #msg_name(#struct_name<'a>) }
+                }
+                [_, _, ..] => {
+                    let struct_name = format_ident!("{msg_name}Args");
+                    quote! { #msg_name(#struct_name) }
+                }
+                [arg] => {
+                    let arg_ty = arg.as_borrowed_ty(wayrs_client_path);
+                    quote! { #msg_name(#arg_ty) }
+                }
             }
         });
-        let args_len = event.args.len();
+        let see_also = format!(
+            "See [`Connection::set_borrowed_callback_for`]({wayrs_client_path}::Connection::set_borrowed_callback_for)."
+        );
+        quote! {
+            #( #structs )*
+
+            #[doc = "Same as [`Event`], but strings and arrays borrow the buffer of the connection."]
+            #[doc = "\n"]
+            #[doc = #see_also]
+            #[derive(Debug)]
+            #incoming_exhaustiveness
+            pub enum BorrowedEvent<'a> {
+                #( #options, )*
+            }
+        }
+    };
+
+    let borrowed_decoding = incoming.iter().enumerate().map(|(opcode, msg)| {
+        let msg_name = make_pascal_case_ident(&msg.name);
+        let opcode = opcode as u16;
+        let arg_ty = msg.args.iter().map(map_arg_to_argval);
+        let arg_names = msg.args.iter().map(|arg| make_ident(&arg.name));
+        let arg_decode = msg.args.iter().map(decode_arg);
+        let arg_pattern = msg.args.iter().map(arg_pattern);
+        let args_len = msg.args.len();
         let retval = match args_len {
-            0 => quote!(Event::#event_name),
-            1 => quote!(Event::#event_name(#( #arg_decode )*)),
+            0 => quote!(BorrowedEvent::#msg_name),
+            1 => quote!(BorrowedEvent::#msg_name(#( #arg_decode )*)),
             _ => {
-                let struct_name = format_ident!("{event_name}Args");
-                quote!(Event::#event_name(#struct_name { #( #arg_names: #arg_decode, )* }))
+                let struct_name = if has_borrowed_args(&msg) {
+                    format_ident!("{msg_name}BorrowedArgs")
+                } else {
+                    format_ident!("{msg_name}Args")
+                };
+                quote!(BorrowedEvent::#msg_name(#struct_name { #( #arg_names: #arg_decode, )* }))
             }
         };
         quote! {
             #opcode => {
-                if __event.args.len() != #args_len {
+                if __args.len() != #args_len {
                     return Err(#wayrs_client_path::object::BadMessage);
                 }
-                #( let Some(#wayrs_client_path::core::ArgValue::#arg_ty_rev(#arg_names_rev)) = __event.args.pop() else { return Err(#wayrs_client_path::object::BadMessage) }; )*
-                __pool.reuse_args(__event.args);
+                #( let Some(#wayrs_client_path::core::BorrowedArgValue::#arg_ty(#arg_pattern)) = __args.next() else { return Err(#wayrs_client_path::object::BadMessage) }; )*
                 Ok(#retval)
             }
         }
     });
 
-    let requests = iface
-        .requests
+    let outgoing_fns = outgoing
         .iter()
         .enumerate()
-        .map(|(opcode, request)| gen_request_fn(opcode as u16, request, wayrs_client_path));
+        .map(|(opcode, msg)| gen_outgoing_fn(opcode as u16, msg, wayrs_client_path, side));
 
     let enums = iface.enums.iter().map(|en| {
         let name = make_pascal_case_ident(&en.name);
@@ -331,7 +662,7 @@ fn gen_interface(iface: &Interface, wayrs_client_path: &Ident) -> TokenStream {
         quote!(pub)
     };
 
-    let extra_impl = if iface.name == "wl_display" {
+    let extra_impl = if iface.name == "wl_display" && side == Side::Client {
         quote! {
             impl WlDisplay {
                 pub const INSTANCE: Self = Self {
@@ -344,17 +675,20 @@ fn gen_interface(iface: &Interface, wayrs_client_path: &Ident) -> TokenStream {
         quote!()
     };
 
-    let event_exhaustiveness =
-        (!FROZEN_IFACES.contains(&iface.name.as_str())).then(|| quote! { #[non_exhaustive] });
-
-    quote! {
-        #mod_doc
-        #visibility mod #mod_name {
-            #![allow(clippy::empty_docs)]
-
-            use #wayrs_client_path::object::Proxy;
+    let interface_desc = match interfaces {
+        Some(interfaces) => quote! { &#interfaces::#mod_name::INTERFACE },
+        None => {
+            let desc = gen_interface_desc(
+                iface,
+                &quote!(#wayrs_client_path::core),
+                object_interface_ref,
+            );
+            quote! { &#desc }
+        }
+    };
 
-            #mod_doc
+    let side_specific = match side {
+        Side::Client => quote! {
             #[doc = "See [`Event`] for the list of possible events."]
             #[derive(Clone, Copy)]
             pub struct #proxy_name {
@@ -366,26 +700,32 @@ fn gen_interface(iface: &Interface, wayrs_client_path: &Ident) -> TokenStream {
 
             impl Proxy for #proxy_name {
                 type Event = Event;
+                type BorrowedEvent<'a> = BorrowedEvent<'a>;
 
-                const INTERFACE: &'static #wayrs_client_path::core::Interface
-                    = &#wayrs_client_path::core::Interface {
-                        name: #raw_iface_name_cstr,
-                        version: #iface_version,
-                        events: &[ #(#events_desc,)* ],
-                        requests: &[ #(#requests_desc,)* ],
-                    };
+                const INTERFACE: &'static #wayrs_client_path::core::Interface = #interface_desc;
 
                 fn new(id: #wayrs_client_path::core::ObjectId, version: u32) -> Self {
                     Self { id, version }
                 }
 
                 fn parse_event(
-                    mut __event: #wayrs_client_path::core::Message,
+                    mut __msg: #wayrs_client_path::core::Message,
                     __self_version: u32,
                     __pool: &mut #wayrs_client_path::core::MessageBuffersPool,
                 ) -> ::std::result::Result<Event, #wayrs_client_path::object::BadMessage> {
-                    match __event.header.opcode {
-                        #( #event_decoding )*
+                    match __msg.header.opcode {
+                        #( #incoming_decoding )*
+                        _ => Err(#wayrs_client_path::object::BadMessage),
+                    }
+                }
+
+                fn parse_event_borrowed<'a>(
+                    __opcode: u16,
+                    mut __args: impl ExactSizeIterator<Item = #wayrs_client_path::core::BorrowedArgValue<'a>>,
+                    __self_version: u32,
+                ) -> ::std::result::Result<BorrowedEvent<'a>, #wayrs_client_path::object::BadMessage> {
+                    match __opcode {
+                        #( #borrowed_decoding )*
                         _ => Err(#wayrs_client_path::object::BadMessage),
                     }
                 }
@@ -414,18 +754,6 @@ fn gen_interface(iface: &Interface, wayrs_client_path: &Ident) -> TokenStream {
                 }
             }
 
-            impl ::std::fmt::Debug for #proxy_name {
-                fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
-                    write!(
-                        f,
-                        "{}@{}v{}",
-                        #raw_iface_name,
-                        self.id.as_u32(),
-                        self.version
-                    )
-                }
-            }
-
             impl ::std::cmp::PartialEq for #proxy_name {
                 #[inline]
                 fn eq(&self, other: &Self) -> bool {
@@ -433,8 +761,6 @@ fn gen_interface(iface: &Interface, wayrs_client_path: &Ident) -> TokenStream {
                 }
             }
 
-            impl ::std::cmp::Eq for #proxy_name {}
-
             impl ::std::cmp::PartialEq<#wayrs_client_path::core::ObjectId> for #proxy_name {
                 #[inline]
                 fn eq(&self, other: &#wayrs_client_path::core::ObjectId) -> bool {
@@ -449,13 +775,6 @@ fn gen_interface(iface: &Interface, wayrs_client_path: &Ident) -> TokenStream {
                 }
             }
 
-            impl ::std::cmp::PartialOrd for #proxy_name {
-                #[inline]
-                fn partial_cmp(&self, other: &Self) -> ::std::option::Option<::std::cmp::Ordering> {
-                    ::std::option::Option::Some(::std::cmp::Ord::cmp(self, other))
-                }
-            }
-
             impl ::std::cmp::Ord for #proxy_name {
                 #[inline]
                 fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
@@ -478,21 +797,148 @@ fn gen_interface(iface: &Interface, wayrs_client_path: &Ident) -> TokenStream {
                     &self.id
                 }
             }
+        },
+        Side::Server => quote! {
+            #[doc = "See [`Request`] for the list of possible requests."]
+            #[derive(Clone, Copy)]
+            pub struct #proxy_name {
+                client: #wayrs_client_path::ClientId,
+                id: #wayrs_client_path::core::ObjectId,
+                version: u32,
+            }
+
+            impl Resource for #proxy_name {
+                type Request = Request;
+
+                const INTERFACE: &'static #wayrs_client_path::core::Interface = #interface_desc;
+
+                fn new(
+                    client: #wayrs_client_path::ClientId,
+                    id: #wayrs_client_path::core::ObjectId,
+                    version: u32,
+                ) -> Self {
+                    Self { client, id, version }
+                }
+
+                fn parse_request(
+                    mut __msg: #wayrs_client_path::core::Message,
+                    __client: #wayrs_client_path::ClientId,
+                    __self_version: u32,
+                    __pool: &mut #wayrs_client_path::core::MessageBuffersPool,
+                ) -> ::std::result::Result<Request, #wayrs_client_path::object::BadMessage> {
+                    match __msg.header.opcode {
+                        #( #incoming_decoding )*
+                        _ => Err(#wayrs_client_path::object::BadMessage),
+                    }
+                }
+
+                fn client(&self) -> #wayrs_client_path::ClientId {
+                    self.client
+                }
+
+                fn id(&self) -> #wayrs_client_path::core::ObjectId {
+                    self.id
+                }
+
+                fn version(&self) -> u32 {
+                    self.version
+                }
+            }
+
+            impl TryFrom<#wayrs_client_path::object::Object> for #proxy_name {
+                type Error = #wayrs_client_path::object::WrongObject;
+
+                fn try_from(object: #wayrs_client_path::object::Object) -> ::std::result::Result<Self, #wayrs_client_path::object::WrongObject> {
+                    if object.interface == Self::INTERFACE {
+                        Ok(Self {
+                            client: object.client,
+                            id: object.id,
+                            version: object.version,
+                        })
+                    } else {
+                        Err(#wayrs_client_path::object::WrongObject)
+                    }
+                }
+            }
+
+            impl ::std::cmp::PartialEq for #proxy_name {
+                #[inline]
+                fn eq(&self, other: &Self) -> bool {
+                    self.client == other.client && self.id == other.id
+                }
+            }
+
+            impl ::std::cmp::Ord for #proxy_name {
+                #[inline]
+                fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
+                    (self.client, self.id).cmp(&(other.client, other.id))
+                }
+            }
+
+            impl ::std::hash::Hash for #proxy_name {
+                #[inline]
+                fn hash<H>(&self, state: &mut H)
+                    where H: ::std::hash::Hasher
+                {
+                    self.client.hash(state);
+                    self.id.hash(state);
+                }
+            }
+        },
+    };
+
+    let incoming_enum_doc = match side {
+        Side::Client => "The event enum for [`",
+        Side::Server => "The request enum for [`",
+    };
 
-            #( #event_args_structs )*
+    quote! {
+        #mod_doc
+        #visibility mod #mod_name {
+            #![allow(clippy::empty_docs)]
+
+            use #wayrs_client_path::object::#object_trait;
+
+            #mod_doc
+            #side_specific
+
+            impl ::std::fmt::Debug for #proxy_name {
+                fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                    write!(
+                        f,
+                        "{}@{}v{}",
+                        #raw_iface_name,
+                        self.id.as_u32(),
+                        self.version
+                    )
+                }
+            }
+
+            impl ::std::cmp::Eq for #proxy_name {}
+
+            impl ::std::cmp::PartialOrd for #proxy_name {
+                #[inline]
+                fn partial_cmp(&self, other: &Self) -> ::std::option::Option<::std::cmp::Ordering> {
+                    ::std::option::Option::Some(::std::cmp::Ord::cmp(self, other))
+                }
+            }
+
+            #( #incoming_args_structs )*
             #( #enums )*
 
-            #[doc = "The event enum for [`"]
+            #[doc = #incoming_enum_doc]
             #[doc = #proxy_name_str]
             #[doc = "`]"]
             #[derive(Debug)]
-            #event_exhaustiveness
-            pub enum Event {
-                #( #event_enum_options, )*
+            #incoming_exhaustiveness
+            pub enum #incoming_enum {
+                #( #incoming_enum_options, )*
             }
 
+            #borrowed_event
+
             impl #proxy_name {
-                #( #requests )*
+                #( #outgoing_fns )*
             }
         }
 
@@ -500,6 +946,15 @@ fn gen_interface(iface: &Interface, wayrs_client_path: &Ident) -> TokenStream {
     }
 }
 
+/// Names of the bindings for the three components of an `AnyNewId` argument.
+fn any_new_id_idents(arg_name: &Ident) -> [Ident; 3] {
+    [
+        format_ident!("{arg_name}_interface"),
+        format_ident!("{arg_name}_version"),
+        format_ident!("{arg_name}_id"),
+    ]
+}
+
 fn gen_pub_fn(
     attrs: &TokenStream,
     name: &str,
@@ -519,118 +974,267 @@ fn gen_pub_fn(
     }
 }
 
-fn gen_request_fn(opcode: u16, request: &Message, wayrs_client_path: &Ident) -> TokenStream {
+/// Generate a function which sends a message: a request on the client side or an event on the
+/// server side.
+fn gen_outgoing_fn(
+    opcode: u16,
+    msg: &Message,
+    wayrs_client_path: &Ident,
+    side: Side,
+) -> TokenStream {
     assert!(
-        request
-            .args
+        msg.args
             .iter()
             .filter(|x| matches!(x.arg_type, ArgType::NewId { .. }))
             .count()
             <= 1,
         "{} has more than one new_id argument",
-        request.name,
+        msg.name,
     );
 
-    let new_id_interface = request.args.iter().find_map(|x| match &x.arg_type {
+    let new_id_interface = msg.args.iter().find_map(|x| match &x.arg_type {
         ArgType::NewId { iface } => Some(iface.as_deref()),
         _ => None,
     });
 
-    let mut fn_args = vec![
-        quote!(self),
-        quote!(conn: &mut #wayrs_client_path::Connection<D>),
-    ];
+    let object_trait = side.object_trait();
+    let (conn, conn_ty, fn_name, generic, ctx_ty) = match side {
+        Side::Client => (
+            format_ident!("conn"),
+            quote!(impl #wayrs_client_path::RequestSink<D>),
+            msg.name.clone(),
+            format_ident!("P"),
+            format_ident!("EventCtx"),
+        ),
+        Side::Server => (
+            format_ident!("server"),
+            quote!(#wayrs_client_path::Server<D>),
+            format!("send_{}", msg.name),
+            format_ident!("R"),
+            format_ident!("RequestCtx"),
+        ),
+    };
+
+    let mut fn_args = vec![quote!(self), quote!(#conn: &mut #conn_ty)];
     fn_args.extend(
-        request
-            .args
+        msg.args
             .iter()
-            .flat_map(|arg| arg.as_request_fn_arg(wayrs_client_path)),
+            .flat_map(|arg| arg.as_outgoing_fn_arg(wayrs_client_path)),
     );
 
-    let msg_args = request.args.iter().map(|arg| {
+    let msg_args = msg.args.iter().map(|arg| {
         let arg_name = make_ident(&arg.name);
-        let arg_ty = map_arg_to_argval(arg, false);
-        match arg.arg_type {
+        let arg_ty = map_arg_to_argval(arg);
+        match &arg.arg_type {
             ArgType::NewId { iface: Some(_) } => {
-                quote! { #wayrs_client_path::core::ArgValue::#arg_ty(Proxy::id(&new_object)) }
+                quote! { #wayrs_client_path::core::ArgValue::#arg_ty(#object_trait::id(&new_object)) }
             }
             ArgType::NewId { iface: None } => {
                 quote! { #wayrs_client_path::core::ArgValue::#arg_ty(
-                    ::std::borrow::Cow::Borrowed(P::INTERFACE.name),
-                    Proxy::version(&new_object),
-                    Proxy::id(&new_object),
+                    ::std::borrow::Cow::Borrowed(#generic::INTERFACE.name),
+                    #object_trait::version(&new_object),
+                    #object_trait::id(&new_object),
                 ) }
             }
+            ArgType::Object {
+                allow_null,
+                iface: None,
+            } => {
+                if *allow_null {
+                    quote! { #wayrs_client_path::core::ArgValue::#arg_ty(#arg_name.map(|x| x.id)) }
+                } else {
+                    quote! { #wayrs_client_path::core::ArgValue::#arg_ty(#arg_name.id) }
+                }
+            }
             ArgType::Object { allow_null, .. } => {
-                if allow_null {
-                    quote! { #wayrs_client_path::core::ArgValue::#arg_ty(#arg_name.as_ref().map(Proxy::id)) }
+                if *allow_null {
+                    quote! { #wayrs_client_path::core::ArgValue::#arg_ty(#arg_name.as_ref().map(#object_trait::id)) }
                 } else {
-                    quote! { #wayrs_client_path::core::ArgValue::#arg_ty(Proxy::id(&#arg_name)) }
+                    quote! { #wayrs_client_path::core::ArgValue::#arg_ty(#object_trait::id(&#arg_name)) }
                 }
             }
             _ => quote! { #wayrs_client_path::core::ArgValue::#arg_ty(#arg_name.into()) },
         }
     });
 
-    let send_message = quote! {
-        let mut _args_vec = conn.alloc_msg_args();
-        #( _args_vec.push(#msg_args); )*
-        conn.send_request(
-            Self::INTERFACE,
-            #wayrs_client_path::core::Message {
+    let msg_args: Vec<_> = msg_args.collect();
+    // The fallible variant propagates the error with `?`
+    let send_message = |fallible: bool| {
+        let send_fn = match (side, fallible) {
+            (Side::Client, false) => quote!(#conn.send_request(Self::INTERFACE, __message)),
+            (Side::Client, true) => quote!(#conn.try_send_request(Self::INTERFACE, __message)?),
+            (Side::Server, _) => {
+                quote!(#conn.send_event(self.client, Self::INTERFACE, __message))
+            }
+        };
+        quote! {
+            let mut _args_vec = #conn.alloc_msg_args();
+            #( _args_vec.push(#msg_args); )*
+            let __message = #wayrs_client_path::core::Message {
                 header: #wayrs_client_path::core::MessageHeader {
                     object_id: self.id,
                     size: 0,
                     opcode: #opcode,
                 },
                 args: _args_vec,
-            }
-        );
+            };
+            #send_fn;
+        }
+    };
+
+    let allocate = |ty: TokenStream, version: TokenStream| match side {
+        Side::Client => quote!(#conn.allocate_new_object::<#ty>(#version)),
+        Side::Server => quote!(#conn.allocate_new_object::<#ty>(self.client, #version)),
+    };
+    let allocate_with_cb = |version: TokenStream| match side {
+        Side::Client => quote!(#conn.allocate_new_object_with_cb(#version, cb)),
+        Side::Server => quote!(#conn.allocate_new_object_with_cb(self.client, #version, cb)),
     };
 
     let doc = gen_doc(
-        request.description.as_ref(),
-        Some(request.since),
-        request.deprecated_since,
+        msg.description.as_ref(),
+        Some(msg.since),
+        msg.deprecated_since,
     );
 
-    match new_id_interface {
-        None => gen_pub_fn(
+    // On the client side, each request also has a `try_` variant, which returns an error if the
+    // object is dead or its version is too low. The default variant panics instead. On the server
+    // side, sending an event which is too new for the resource always panics.
+    //
+    // `new_object` is the type of the object created by the request, which is returned by the
+    // generated functions. Requests without a `new_id` argument return `()`.
+    let gen_fn = |name: &str,
+                  generics: &[TokenStream],
+                  args: &[TokenStream],
+                  new_object: Option<TokenStream>,
+                  body: &dyn Fn(bool) -> TokenStream| {
+        let ret_ty = new_object.clone().unwrap_or_else(|| quote!(()));
+        let since = msg.since;
+        let msg_name = &msg.name;
+
+        if side == Side::Server {
+            let check = (since > 1).then(|| {
+                quote! {
+                    if self.version < #since {
+                        ::std::panic!(
+                            "event {}.{} requires version {}, but {:?} has version {}",
+                            Self::INTERFACE.name.to_string_lossy(),
+                            #msg_name,
+                            #since,
+                            #wayrs_client_path::object::Object::from(self),
+                            self.version,
+                        );
+                    }
+                }
+            });
+            let body = body(false);
+            return gen_pub_fn(
+                &doc,
+                name,
+                generics,
+                args,
+                ret_ty,
+                None,
+                quote! { #check #body },
+            );
+        }
+
+        let error = quote! {
+            #wayrs_client_path::VersionTooLow {
+                object: ::std::convert::From::from(self),
+                request: #msg_name,
+                since: #since,
+            }
+        };
+        let (check, try_check) = if since > 1 {
+            (
+                quote! { if self.version < #since { ::std::panic!("{}", #error); } },
+                quote! {
+                    if self.version < #since {
+                        return ::std::result::Result::Err(
+                            #wayrs_client_path::RequestError::VersionTooLow(#error),
+                        );
+                    }
+                },
+            )
+        } else {
+            (quote!(), quote!())
+        };
+        let (body, try_body) = (body(false), body(true));
+        let try_body = match new_object {
+            None => quote! { #try_check #try_body ::std::result::Result::Ok(()) },
+            Some(_) => quote! { #try_check ::std::result::Result::Ok({ #try_body }) },
+        };
+
+        let default_fn = gen_pub_fn(
             &doc,
-            &request.name,
-            &[quote!(D)],
-            &fn_args,
-            quote!(()),
+            name,
+            generics,
+            args,
+            ret_ty.clone(),
             None,
-            send_message,
-        ),
+            quote! { #check #body },
+        );
+        let try_doc = if since > 1 {
+            format!(
+                "Same as [`{name}`](Self::{name}), but returns an error instead of panicking if \
+                the object is dead or its version is lower than {since}."
+            )
+        } else {
+            format!(
+                "Same as [`{name}`](Self::{name}), but returns an error instead of panicking if \
+                the object is dead."
+            )
+        };
+        let try_fn = gen_pub_fn(
+            &quote!(#[doc = #try_doc]),
+            &format!("try_{name}"),
+            generics,
+            args,
+            quote!(::std::result::Result<#ret_ty, #wayrs_client_path::RequestError>),
+            None,
+            try_body,
+        );
+        quote! {
+            #default_fn
+            #try_fn
+        }
+    };
+
+    match new_id_interface {
+        None => gen_fn(&fn_name, &[quote!(D)], &fn_args, None, &send_message),
         Some(None) => {
-            let no_cb = gen_pub_fn(
-                &doc,
-                &request.name,
-                &[quote!(P: Proxy), quote!(D)],
+            let alloc = allocate(quote!(#generic), quote!(version));
+            let no_cb = gen_fn(
+                &fn_name,
+                &[quote!(#generic: #object_trait), quote!(D)],
                 &fn_args,
-                quote!(P),
-                None,
-                quote! {
-                    let new_object = conn.allocate_new_object::<P>(version);
-                    #send_message
-                    new_object
+                Some(quote!(#generic)),
+                &|fallible| {
+                    let send_message = send_message(fallible);
+                    quote! {
+                        let new_object = #alloc;
+                        #send_message
+                        new_object
+                    }
                 },
             );
-            fn_args
-                .push(quote!(cb: impl FnMut(#wayrs_client_path::EventCtx<D, P>) + Send + 'static));
-            let cb = gen_pub_fn(
-                &doc,
-                &format!("{}_with_cb", request.name),
-                &[quote!(P: Proxy), quote!(D)],
+            fn_args.push(
+                quote!(cb: impl FnMut(#wayrs_client_path::#ctx_ty<D, #generic>) + Send + 'static),
+            );
+            let alloc = allocate_with_cb(quote!(version));
+            let cb = gen_fn(
+                &format!("{fn_name}_with_cb"),
+                &[quote!(#generic: #object_trait), quote!(D)],
                 &fn_args,
-                quote!(P),
-                None,
-                quote! {
-                    let new_object = conn.allocate_new_object_with_cb(version, cb);
-                    #send_message
-                    new_object
+                Some(quote!(#generic)),
+                &|fallible| {
+                    let send_message = send_message(fallible);
+                    quote! {
+                        let new_object = #alloc;
+                        #send_message
+                        new_object
+                    }
                 },
             );
             quote! {
@@ -640,31 +1244,37 @@ fn gen_request_fn(opcode: u16, request: &Message, wayrs_client_path: &Ident) ->
         }
         Some(Some(i)) => {
             let proxy_path = make_proxy_path(i);
-            let no_cb = gen_pub_fn(
-                &doc,
-                &request.name,
+            let alloc = allocate(proxy_path.clone(), quote!(self.version));
+            let no_cb = gen_fn(
+                &fn_name,
                 &[quote!(D)],
                 &fn_args,
-                proxy_path.clone(),
-                None,
-                quote! {
-                    let new_object = conn.allocate_new_object::<#proxy_path>(self.version);
-                    #send_message
-                    new_object
+                Some(proxy_path.clone()),
+                &|fallible| {
+                    let send_message = send_message(fallible);
+                    quote! {
+                        let new_object = #alloc;
+                        #send_message
+                        new_object
+                    }
                 },
             );
-            fn_args.push(quote!(cb: impl FnMut(#wayrs_client_path::EventCtx<D, #proxy_path>) + Send + 'static));
-            let cb = gen_pub_fn(
-                &doc,
-                &format!("{}_with_cb", request.name),
+            fn_args.push(
+                quote!(cb: impl FnMut(#wayrs_client_path::#ctx_ty<D, #proxy_path>) + Send + 'static),
+            );
+            let alloc = allocate_with_cb(quote!(self.version));
+            let cb = gen_fn(
+                &format!("{fn_name}_with_cb"),
                 &[quote!(D)],
                 &fn_args,
-                proxy_path.clone(),
-                None,
-                quote! {
-                    let new_object = conn.allocate_new_object_with_cb(self.version, cb);
-                    #send_message
-                    new_object
+                Some(proxy_path.clone()),
+                &|fallible| {
+                    let send_message = send_message(fallible);
+                    quote! {
+                        let new_object = #alloc;
+                        #send_message
+                        new_object
+                    }
                 },
             );
             quote! {
@@ -675,7 +1285,7 @@ fn gen_request_fn(opcode: u16, request: &Message, wayrs_client_path: &Ident) ->
     }
 }
 
-fn map_arg_to_argtype(arg: &Argument) -> TokenStream {
+fn map_arg_to_argtype(arg: &Argument, interface_ref: fn(&str) -> TokenStream) -> TokenStream {
     match &arg.arg_type {
         ArgType::Int => quote!(Int),
         ArgType::Uint | ArgType::Enum(_) => quote!(Uint),
@@ -688,8 +1298,8 @@ fn map_arg_to_argtype(arg: &Argument) -> TokenStream {
         } => quote!(OptObject),
         ArgType::NewId { iface: None } => quote!(AnyNewId),
         ArgType::NewId { iface: Some(iface) } => {
-            let proxy_name = make_proxy_path(iface);
-            quote!(NewId(#proxy_name::INTERFACE))
+            let interface = interface_ref(iface);
+            quote!(NewId(#interface))
         }
         ArgType::String { allow_null: false } => quote!(String),
         ArgType::String { allow_null: true } => quote!(OptString),
@@ -698,7 +1308,19 @@ fn map_arg_to_argtype(arg: &Argument) -> TokenStream {
     }
 }
 
-fn map_arg_to_argval(arg: &Argument, is_event: bool) -> TokenStream {
+/// Refer to an interface by its proxy or resource type.
+fn object_interface_ref(iface: &str) -> TokenStream {
+    let proxy_name = make_proxy_path(iface);
+    quote!(#proxy_name::INTERFACE)
+}
+
+/// Refer to an interface generated with `generate_interfaces!`.
+fn static_interface_ref(iface: &str) -> TokenStream {
+    let mod_name = Ident::new(iface, Span::call_site());
+    quote!(&super::#mod_name::INTERFACE)
+}
+
+fn map_arg_to_argval(arg: &Argument) -> TokenStream {
     match &arg.arg_type {
         ArgType::Int => quote!(Int),
         ArgType::Uint | ArgType::Enum(_) => quote!(Uint),
@@ -709,10 +1331,6 @@ fn map_arg_to_argval(arg: &Argument, is_event: bool) -> TokenStream {
         ArgType::Object {
             allow_null: true, ..
         } => quote!(OptObject),
-        ArgType::NewId { iface } if is_event => match iface.as_deref() {
-            Some(_) => quote!(NewId),
-            None => unimplemented!(),
-        },
         ArgType::NewId { iface: None } => quote!(AnyNewId),
         ArgType::NewId { iface: Some(_) } => quote!(NewId),
         ArgType::String { allow_null: false } => quote!(String),
@@ -760,14 +1378,16 @@ fn gen_doc(
 }
 
 trait ArgExt {
-    fn as_request_fn_arg(&self, wayrs_client_path: &Ident) -> Option<TokenStream>;
-    fn as_event_ty(&self, wayrs_client_path: &Ident) -> TokenStream;
+    fn as_outgoing_fn_arg(&self, wayrs_client_path: &Ident) -> Option<TokenStream>;
+    fn as_incoming_ty(&self, wayrs_client_path: &Ident, side: Side) -> TokenStream;
+    fn as_borrowed_ty(&self, wayrs_client_path: &Ident) -> TokenStream;
+    fn is_borrowed(&self) -> bool;
     fn is_clone(&self) -> bool;
-    fn is_copy(&self) -> bool;
+    fn is_copy(&self, side: Side) -> bool;
 }
 
 impl ArgExt for Argument {
-    fn as_request_fn_arg(&self, wayrs_client_path: &Ident) -> Option<TokenStream> {
+    fn as_outgoing_fn_arg(&self, wayrs_client_path: &Ident) -> Option<TokenStream> {
         let arg_name = make_ident(&self.name);
         let retval = match &self.arg_type {
             ArgType::Int => quote!(#arg_name: i32),
@@ -814,7 +1434,7 @@ impl ArgExt for Argument {
         Some(retval)
     }
 
-    fn as_event_ty(&self, wayrs_client_path: &Ident) -> TokenStream {
+    fn as_incoming_ty(&self, wayrs_client_path: &Ident, side: Side) -> TokenStream {
         match &self.arg_type {
             ArgType::Int => quote!(i32),
             ArgType::Uint => quote!(u32),
@@ -833,7 +1453,10 @@ impl ArgExt for Argument {
                 false => quote!(#wayrs_client_path::core::ObjectId),
                 true => quote!(::std::option::Option<#wayrs_client_path::core::ObjectId>),
             },
-            ArgType::NewId { iface: None } => quote!(#wayrs_client_path::object::Object),
+            ArgType::NewId { iface: None } => match side {
+                Side::Client => quote!(#wayrs_client_path::object::Object),
+                Side::Server => quote!(#wayrs_client_path::object::NewId),
+            },
             ArgType::NewId { iface: Some(iface) } => make_proxy_path(iface),
             ArgType::String { allow_null } => match allow_null {
                 false => quote!(::std::ffi::CString),
@@ -844,6 +1467,21 @@ impl ArgExt for Argument {
         }
     }
 
+    fn as_borrowed_ty(&self, wayrs_client_path: &Ident) -> TokenStream {
+        match &self.arg_type {
+            ArgType::String { allow_null } => match allow_null {
+                false => quote!(&'a ::std::ffi::CStr),
+                true => quote!(::std::option::Option<&'a ::std::ffi::CStr>),
+            },
+            ArgType::Array => quote!(&'a [u8]),
+            _ => self.as_incoming_ty(wayrs_client_path, Side::Client),
+        }
+    }
+
+    fn is_borrowed(&self) -> bool {
+        matches!(self.arg_type, ArgType::String { .. } | ArgType::Array)
+    }
+
     fn is_clone(&self) -> bool {
         match &self.arg_type {
             ArgType::Int
@@ -858,8 +1496,9 @@ impl ArgExt for Argument {
         }
     }
 
-    fn is_copy(&self) -> bool {
+    fn is_copy(&self, side: Side) -> bool {
         match &self.arg_type {
+            ArgType::NewId { iface: None } if side == Side::Server => false,
             ArgType::Int
             | ArgType::Uint
             | ArgType::Enum(_)
@@ -0,0 +1,55 @@
+//! A simple Rust implementation of Wayland server library
+//!
+//! This crate is the server side counterpart of `wayrs-client`. [`Server`] listens on a Unix
+//! socket, accepts clients, keeps track of the objects each client has created and dispatches
+//! requests to per-object callbacks.
+
+#![cfg_attr(docsrs, feature(doc_cfg))]
+
+pub mod object;
+pub mod protocol;
+
+mod server;
+
+pub use server::{ClientId, ListenError, Server};
+
+#[doc(hidden)]
+pub use wayrs_scanner as _private_scanner;
+
+pub use wayrs_core as core;
+pub use wayrs_core::{Fixed, IoMode};
+
+use std::fmt;
+
+/// Generate glue code from .xml protocol file. The path is relative to your project root.
+///
+/// Unlike `wayrs_client::generate!`, this generates resources which receive requests and send
+/// events.
+#[macro_export]
+macro_rules! generate {
+    ($path:literal) => {
+        $crate::_private_scanner::generate_server!($crate, $path);
+    };
+}
+
+/// Request callback context.
+#[non_exhaustive]
+pub struct RequestCtx<'a, D, R: object::Resource> {
+    pub server: &'a mut Server<D>,
+    pub state: &'a mut D,
+    pub resource: R,
+    pub request: R::Request,
+}
+
+impl<D, R: object::Resource> fmt::Debug for RequestCtx<'_, D, R>
+where
+    R: fmt::Debug,
+    R::Request: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestCtx")
+            .field("resource", &self.resource)
+            .field("request", &self.request)
+            .finish_non_exhaustive()
+    }
+}
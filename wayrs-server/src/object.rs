@@ -0,0 +1,248 @@
+//! Server side object representation
+
+use std::borrow::Borrow;
+use std::cmp;
+use std::ffi::CString;
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroU32;
+
+use crate::protocol::WlDisplay;
+use crate::server::GenericCallback;
+use crate::ClientId;
+
+pub use wayrs_core::ObjectId;
+use wayrs_core::{Interface, Message, MessageBuffersPool};
+
+/// A Wayland object owned by a client.
+///
+/// The [`Debug`] representation is `<interface>@<id>v<version>`.
+///
+/// [`Eq`], [`Ord`] and [`Hash`] implementations are delegated to the object's client and ID for
+/// performance reasons. This is fine because two different objects with the same ID must not exist
+/// at the same time within one client.
+#[derive(Clone, Copy)]
+pub struct Object {
+    pub client: ClientId,
+    pub id: ObjectId,
+    pub interface: &'static Interface,
+    pub version: u32,
+}
+
+impl PartialEq for Object {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.client == other.client && self.id == other.id
+    }
+}
+
+impl Eq for Object {}
+
+impl PartialOrd for Object {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Object {
+    #[inline]
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (self.client, self.id).cmp(&(other.client, other.id))
+    }
+}
+
+impl Hash for Object {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.client.hash(state);
+        self.id.hash(state);
+    }
+}
+
+impl Borrow<ObjectId> for Object {
+    #[inline]
+    fn borrow(&self) -> &ObjectId {
+        &self.id
+    }
+}
+
+impl Debug for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{}v{}",
+            self.interface.name.to_string_lossy(),
+            self.id.0,
+            self.version
+        )
+    }
+}
+
+pub(crate) struct ObjectManager<D> {
+    vacant_server_ids: Vec<ObjectId>,
+    client_objects: Vec<Option<ObjectState<D>>>,
+    server_objects: Vec<Option<ObjectState<D>>>,
+}
+
+pub(crate) struct ObjectState<D> {
+    pub object: Object,
+    pub cb: Option<GenericCallback<D>>,
+}
+
+/// An untyped "new_id" argument, as received in `wl_registry.bind`.
+#[derive(Debug, Clone)]
+pub struct NewId {
+    pub interface: CString,
+    pub version: u32,
+    pub id: ObjectId,
+}
+
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct BadMessage;
+
+/// Error which may occur in `Resource: TryFrom<Object>` conversion.
+#[derive(Debug)]
+pub struct WrongObject;
+
+/// A Wayland object resource, the server side counterpart of a client proxy.
+///
+/// This trait is implemented automatically for generated resources, do not implement it yourself.
+pub trait Resource: TryFrom<Object, Error = WrongObject> + Copy {
+    type Request;
+
+    const INTERFACE: &'static Interface;
+
+    #[doc(hidden)]
+    fn new(client: ClientId, id: ObjectId, version: u32) -> Self;
+
+    #[doc(hidden)]
+    fn parse_request(
+        request: Message,
+        client: ClientId,
+        version: u32,
+        pool: &mut MessageBuffersPool,
+    ) -> Result<Self::Request, BadMessage>;
+
+    fn client(&self) -> ClientId;
+
+    fn id(&self) -> ObjectId;
+
+    fn version(&self) -> u32;
+}
+
+impl<R: Resource> From<R> for Object {
+    fn from(value: R) -> Self {
+        Self {
+            client: value.client(),
+            id: value.id(),
+            interface: R::INTERFACE,
+            version: value.version(),
+        }
+    }
+}
+
+impl<D> ObjectManager<D> {
+    pub fn new(client: ClientId) -> Self {
+        let mut this = Self {
+            vacant_server_ids: Vec::new(),
+            client_objects: Vec::with_capacity(16),
+            server_objects: Vec::new(),
+        };
+
+        // Dummy NULL object
+        this.client_objects.push(None);
+
+        // Display
+        this.client_objects.push(Some(ObjectState {
+            object: Object {
+                client,
+                id: ObjectId::DISPLAY,
+                interface: WlDisplay::INTERFACE,
+                version: 1,
+            },
+            cb: None,
+        }));
+
+        this
+    }
+
+    /// Register an object created by the client with a "new_id" argument.
+    ///
+    /// Like `libwayland`, this only accepts IDs which are either vacant or immediately follow the
+    /// largest known ID. Returns `None` if the ID cannot be used.
+    pub fn register_client_object(&mut self, object: Object) -> Option<&mut ObjectState<D>> {
+        if !object.id.created_by_client() {
+            return None;
+        }
+
+        let index = object.id.as_u32() as usize;
+        if index == self.client_objects.len() {
+            self.client_objects.push(None);
+        }
+
+        let slot = self.client_objects.get_mut(index)?;
+        if slot.is_some() {
+            return None;
+        }
+
+        Some(slot.insert(ObjectState { object, cb: None }))
+    }
+
+    pub fn alloc_server_object(
+        &mut self,
+        client: ClientId,
+        interface: &'static Interface,
+        version: u32,
+    ) -> &mut ObjectState<D> {
+        let id = self.vacant_server_ids.pop().unwrap_or_else(|| {
+            let id = self.server_objects.len() as u32 + ObjectId::MIN_SERVER.as_u32();
+            self.server_objects.push(None);
+            ObjectId(NonZeroU32::new(id).unwrap())
+        });
+
+        let index = (id.as_u32() - ObjectId::MIN_SERVER.as_u32()) as usize;
+        let obj = self.server_objects.get_mut(index).unwrap();
+        assert!(obj.is_none());
+
+        obj.insert(ObjectState {
+            object: Object {
+                client,
+                id,
+                interface,
+                version,
+            },
+            cb: None,
+        })
+    }
+
+    pub fn get_object_mut(&mut self, id: ObjectId) -> Option<&mut ObjectState<D>> {
+        if id.created_by_client() {
+            self.client_objects
+                .get_mut(id.as_u32() as usize)
+                .and_then(Option::as_mut)
+        } else {
+            self.server_objects
+                .get_mut((id.as_u32() - ObjectId::MIN_SERVER.as_u32()) as usize)
+                .and_then(Option::as_mut)
+        }
+    }
+
+    /// Remove an object. IDs of server-created objects become vacant immediately, IDs of
+    /// client-created objects are reused by the client after `wl_display.delete_id`.
+    pub fn remove_object(&mut self, id: ObjectId) -> Option<ObjectState<D>> {
+        if id.created_by_client() {
+            self.client_objects
+                .get_mut(id.as_u32() as usize)
+                .and_then(Option::take)
+        } else {
+            let state = self
+                .server_objects
+                .get_mut((id.as_u32() - ObjectId::MIN_SERVER.as_u32()) as usize)
+                .and_then(Option::take)?;
+            self.vacant_server_ids.push(id);
+            Some(state)
+        }
+    }
+}
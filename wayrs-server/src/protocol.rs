@@ -0,0 +1,3 @@
+//! The core Wayland protocol
+
+crate::_private_scanner::generate_server!(crate, core_protocol, interfaces = wayrs_core::protocol);
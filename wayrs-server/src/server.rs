@@ -0,0 +1,1098 @@
+//! Wayland server
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use crate::object::{BadMessage, Object, ObjectManager, Resource};
+use crate::protocol::wl_display::{self, WlDisplay};
+use crate::protocol::wl_registry::{self, WlRegistry};
+use crate::RequestCtx;
+
+use wayrs_core::transport::{
+    BufferLimitError, BufferedSocket, PeekHeaderError, RecvMessageError, SendMessageError,
+};
+use wayrs_core::{ArgType, ArgValue, Interface, IoMode, Message, MessageBuffersPool, ObjectId};
+
+/// An error that can occur while creating a listening Wayland socket.
+#[derive(Debug)]
+pub enum ListenError {
+    /// `$XDG_RUNTIME_DIR` was not available and the socket name is not an absolute path.
+    NoRuntimeDir,
+    /// The socket is already used by a different server.
+    AddressInUse,
+    /// Some IO error.
+    Io(io::Error),
+}
+
+impl std::error::Error for ListenError {}
+
+impl fmt::Display for ListenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoRuntimeDir => f.write_str("$XDG_RUNTIME_DIR must be set"),
+            Self::AddressInUse => f.write_str("socket is already in use"),
+            Self::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl From<io::Error> for ListenError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// A unique identifier of a connected client.
+///
+/// IDs are never reused, so a stale `ClientId` will never refer to a different client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClientId(u32);
+
+/// Wayland server state.
+///
+/// This struct manages a listening socket, the set of connected clients, their objects and globals
+/// and dispatches client requests.
+///
+/// `wl_display` and `wl_registry` requests are handled internally.
+pub struct Server<D> {
+    listener: UnixListener,
+    socket_path: Option<PathBuf>,
+    lock: Option<(PathBuf, File)>,
+
+    clients: HashMap<ClientId, Client<D>>,
+    next_client_id: u32,
+
+    globals: Vec<Global<D>>,
+    next_global_name: u32,
+    serial: u32,
+
+    msg_buffers_pool: MessageBuffersPool,
+}
+
+struct Client<D> {
+    socket: BufferedSocket<UnixStream>,
+    object_mgr: ObjectManager<D>,
+    registries: Vec<ObjectId>,
+    events_queue: VecDeque<Message>,
+    error: Option<String>,
+}
+
+struct Global<D> {
+    name: u32,
+    interface: &'static Interface,
+    version: u32,
+    // This is `None` while the global is being bound, to make the borrow checker happy.
+    bind_cb: Option<BindCallback<D>>,
+}
+
+pub(crate) type GenericCallback<D> = Box<dyn FnMut(&mut Server<D>, &mut D, Object, Message) + Send>;
+
+type BindCallback<D> = Box<dyn FnMut(&mut Server<D>, &mut D, Object) + Send>;
+
+impl<D> AsRawFd for Server<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl<D> Drop for Server<D> {
+    fn drop(&mut self) {
+        if let Some(path) = &self.socket_path {
+            let _ = fs::remove_file(path);
+        }
+        if let Some((path, _file)) = &self.lock {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl<D> Server<D> {
+    /// Create a listening socket with a given name.
+    ///
+    /// If `name` is not an absolute path, the socket is created in `$XDG_RUNTIME_DIR`. Just like
+    /// `libwayland`, a `<socket>.lock` file is used to make sure only one server uses the socket.
+    /// Both files are removed when the server is dropped.
+    pub fn bind(name: impl AsRef<Path>) -> Result<Self, ListenError> {
+        let name = name.as_ref();
+        let path = if name.is_absolute() {
+            name.to_path_buf()
+        } else {
+            let runtime_dir = env::var_os("XDG_RUNTIME_DIR").ok_or(ListenError::NoRuntimeDir)?;
+            let mut path = PathBuf::from(runtime_dir);
+            path.push(name);
+            path
+        };
+
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .mode(0o660)
+            .open(&lock_path)?;
+        if unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            return Err(ListenError::AddressInUse);
+        }
+
+        // We hold the lock, so whatever is left at this path is stale.
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+
+        let mut this = Self::from_listener(UnixListener::bind(&path)?);
+        this.socket_path = Some(path);
+        this.lock = Some((lock_path, lock_file));
+        Ok(this)
+    }
+
+    /// Create a listening socket with the first available name in `wayland-1` ..= `wayland-32`.
+    ///
+    /// Use [`socket_name`](Self::socket_name) to get the chosen name.
+    pub fn bind_auto() -> Result<Self, ListenError> {
+        for i in 1..=32 {
+            match Self::bind(format!("wayland-{i}")) {
+                Err(ListenError::AddressInUse) => continue,
+                result => return result,
+            }
+        }
+        Err(ListenError::AddressInUse)
+    }
+
+    /// Create a server from an already listening socket.
+    ///
+    /// The socket file is not removed when the server is dropped.
+    #[must_use]
+    pub fn from_listener(listener: UnixListener) -> Self {
+        Self {
+            listener,
+            socket_path: None,
+            lock: None,
+
+            clients: HashMap::new(),
+            next_client_id: 0,
+
+            globals: Vec::new(),
+            next_global_name: 1,
+            serial: 0,
+
+            msg_buffers_pool: MessageBuffersPool::default(),
+        }
+    }
+
+    /// The name of the socket, suitable for `$WAYLAND_DISPLAY`.
+    ///
+    /// Returns `None` if the server was created with [`from_listener`](Self::from_listener).
+    #[must_use]
+    pub fn socket_name(&self) -> Option<&Path> {
+        let path = self.socket_path.as_deref()?;
+        path.file_name().map(Path::new)
+    }
+
+    /// Accept a new client.
+    ///
+    /// If `mode` is [`NonBlocking`](IoMode::NonBlocking) and there are no pending connections,
+    /// [`WouldBlock`](io::ErrorKind::WouldBlock) is returned.
+    pub fn accept(&mut self, mode: IoMode) -> io::Result<ClientId> {
+        self.listener.set_nonblocking(mode == IoMode::NonBlocking)?;
+        let (stream, _addr) = self.listener.accept()?;
+        stream.set_nonblocking(false)?;
+        Ok(self.add_client(stream))
+    }
+
+    /// Add a client connected with an existing socket, e.g. one end of a `socketpair`.
+    pub fn add_client(&mut self, stream: UnixStream) -> ClientId {
+        let id = ClientId(self.next_client_id);
+        self.next_client_id += 1;
+        self.clients.insert(
+            id,
+            Client {
+                socket: BufferedSocket::from(stream),
+                object_mgr: ObjectManager::new(id),
+                registries: Vec::new(),
+                events_queue: VecDeque::new(),
+                error: None,
+            },
+        );
+        id
+    }
+
+    /// Disconnect a client and destroy all of its objects.
+    pub fn disconnect(&mut self, client: ClientId) {
+        self.clients.remove(&client);
+    }
+
+    /// Get an iterator of currently connected clients.
+    pub fn clients(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.clients.keys().copied()
+    }
+
+    /// Get the file descriptor of the client's socket, for polling.
+    #[must_use]
+    pub fn client_fd(&self, client: ClientId) -> Option<RawFd> {
+        self.clients.get(&client).map(|c| c.socket.as_raw_fd())
+    }
+
+    /// Get the next serial number.
+    pub fn next_serial(&mut self) -> u32 {
+        self.serial = self.serial.wrapping_add(1);
+        self.serial
+    }
+
+    /// Advertise a new global to all current and future clients.
+    ///
+    /// `cb` is called each time a client binds the global. Returns the name of the global.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `version` is zero or larger than the version of `R`'s interface.
+    pub fn add_global<R: Resource, F: FnMut(&mut Server<D>, &mut D, R) + Send + 'static>(
+        &mut self,
+        version: u32,
+        mut cb: F,
+    ) -> u32 {
+        assert!(version >= 1 && version <= R::INTERFACE.version);
+
+        let name = self.next_global_name;
+        self.next_global_name += 1;
+
+        self.globals.push(Global {
+            name,
+            interface: R::INTERFACE,
+            version,
+            bind_cb: Some(Box::new(move |server, state, object| {
+                cb(server, state, object.try_into().unwrap())
+            })),
+        });
+
+        let clients: Vec<ClientId> = self.clients.keys().copied().collect();
+        for client in clients {
+            self.send_globals_to(client, None, Some(name));
+        }
+
+        name
+    }
+
+    /// Remove a global, notifying all clients which have a registry.
+    ///
+    /// Objects which were bound to this global are not affected.
+    pub fn remove_global(&mut self, name: u32) {
+        let Some(i) = self.globals.iter().position(|g| g.name == name) else {
+            return;
+        };
+        self.globals.remove(i);
+
+        let mut registries = Vec::new();
+        for (&client, state) in &self.clients {
+            registries.extend(state.registries.iter().map(|&r| (client, r)));
+        }
+        for (client, registry) in registries {
+            WlRegistry::new(client, registry, 1).send_global_remove(self, name);
+        }
+    }
+
+    /// Set a callback for a given resource.
+    ///
+    /// Does nothing if the resource was destroyed or its client has disconnected.
+    pub fn set_callback_for<R: Resource, F: FnMut(RequestCtx<D, R>) + Send + 'static>(
+        &mut self,
+        resource: R,
+        cb: F,
+    ) {
+        let Some(obj) = self
+            .clients
+            .get_mut(&resource.client())
+            .and_then(|client| client.object_mgr.get_object_mut(resource.id()))
+        else {
+            return;
+        };
+
+        // The ID may have been reused for an object of a different interface
+        if obj.object.interface == R::INTERFACE {
+            obj.cb = Some(Self::make_generic_cb(cb));
+        }
+    }
+
+    /// Send a protocol error to the client that owns `object`.
+    ///
+    /// `code` is usually a variant of the interface's `Error` enum, e.g.
+    /// `wl_display::Error::InvalidObject`.
+    ///
+    /// The client is disconnected once the error is flushed. All further requests from this
+    /// client are ignored.
+    pub fn post_error(
+        &mut self,
+        object: impl Into<Object>,
+        code: impl Into<u32>,
+        message: impl Into<String>,
+    ) {
+        let object = object.into();
+        let code = code.into();
+        let message = message.into();
+
+        match self.clients.get(&object.client) {
+            Some(client) if client.error.is_none() => (),
+            _ => return,
+        }
+
+        Self::display(object.client).send_error(
+            self,
+            object,
+            code,
+            CString::new(message.clone()).unwrap_or_default(),
+        );
+        self.clients.get_mut(&object.client).unwrap().error = Some(format!(
+            "protocol error in object {object:?} (code({code})): {message}"
+        ));
+    }
+
+    /// Receive and dispatch requests from a client.
+    ///
+    /// If `mode` is [`Blocking`](IoMode::Blocking), this function will block the current thread
+    /// until at least one request is read.
+    ///
+    /// If `mode` is [`NonBlocking`](IoMode::NonBlocking), this function will read from the socket
+    /// until reading would block. If at least one request was dispatched, `Ok` will be returned.
+    /// Otherwise, [`WouldBlock`](io::ErrorKind::WouldBlock) will be propagated.
+    ///
+    /// On any other error, including a protocol error posted while handling a request, the client
+    /// is disconnected and the error is returned.
+    pub fn dispatch_requests(
+        &mut self,
+        client: ClientId,
+        state: &mut D,
+        mut mode: IoMode,
+    ) -> io::Result<()> {
+        let mut at_least_one = false;
+
+        loop {
+            match self.dispatch_request(client, state, mode) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && at_least_one => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Err(e),
+                Err(e) => {
+                    // Try to deliver the error before disconnecting.
+                    let _ = self.flush(client, IoMode::NonBlocking);
+                    self.disconnect(client);
+                    return Err(e);
+                }
+            }
+
+            at_least_one = true;
+            mode = IoMode::NonBlocking;
+        }
+    }
+
+    /// Send the queue of pending events to a client.
+    ///
+    /// If an event exceeds the limits of the socket buffers, it is dropped and an error wrapping
+    /// [`BufferLimitError`] is returned.
+    pub fn flush(&mut self, client: ClientId, mode: IoMode) -> io::Result<()> {
+        let client = self
+            .clients
+            .get_mut(&client)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+
+        // Send pending messages
+        while let Some(msg) = client.events_queue.pop_front() {
+            if let Err(SendMessageError { msg, err }) =
+                client
+                    .socket
+                    .write_message(msg, &mut self.msg_buffers_pool, mode)
+            {
+                // A message which exceeds the buffer limits will never be sent, so drop it
+                if BufferLimitError::from_io(&err).is_some() {
+                    self.msg_buffers_pool.reuse_args(msg.args);
+                } else {
+                    client.events_queue.push_front(msg);
+                }
+                return Err(err);
+            }
+        }
+
+        // Flush socket
+        client.socket.flush(mode)
+    }
+
+    /// Flush all clients without blocking.
+    ///
+    /// Clients which fail with an error other than [`WouldBlock`](io::ErrorKind::WouldBlock) are
+    /// disconnected.
+    pub fn flush_clients(&mut self) {
+        let clients: Vec<ClientId> = self.clients.keys().copied().collect();
+        for client in clients {
+            match self.flush(client, IoMode::NonBlocking) {
+                Err(e) if e.kind() != io::ErrorKind::WouldBlock => self.disconnect(client),
+                _ => (),
+            }
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn alloc_msg_args(&mut self) -> Vec<ArgValue> {
+        self.msg_buffers_pool.get_args()
+    }
+
+    #[doc(hidden)]
+    pub fn send_event(&mut self, client_id: ClientId, iface: &'static Interface, event: Message) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            // The client has disconnected, nothing to do.
+            self.msg_buffers_pool.reuse_args(event.args);
+            return;
+        };
+
+        let object_id = event.header.object_id;
+        if client.object_mgr.get_object_mut(object_id).is_none() {
+            // The object was destroyed by the client, nothing to do.
+            self.msg_buffers_pool.reuse_args(event.args);
+            return;
+        }
+
+        let is_destructor = iface.events[event.header.opcode as usize].is_destructor;
+        client.events_queue.push_back(event);
+
+        // Destroy object if event is destructor
+        if is_destructor {
+            client.object_mgr.remove_object(object_id);
+            if object_id.created_by_client() {
+                Self::display(client_id).send_delete_id(self, object_id.as_u32());
+            }
+        }
+    }
+
+    /// Allocate a new object. Returned object must be sent in an event as a "new_id" argument.
+    #[doc(hidden)]
+    pub fn allocate_new_object<R: Resource>(&mut self, client: ClientId, version: u32) -> R {
+        let id = self
+            .clients
+            .get_mut(&client)
+            .expect("attempt to allocate an object for a disconnected client")
+            .object_mgr
+            .alloc_server_object(client, R::INTERFACE, version)
+            .object
+            .id;
+        R::new(client, id, version)
+    }
+
+    /// Allocate a new object and set its callback. Returned object must be sent in an event as a
+    /// "new_id" argument.
+    #[doc(hidden)]
+    pub fn allocate_new_object_with_cb<R: Resource, F: FnMut(RequestCtx<D, R>) + Send + 'static>(
+        &mut self,
+        client: ClientId,
+        version: u32,
+        cb: F,
+    ) -> R {
+        let obj = self
+            .clients
+            .get_mut(&client)
+            .expect("attempt to allocate an object for a disconnected client")
+            .object_mgr
+            .alloc_server_object(client, R::INTERFACE, version);
+        obj.cb = Some(Self::make_generic_cb(cb));
+        R::new(client, obj.object.id, version)
+    }
+
+    fn dispatch_request(
+        &mut self,
+        client_id: ClientId,
+        state: &mut D,
+        mode: IoMode,
+    ) -> io::Result<()> {
+        let client = self
+            .clients
+            .get_mut(&client_id)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+
+        if let Some(error) = &client.error {
+            return Err(io::Error::other(error.clone()));
+        }
+
+        let header = client
+            .socket
+            .peek_message_header(mode)
+            .map_err(|err| match err {
+                PeekHeaderError::Io(io) => io,
+                other => io::Error::new(io::ErrorKind::InvalidData, other),
+            })?;
+
+        let Some(obj) = client.object_mgr.get_object_mut(header.object_id) else {
+            self.post_error(
+                Self::display(client_id),
+                wl_display::Error::InvalidObject,
+                format!("invalid object {}", header.object_id.as_u32()),
+            );
+            return self.check_error(client_id);
+        };
+        let object = obj.object;
+
+        let Some(desc) = object.interface.requests.get(header.opcode as usize) else {
+            self.post_error(
+                object,
+                wl_display::Error::InvalidMethod,
+                format!("invalid method {}, object {object:?}", header.opcode),
+            );
+            return self.check_error(client_id);
+        };
+
+        if desc.since > object.version {
+            self.post_error(
+                object,
+                wl_display::Error::InvalidMethod,
+                format!(
+                    "invalid method {} (since {} < {}), object {object:?}",
+                    header.opcode, object.version, desc.since
+                ),
+            );
+            return self.check_error(client_id);
+        }
+
+        let request = client
+            .socket
+            .recv_message(header, desc.signature, &mut self.msg_buffers_pool, mode)
+            .map_err(|err| match err {
+                RecvMessageError::Io(io) => io,
+                other => io::Error::new(io::ErrorKind::InvalidData, other),
+            })?;
+
+        // Register objects created by this request
+        for (arg, arg_ty) in request.args.iter().zip(desc.signature) {
+            match (arg, arg_ty) {
+                (ArgValue::NewId(id), ArgType::NewId(interface)) => {
+                    let new_object = Object {
+                        client: client_id,
+                        id: *id,
+                        interface,
+                        version: object.version,
+                    };
+                    if client
+                        .object_mgr
+                        .register_client_object(new_object)
+                        .is_none()
+                    {
+                        self.post_error(
+                            object,
+                            wl_display::Error::InvalidObject,
+                            format!("invalid new id {}", id.as_u32()),
+                        );
+                        return self.check_error(client_id);
+                    }
+                }
+                (ArgValue::AnyNewId(..), _) if object.interface != WlRegistry::INTERFACE => {
+                    self.post_error(
+                        object,
+                        wl_display::Error::InvalidMethod,
+                        "untyped new_id arguments are only supported in wl_registry.bind",
+                    );
+                    return self.check_error(client_id);
+                }
+                _ => (),
+            }
+        }
+
+        if object.interface == WlDisplay::INTERFACE {
+            self.handle_display_request(client_id, request);
+            return self.check_error(client_id);
+        }
+
+        if object.interface == WlRegistry::INTERFACE {
+            self.handle_registry_request(object, request, state);
+            return self.check_error(client_id);
+        }
+
+        // Removing the callback from the object to make borrow checker happy
+        let mut object_cb = client
+            .object_mgr
+            .get_object_mut(object.id)
+            .and_then(|obj| obj.cb.take());
+
+        match &mut object_cb {
+            Some(cb) => cb(self, state, object, request),
+            None => self.msg_buffers_pool.reuse_args(request.args),
+        }
+
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            // Disconnected from the callback
+            return Ok(());
+        };
+
+        if desc.is_destructor {
+            if client.object_mgr.remove_object(object.id).is_some() && object.id.created_by_client()
+            {
+                Self::display(client_id).send_delete_id(self, object.id.as_u32());
+            }
+        } else if let Some(obj) = client.object_mgr.get_object_mut(object.id) {
+            // Re-add callback if it wasn't re-set in the callback
+            if obj.cb.is_none() && std::ptr::eq(obj.object.interface, object.interface) {
+                obj.cb = object_cb;
+            }
+        }
+
+        self.check_error(client_id)
+    }
+
+    fn handle_display_request(&mut self, client: ClientId, request: Message) {
+        let request = WlDisplay::parse_request(request, client, 1, &mut self.msg_buffers_pool)
+            .expect("request was validated against the signature");
+
+        match request {
+            wl_display::Request::Sync(callback) => {
+                let serial = self.next_serial();
+                callback.send_done(self, serial);
+            }
+            wl_display::Request::GetRegistry(registry) => {
+                self.clients
+                    .get_mut(&client)
+                    .unwrap()
+                    .registries
+                    .push(registry.id());
+                self.send_globals_to(client, Some(registry.id()), None);
+            }
+        }
+    }
+
+    fn handle_registry_request(&mut self, registry: Object, request: Message, state: &mut D) {
+        let request = WlRegistry::parse_request(
+            request,
+            registry.client,
+            registry.version,
+            &mut self.msg_buffers_pool,
+        )
+        .expect("request was validated against the signature");
+        let wl_registry::Request::Bind(wl_registry::BindArgs { name, id: new_id }) = request;
+        let iface = new_id.interface;
+
+        let Some(global) = self.globals.iter_mut().find(|g| g.name == name) else {
+            self.post_error(
+                registry,
+                wl_display::Error::InvalidObject,
+                format!("invalid global {} ({name})", iface.to_string_lossy()),
+            );
+            return;
+        };
+
+        if global.interface.name != iface.as_c_str()
+            || new_id.version == 0
+            || new_id.version > global.version
+        {
+            let message = format!(
+                "invalid interface for global {name}: have {}v{}, wanted {}v{}",
+                global.interface.name.to_string_lossy(),
+                global.version,
+                iface.to_string_lossy(),
+                new_id.version,
+            );
+            self.post_error(registry, wl_display::Error::InvalidObject, message);
+            return;
+        }
+
+        let object = Object {
+            client: registry.client,
+            id: new_id.id,
+            interface: global.interface,
+            version: new_id.version,
+        };
+        let mut bind_cb = global.bind_cb.take();
+
+        let client = self.clients.get_mut(&registry.client).unwrap();
+        if client.object_mgr.register_client_object(object).is_none() {
+            if let Some(global) = self.globals.iter_mut().find(|g| g.name == name) {
+                global.bind_cb = bind_cb;
+            }
+            self.post_error(
+                registry,
+                wl_display::Error::InvalidObject,
+                format!("invalid new id {}", new_id.id.as_u32()),
+            );
+            return;
+        }
+
+        if let Some(cb) = &mut bind_cb {
+            cb(self, state, object);
+        }
+
+        // Re-add the callback unless the global was removed
+        if let Some(global) = self.globals.iter_mut().find(|g| g.name == name) {
+            global.bind_cb = bind_cb;
+        }
+    }
+
+    /// Send `wl_registry.global` events. If `registry` is `None`, all registries of the client are
+    /// used. If `name` is `None`, all globals are sent.
+    fn send_globals_to(&mut self, client: ClientId, registry: Option<ObjectId>, name: Option<u32>) {
+        let Some(state) = self.clients.get(&client) else {
+            return;
+        };
+
+        let registries = match registry {
+            Some(registry) => vec![registry],
+            None => state.registries.clone(),
+        };
+
+        let globals: Vec<(u32, &'static Interface, u32)> = self
+            .globals
+            .iter()
+            .filter(|g| name.map_or(true, |name| name == g.name))
+            .map(|g| (g.name, g.interface, g.version))
+            .collect();
+
+        for registry in registries {
+            for &(name, interface, version) in &globals {
+                WlRegistry::new(client, registry, 1).send_global(
+                    self,
+                    name,
+                    interface.name.to_owned(),
+                    version,
+                );
+            }
+        }
+    }
+
+    fn check_error(&self, client: ClientId) -> io::Result<()> {
+        match self.clients.get(&client).and_then(|c| c.error.as_ref()) {
+            Some(error) => Err(io::Error::other(error.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn display(client: ClientId) -> WlDisplay {
+        WlDisplay::new(client, ObjectId::DISPLAY, 1)
+    }
+
+    fn make_generic_cb<R: Resource, F: FnMut(RequestCtx<D, R>) + Send + 'static>(
+        mut cb: F,
+    ) -> GenericCallback<D> {
+        // Note: if `F` does not capture anything, this `Box::new` will not allocate.
+        Box::new(move |server, state, object, request| {
+            let resource: R = object.try_into().unwrap();
+            let request = match R::parse_request(
+                request,
+                object.client,
+                object.version,
+                &mut server.msg_buffers_pool,
+            ) {
+                Ok(request) => request,
+                Err(BadMessage) => {
+                    server.post_error(
+                        object,
+                        wl_display::Error::InvalidMethod,
+                        "invalid arguments",
+                    );
+                    return;
+                }
+            };
+            let ctx = RequestCtx {
+                server,
+                state,
+                resource,
+                request,
+            };
+            cb(ctx);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::*;
+
+    use std::num::NonZeroU32;
+
+    use wayrs_client::global::GlobalExt;
+    use wayrs_client::object::Proxy;
+    use wayrs_client::protocol as client;
+    use wayrs_client::{ConnectOptions, Connection, ProtocolError};
+    use wayrs_core::MessageHeader;
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn send() {
+        assert_send::<Server<()>>();
+    }
+
+    #[test]
+    fn interfaces_are_shared() {
+        assert!(std::ptr::eq(
+            <WlSurface as Resource>::INTERFACE,
+            <client::WlSurface as Proxy>::INTERFACE,
+        ));
+        let ArgType::NewId(surface) = WlCompositor::INTERFACE.requests[0].signature[0] else {
+            panic!("create_surface must have a new_id argument");
+        };
+        assert!(std::ptr::eq(
+            surface,
+            &wayrs_core::protocol::wl_surface::INTERFACE
+        ));
+    }
+
+    #[derive(Default)]
+    struct State {
+        log: Vec<String>,
+        surfaces: Vec<WlSurface>,
+    }
+
+    /// Create a server in a temporary directory and connect a client to it.
+    fn connect<D>(name: &str) -> (Server<D>, ClientId, Connection<()>) {
+        let path = env::temp_dir().join(format!("wayrs-server-{}-{name}", std::process::id()));
+        let mut server = Server::bind(&path).unwrap();
+        let conn = ConnectOptions::new().path(&path).connect().unwrap();
+        let client = server.accept(IoMode::Blocking).unwrap();
+        (server, client, conn)
+    }
+
+    /// Send the client's requests, dispatch them and dispatch the server's events.
+    fn roundtrip(
+        server: &mut Server<State>,
+        client: ClientId,
+        state: &mut State,
+        conn: &mut Connection<()>,
+    ) {
+        conn.flush(IoMode::Blocking).unwrap();
+        match server.dispatch_requests(client, state, IoMode::NonBlocking) {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => panic!("{e}"),
+            _ => (),
+        }
+        server.flush(client, IoMode::Blocking).unwrap();
+        match conn.recv_events(IoMode::NonBlocking) {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => panic!("{e}"),
+            _ => (),
+        }
+        conn.dispatch_events(&mut ());
+    }
+
+    #[test]
+    fn sync_callbacks_are_deleted() {
+        let (mut server, client, mut conn) = connect("sync");
+        assert_eq!(server.clients().collect::<Vec<_>>(), [client]);
+
+        // Answer `get_registry` and `sync` while the client waits for the callback
+        let server_thread = std::thread::spawn(move || {
+            server
+                .dispatch_requests(client, &mut (), IoMode::Blocking)
+                .unwrap();
+            server.flush(client, IoMode::Blocking).unwrap();
+            server
+        });
+        conn.blocking_roundtrip().unwrap();
+        let mut server = server_thread.join().unwrap();
+        assert!(conn.globals().is_empty());
+
+        // The callback is destroyed by its `done` event
+        let callback_id = ObjectId(NonZeroU32::new(3).unwrap());
+        let client = server.clients.get_mut(&client).unwrap();
+        assert!(client.object_mgr.get_object_mut(callback_id).is_none());
+    }
+
+    #[test]
+    fn unknown_objects_are_protocol_errors() {
+        let (mut server, client, mut conn) = connect("unknown");
+
+        // The server does not know about this object
+        let surface: client::WlSurface = conn.allocate_new_object(1);
+        surface.commit(&mut conn);
+        conn.flush(IoMode::Blocking).unwrap();
+
+        let err = server
+            .dispatch_requests(client, &mut (), IoMode::NonBlocking)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("invalid object {}", surface.id().as_u32())));
+        assert_eq!(server.clients().count(), 0);
+
+        let err = conn.recv_events(IoMode::Blocking).unwrap_err();
+        assert!(err.to_string().contains("invalid object"));
+    }
+
+    fn add_compositor(server: &mut Server<State>) {
+        server.add_global::<WlCompositor, _>(6, |server, state, compositor| {
+            state.log.push("bind".into());
+            server.set_callback_for(compositor, |ctx| {
+                let wl_compositor::Request::CreateSurface(surface) = ctx.request else {
+                    return;
+                };
+                ctx.state.log.push(format!("create_surface {surface:?}"));
+                ctx.state.surfaces.push(surface);
+                ctx.server.set_callback_for(surface, surface_cb);
+            });
+        });
+    }
+
+    fn surface_cb(ctx: RequestCtx<State, WlSurface>) {
+        match ctx.request {
+            wl_surface::Request::SetBufferScale(scale) if scale <= 0 => {
+                ctx.server.post_error(
+                    ctx.resource,
+                    wl_surface::Error::InvalidScale,
+                    format!("invalid scale {scale}"),
+                );
+            }
+            wl_surface::Request::Destroy => ctx.state.log.push("destroy".into()),
+            wl_surface::Request::Commit => ctx.state.log.push("commit".into()),
+            _ => (),
+        }
+    }
+
+    #[test]
+    fn accept_and_bind() {
+        let (mut server, client, mut conn) = connect("bind");
+        let mut state = State::default();
+        add_compositor(&mut server);
+        assert_eq!(server.clients().collect::<Vec<_>>(), [client]);
+
+        roundtrip(&mut server, client, &mut state, &mut conn);
+        assert_eq!(conn.globals().len(), 1);
+        assert!(conn.globals()[0].is::<client::WlCompositor>());
+
+        let _: client::WlCompositor = conn.bind_singleton(6).unwrap();
+        roundtrip(&mut server, client, &mut state, &mut conn);
+        assert_eq!(state.log, ["bind"]);
+    }
+
+    #[test]
+    fn requests_are_dispatched() {
+        let (mut server, client, mut conn) = connect("dispatch");
+        let mut state = State::default();
+        add_compositor(&mut server);
+        roundtrip(&mut server, client, &mut state, &mut conn);
+
+        let compositor: client::WlCompositor = conn.bind_singleton(6).unwrap();
+        let surface = compositor.create_surface(&mut conn);
+        surface.commit(&mut conn);
+        roundtrip(&mut server, client, &mut state, &mut conn);
+        assert_eq!(state.surfaces.len(), 1);
+        assert_eq!(state.surfaces[0].id(), surface.id());
+        assert_eq!(
+            state.log,
+            [
+                "bind".to_owned(),
+                format!("create_surface {:?}", state.surfaces[0]),
+                "commit".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn objects_are_deleted() {
+        let (mut server, client, mut conn) = connect("delete");
+        let mut state = State::default();
+        add_compositor(&mut server);
+        roundtrip(&mut server, client, &mut state, &mut conn);
+
+        let compositor: client::WlCompositor = conn.bind_singleton(6).unwrap();
+        let surface = compositor.create_surface(&mut conn);
+        roundtrip(&mut server, client, &mut state, &mut conn);
+        let server_surface = state.surfaces[0];
+
+        surface.destroy(&mut conn);
+        roundtrip(&mut server, client, &mut state, &mut conn);
+        assert_eq!(state.log.last().unwrap(), "destroy");
+
+        // Events for destroyed objects are dropped
+        server_surface.send_preferred_buffer_scale(&mut server, 2);
+        server.set_callback_for(server_surface, surface_cb);
+
+        // The client received `delete_id`, so the ID is reused
+        let surface2 = compositor.create_surface(&mut conn);
+        assert_eq!(surface2.id(), surface.id());
+        roundtrip(&mut server, client, &mut state, &mut conn);
+        assert_eq!(state.surfaces.len(), 2);
+    }
+
+    #[test]
+    fn protocol_error_disconnects() {
+        let (mut server, client, mut conn) = connect("error");
+        let mut state = State::default();
+        add_compositor(&mut server);
+        roundtrip(&mut server, client, &mut state, &mut conn);
+
+        let compositor: client::WlCompositor = conn.bind_singleton(6).unwrap();
+        let surface = compositor.create_surface(&mut conn);
+        surface.set_buffer_scale(&mut conn, 0);
+        surface.commit(&mut conn);
+        conn.flush(IoMode::Blocking).unwrap();
+
+        let err = server
+            .dispatch_requests(client, &mut state, IoMode::NonBlocking)
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid scale 0"));
+        assert_eq!(server.clients().count(), 0);
+        // Requests after the error are not dispatched
+        assert!(!state.log.iter().any(|entry| entry == "commit"));
+
+        let err = conn.recv_events(IoMode::Blocking).unwrap_err();
+        let err = ProtocolError::from_io(&err).unwrap();
+        assert_eq!(err.object.unwrap().id, surface.id());
+        assert_eq!(err.code_name, Some("invalid_scale"));
+        assert_eq!(err.message.as_c_str(), c"invalid scale 0");
+    }
+
+    #[test]
+    #[should_panic = "event wl_surface.preferred_buffer_scale requires version 6"]
+    fn too_new_events_panic() {
+        let (mut server, client, mut conn) = connect("send-since");
+        let mut state = State::default();
+        add_compositor(&mut server);
+        roundtrip(&mut server, client, &mut state, &mut conn);
+
+        let compositor: client::WlCompositor = conn.bind_singleton(5).unwrap();
+        compositor.create_surface(&mut conn);
+        roundtrip(&mut server, client, &mut state, &mut conn);
+
+        state.surfaces[0].send_preferred_buffer_scale(&mut server, 2);
+    }
+
+    #[test]
+    fn too_new_requests_are_rejected() {
+        let (mut server, client, mut conn) = connect("since");
+        let mut state = State::default();
+        add_compositor(&mut server);
+        roundtrip(&mut server, client, &mut state, &mut conn);
+
+        // Bind version 1 while the client believes it has version 6
+        let compositor: client::WlCompositor = conn.allocate_new_object(6);
+        let registry = conn.registry();
+        conn.send_request(
+            client::WlRegistry::INTERFACE,
+            Message {
+                header: MessageHeader {
+                    object_id: registry.id(),
+                    size: 0,
+                    opcode: 0,
+                },
+                args: vec![
+                    ArgValue::Uint(conn.globals()[0].name),
+                    ArgValue::AnyNewId(c"wl_compositor".into(), 1, compositor.id()),
+                ],
+            },
+        );
+        let surface = compositor.create_surface(&mut conn);
+        surface.set_buffer_scale(&mut conn, 2);
+        conn.flush(IoMode::Blocking).unwrap();
+
+        let err = server
+            .dispatch_requests(client, &mut state, IoMode::NonBlocking)
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid method 8 (since 1 < 3)"));
+        assert_eq!(server.clients().count(), 0);
+
+        let err = conn.recv_events(IoMode::Blocking).unwrap_err();
+        let err = ProtocolError::from_io(&err).unwrap();
+        assert_eq!(err.object.unwrap().id, surface.id());
+        assert_eq!(err.code, wl_display::Error::InvalidMethod as u32);
+    }
+}
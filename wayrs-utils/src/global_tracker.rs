@@ -0,0 +1,326 @@
+//! Tracker of multi-instance globals
+//!
+//! [`Connection::bind_singleton`] is enough for globals such as `wl_compositor`, but globals like
+//! `wl_output` and `wl_seat` can be added and removed at any time. [`GlobalTracker`] binds each
+//! instance of such a global when it is announced, stores per-instance state and releases the
+//! instance when it is removed.
+//!
+//! To use this abstraction, create an instance of [`GlobalTracker`] using [`GlobalTracker::new`],
+//! store it in your state struct and implement [`GlobalHandler`] for your state type.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use wayrs_utils::global_tracker::*;
+//! use wayrs_client::Connection;
+//! use wayrs_client::object::Proxy;
+//! use wayrs_client::protocol::*;
+//!
+//! struct State {
+//!     outputs: GlobalTracker<WlOutput, Option<String>>,
+//! }
+//!
+//! impl GlobalHandler<WlOutput, Option<String>> for State {
+//!     fn get_tracker(&mut self) -> &mut GlobalTracker<WlOutput, Option<String>> {
+//!         &mut self.outputs
+//!     }
+//!
+//!     fn global_added(&mut self, conn: &mut Connection<Self>, output: WlOutput) -> Option<String> {
+//!         conn.set_callback_for(output, |ctx| {
+//!             if let wl_output::Event::Name(name) = ctx.event {
+//!                 let name = name.to_string_lossy().into_owned();
+//!                 *ctx.state.outputs.get_mut(ctx.proxy).unwrap() = Some(name);
+//!             }
+//!         });
+//!         None
+//!     }
+//!
+//!     fn release_global(&mut self, conn: &mut Connection<Self>, output: WlOutput) {
+//!         if output.version() >= 3 {
+//!             output.release(conn);
+//!         }
+//!     }
+//!
+//!     // Optional to implement
+//!     fn global_removed(&mut self, _: &mut Connection<Self>, _: WlOutput, name: Option<String>) {
+//!         eprintln!("output {name:?} removed");
+//!     }
+//! }
+//!
+//! let mut conn = Connection::connect().unwrap();
+//!
+//! let mut state = State {
+//!     outputs: GlobalTracker::new(&mut conn, 4..=4),
+//! };
+//!
+//! conn.blocking_roundtrip().unwrap();
+//! conn.dispatch_events(&mut state);
+//!
+//! for (output, name) in state.outputs.iter() {
+//!     println!("{output:?}: {name:?}");
+//! }
+//! ```
+
+use wayrs_client::global::*;
+use wayrs_client::object::Proxy;
+use wayrs_client::protocol::*;
+use wayrs_client::Connection;
+
+/// Callbacks of a [`GlobalTracker`], implemented by the state type.
+///
+/// `P` is the interface of the global and `T` is the state of each instance.
+pub trait GlobalHandler<P: Proxy, T = ()>: Sized + 'static {
+    fn get_tracker(&mut self) -> &mut GlobalTracker<P, T>;
+
+    /// A new instance of the global is bound. Returns the state of this instance.
+    ///
+    /// The instance is added to the tracker after this function returns.
+    fn global_added(&mut self, conn: &mut Connection<Self>, proxy: P) -> T;
+
+    /// An instance of the global is removed.
+    ///
+    /// The instance is already removed from the tracker. The proxy is released with
+    /// [`release_global`](Self::release_global) after this function returns.
+    fn global_removed(&mut self, _: &mut Connection<Self>, _: P, _: T) {}
+
+    /// Release a removed instance, usually with its destructor request, such as
+    /// `wl_output.release`.
+    ///
+    /// The destructor may be unavailable at the bound version, in which case the proxy cannot be
+    /// destroyed and this function should do nothing.
+    fn release_global(&mut self, conn: &mut Connection<Self>, proxy: P);
+}
+
+/// The state of all instances of a global.
+///
+/// Each announced instance of `P` is bound with the highest version supported by both the
+/// compositor and the version bounds. Instances with versions lower than the bounds are ignored.
+///
+/// When an instance is removed, it is released with [`GlobalHandler::release_global`].
+#[derive(Debug)]
+pub struct GlobalTracker<P, T = ()> {
+    min_version: u32,
+    max_version: u32,
+    instances: Vec<Instance<P, T>>,
+}
+
+#[derive(Debug)]
+struct Instance<P, T> {
+    reg_name: u32,
+    proxy: P,
+    data: T,
+}
+
+/// The callbacks of a tracker.
+///
+/// [`GlobalTracker::new`] takes them from [`GlobalHandler`], helpers of this crate which are
+/// built on a tracker provide their own.
+pub(crate) struct Hooks<D, P, T> {
+    pub get_tracker: fn(&mut D) -> &mut GlobalTracker<P, T>,
+    pub added: fn(&mut D, &mut Connection<D>, P) -> T,
+    /// Called after the instance is added to the tracker.
+    pub inserted: fn(&mut D, &mut Connection<D>, P),
+    pub removed: fn(&mut D, &mut Connection<D>, P, T),
+    pub release: fn(&mut D, &mut Connection<D>, P),
+}
+
+impl<P: Proxy + 'static, T: 'static> GlobalTracker<P, T> {
+    /// Create new `GlobalTracker`.
+    ///
+    /// This function sets up the registry callback and nothing else. Call it only once per
+    /// [`Connection`] and interface, before dispatching any events.
+    ///
+    /// The version argument can be a:
+    /// - Number - require a specific version
+    /// - Range to inclusive (`..=b` - bind a version in range `[1, b]`)
+    /// - Range inclusive (`a..=b` - bind a version in range `[a, b]`)
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the upper bound is higher than the version of `P` known to this
+    /// crate.
+    pub fn new<D: GlobalHandler<P, T>>(
+        conn: &mut Connection<D>,
+        version: impl VersionBounds,
+    ) -> Self {
+        let hooks = Hooks {
+            get_tracker: D::get_tracker,
+            added: D::global_added,
+            inserted: |_, _, _| (),
+            removed: D::global_removed,
+            release: D::release_global,
+        };
+        Self::with_hooks(conn, version, hooks)
+    }
+
+    /// Same as [`new`](Self::new), but the callbacks are not tied to [`GlobalHandler`].
+    pub(crate) fn with_hooks<D: 'static>(
+        conn: &mut Connection<D>,
+        version: impl VersionBounds,
+        hooks: Hooks<D, P, T>,
+    ) -> Self {
+        assert!(version.upper() <= P::INTERFACE.version);
+        conn.add_registry_cb(move |conn, state, event| registry_cb(conn, state, event, &hooks));
+        Self {
+            min_version: version.lower(),
+            max_version: version.upper(),
+            instances: Vec::new(),
+        }
+    }
+}
+
+impl<P: Proxy, T> GlobalTracker<P, T> {
+    /// Get an iterator of currently bound instances and their states, in the order they were
+    /// announced.
+    pub fn iter(&self) -> impl Iterator<Item = (P, &T)> + '_ {
+        self.instances.iter().map(|i| (i.proxy, &i.data))
+    }
+
+    /// Same as [`iter`](Self::iter), but the states are mutable.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (P, &mut T)> + '_ {
+        self.instances.iter_mut().map(|i| (i.proxy, &mut i.data))
+    }
+
+    /// Get an iterator of currently bound instances.
+    pub fn proxies(&self) -> impl Iterator<Item = P> + '_ {
+        self.instances.iter().map(|i| i.proxy)
+    }
+
+    /// Get the state of an instance.
+    #[must_use]
+    pub fn get(&self, proxy: P) -> Option<&T> {
+        let id = proxy.id();
+        self.instances
+            .iter()
+            .find(|i| i.proxy.id() == id)
+            .map(|i| &i.data)
+    }
+
+    /// Get the mutable state of an instance.
+    #[must_use]
+    pub fn get_mut(&mut self, proxy: P) -> Option<&mut T> {
+        let id = proxy.id();
+        self.instances
+            .iter_mut()
+            .find(|i| i.proxy.id() == id)
+            .map(|i| &mut i.data)
+    }
+
+    /// The number of currently bound instances.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    /// Whether there are no bound instances.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+}
+
+fn registry_cb<D, P: Proxy, T>(
+    conn: &mut Connection<D>,
+    state: &mut D,
+    event: &wl_registry::Event,
+    hooks: &Hooks<D, P, T>,
+) {
+    match event {
+        wl_registry::Event::Global(g) if g.is::<P>() => {
+            let tracker = (hooks.get_tracker)(state);
+            let Ok(proxy) = g.bind::<P, D>(conn, tracker.min_version..=tracker.max_version) else {
+                return;
+            };
+            let data = (hooks.added)(state, conn, proxy);
+            (hooks.get_tracker)(state).instances.push(Instance {
+                reg_name: g.name,
+                proxy,
+                data,
+            });
+            (hooks.inserted)(state, conn, proxy);
+        }
+        wl_registry::Event::GlobalRemove(name) => {
+            let tracker = (hooks.get_tracker)(state);
+            let Some(i) = tracker.instances.iter().position(|i| i.reg_name == *name) else {
+                return;
+            };
+            let instance = tracker.instances.remove(i);
+            (hooks.removed)(state, conn, instance.proxy, instance.data);
+            (hooks.release)(state, conn, instance.proxy);
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wayrs_client::testing::MockCompositor;
+
+    struct State {
+        outputs: GlobalTracker<WlOutput, u32>,
+        removed: Vec<u32>,
+    }
+
+    impl GlobalHandler<WlOutput, u32> for State {
+        fn get_tracker(&mut self) -> &mut GlobalTracker<WlOutput, u32> {
+            &mut self.outputs
+        }
+
+        fn global_added(&mut self, _: &mut Connection<Self>, output: WlOutput) -> u32 {
+            output.version()
+        }
+
+        fn global_removed(&mut self, _: &mut Connection<Self>, _: WlOutput, version: u32) {
+            self.removed.push(version);
+        }
+
+        fn release_global(&mut self, conn: &mut Connection<Self>, output: WlOutput) {
+            if output.version() >= 3 {
+                output.release(conn);
+            }
+        }
+    }
+
+    #[test]
+    fn instances_are_tracked_and_released() {
+        let (mut mock, mut conn) = MockCompositor::new::<State>();
+        let mut state = State {
+            outputs: GlobalTracker::new(&mut conn, 2..=3),
+            removed: Vec::new(),
+        };
+
+        let a = mock.add_global::<WlOutput>(4);
+        let b = mock.add_global::<WlOutput>(1);
+        let c = mock.add_global::<WlOutput>(2);
+        mock.roundtrip(&mut conn, &mut state);
+        mock.roundtrip(&mut conn, &mut state);
+
+        // The instance with a version lower than the bounds is ignored
+        let versions: Vec<u32> = state.outputs.iter().map(|(_, v)| *v).collect();
+        assert_eq!(versions, [3, 2]);
+        let [output_a, output_c] = mock.objects_of::<WlOutput>()[..] else {
+            panic!("expected two outputs");
+        };
+        assert_eq!(state.outputs.get(output_c), Some(&2));
+
+        mock.remove_global(b);
+        mock.remove_global(a);
+        mock.roundtrip(&mut conn, &mut state);
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(state.removed, [3]);
+        assert_eq!(state.outputs.proxies().collect::<Vec<_>>(), [output_c]);
+        let requests = mock.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].object, output_a.id());
+        assert_eq!(requests[0].name, "release");
+
+        // `wl_output.release` is not available at version 2
+        mock.remove_global(c);
+        mock.roundtrip(&mut conn, &mut state);
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(state.removed, [3, 2]);
+        assert!(state.outputs.is_empty());
+        assert!(mock.take_requests().is_empty());
+    }
+}
@@ -2,6 +2,7 @@
 
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+pub mod global_tracker;
 pub mod timer;
 
 #[cfg(feature = "seats")]
@@ -52,12 +52,13 @@
 
 use std::ffi::CString;
 
-use wayrs_client::global::*;
 use wayrs_client::object::Proxy;
 use wayrs_client::protocol::wl_seat::Capability;
 use wayrs_client::protocol::*;
 use wayrs_client::{Connection, EventCtx};
 
+use crate::global_tracker::{GlobalTracker, Hooks};
+
 pub trait SeatHandler: Sized + 'static {
     fn get_seats(&mut self) -> &mut Seats;
 
@@ -91,17 +92,11 @@ pub trait SeatHandler: Sized + 'static {
 
 /// The state of `wl_seat`s.
 ///
-/// This struct keeps track of currently available `wl_seat`s and their capabilities.
+/// This struct keeps track of currently available `wl_seat`s and their capabilities. It is a
+/// [`GlobalTracker`] of seats and their capabilities.
 #[derive(Debug)]
 pub struct Seats {
-    seats: Vec<Seat>,
-}
-
-#[derive(Debug)]
-struct Seat {
-    reg_name: u32,
-    wl_seat: WlSeat,
-    capabilities: Capability,
+    seats: GlobalTracker<WlSeat, Capability>,
 }
 
 impl Seats {
@@ -110,8 +105,23 @@ impl Seats {
     /// This function sets up the registry callback and nothing else. Call it only once per
     /// [`Connection`](Connection) and before dispatching any events.
     pub fn new<D: SeatHandler>(conn: &mut Connection<D>) -> Self {
-        conn.add_registry_cb(registry_cb);
-        Self { seats: Vec::new() }
+        let hooks = Hooks {
+            get_tracker: |state: &mut D| &mut state.get_seats().seats,
+            added: |_, conn, seat| {
+                conn.set_callback_for(seat, wl_seat_cb);
+                Capability::empty()
+            },
+            inserted: D::seat_added,
+            removed: seat_removed,
+            release: |_, conn, seat| {
+                if seat.version() >= 5 {
+                    seat.release(conn);
+                }
+            },
+        };
+        Self {
+            seats: GlobalTracker::with_hooks(conn, ..=8, hooks),
+        }
     }
 
     #[deprecated = "use `new` instead (this name is misleading, it does not bind anything)"]
@@ -121,74 +131,35 @@ impl Seats {
 
     /// Get an iterator of currently available `wl_seat`s.
     pub fn iter(&self) -> impl Iterator<Item = WlSeat> + '_ {
-        self.seats.iter().map(|s| s.wl_seat)
+        self.seats.proxies()
     }
 }
 
-impl Seat {
-    fn bind<D: SeatHandler>(conn: &mut Connection<D>, global: &Global) -> Self {
-        Self {
-            reg_name: global.name,
-            wl_seat: global.bind_with_cb(conn, 1..=8, wl_seat_cb).unwrap(),
-            capabilities: Capability::empty(),
-        }
-    }
-}
-
-fn registry_cb<D: SeatHandler>(
-    conn: &mut Connection<D>,
+fn seat_removed<D: SeatHandler>(
     state: &mut D,
-    event: &wl_registry::Event,
+    conn: &mut Connection<D>,
+    seat: WlSeat,
+    caps: Capability,
 ) {
-    let seat_state = state.get_seats();
-
-    match event {
-        wl_registry::Event::Global(g) if g.is::<WlSeat>() => {
-            let seat = Seat::bind(conn, g);
-            let wl_seat = seat.wl_seat;
-            seat_state.seats.push(seat);
-
-            state.seat_added(conn, wl_seat);
-        }
-        wl_registry::Event::GlobalRemove(name) => {
-            let Some(i) = seat_state.seats.iter().position(|s| s.reg_name == *name) else {
-                return;
-            };
-            let seat = seat_state.seats.swap_remove(i);
-
-            if seat.capabilities.contains(Capability::Pointer) {
-                state.pointer_removed(conn, seat.wl_seat);
-            }
-            if seat.capabilities.contains(Capability::Keyboard) {
-                state.keyboard_removed(conn, seat.wl_seat);
-            }
-            if seat.capabilities.contains(Capability::Touch) {
-                state.touch_removed(conn, seat.wl_seat);
-            }
-
-            state.seat_removed(conn, seat.wl_seat);
-
-            if seat.wl_seat.version() >= 5 {
-                seat.wl_seat.release(conn);
-            }
-        }
-        _ => (),
+    if caps.contains(Capability::Pointer) {
+        state.pointer_removed(conn, seat);
+    }
+    if caps.contains(Capability::Keyboard) {
+        state.keyboard_removed(conn, seat);
+    }
+    if caps.contains(Capability::Touch) {
+        state.touch_removed(conn, seat);
     }
+    state.seat_removed(conn, seat);
 }
 
 fn wl_seat_cb<D: SeatHandler>(ctx: EventCtx<D, WlSeat>) {
-    let seat = ctx
-        .state
-        .get_seats()
-        .seats
-        .iter_mut()
-        .find(|s| s.wl_seat == ctx.proxy)
-        .unwrap();
+    let capabilities = ctx.state.get_seats().seats.get_mut(ctx.proxy).unwrap();
 
     match ctx.event {
         wl_seat::Event::Capabilities(new_caps) => {
-            let old_caps = seat.capabilities;
-            seat.capabilities = new_caps;
+            let old_caps = *capabilities;
+            *capabilities = new_caps;
 
             match (
                 new_caps.contains(Capability::Pointer),
@@ -223,3 +194,76 @@ fn wl_seat_cb<D: SeatHandler>(ctx: EventCtx<D, WlSeat>) {
         _ => (),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wayrs_client::core::ArgValue;
+    use wayrs_client::testing::MockCompositor;
+
+    struct State {
+        seats: Seats,
+        log: Vec<&'static str>,
+    }
+
+    impl SeatHandler for State {
+        fn get_seats(&mut self) -> &mut Seats {
+            &mut self.seats
+        }
+
+        fn seat_added(&mut self, _: &mut Connection<Self>, seat: WlSeat) {
+            assert!(self.seats.iter().any(|s| s == seat));
+            self.log.push("seat_added");
+        }
+
+        fn seat_removed(&mut self, _: &mut Connection<Self>, _: WlSeat) {
+            self.log.push("seat_removed");
+        }
+
+        fn pointer_added(&mut self, _: &mut Connection<Self>, _: WlSeat) {
+            self.log.push("pointer_added");
+        }
+
+        fn pointer_removed(&mut self, _: &mut Connection<Self>, _: WlSeat) {
+            self.log.push("pointer_removed");
+        }
+    }
+
+    #[test]
+    fn seats_are_released() {
+        let (mut mock, mut conn) = MockCompositor::new::<State>();
+        let mut state = State {
+            seats: Seats::new(&mut conn),
+            log: Vec::new(),
+        };
+
+        let old = mock.add_global::<WlSeat>(4);
+        let new = mock.add_global::<WlSeat>(7);
+        mock.roundtrip(&mut conn, &mut state);
+        mock.roundtrip(&mut conn, &mut state);
+        let [old_seat, new_seat] = mock.objects_of::<WlSeat>()[..] else {
+            panic!("expected two seats");
+        };
+        assert_eq!(state.seats.iter().collect::<Vec<_>>(), [old_seat, new_seat]);
+
+        mock.send_event(new_seat, "capabilities", vec![ArgValue::Uint(1)]);
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(state.log, ["seat_added", "seat_added", "pointer_added"]);
+        state.log.clear();
+
+        // `wl_seat.release` is available since version 5
+        mock.remove_global(old);
+        mock.remove_global(new);
+        mock.roundtrip(&mut conn, &mut state);
+        mock.roundtrip(&mut conn, &mut state);
+        assert_eq!(
+            state.log,
+            ["seat_removed", "pointer_removed", "seat_removed"]
+        );
+        assert_eq!(state.seats.iter().count(), 0);
+        let requests = mock.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].object, new_seat.id());
+        assert_eq!(requests[0].name, "release");
+    }
+}